@@ -1,36 +1,104 @@
 #[cfg(test)]
 mod test {
+    use http::HeaderMap;
+
     use crate::util::case_sense_map::CaseSenseMap;
 
     #[test]
     fn init_map() {
         let mut case_map = CaseSenseMap::new();
-        case_map.insert("a".to_string(), "b".to_string());
-        case_map.append("A".to_string(), "c".to_string());
-        assert_eq!(case_map.get("a").unwrap(), "[b,c]".to_string());
+        case_map.insert("a", "b");
+        case_map.append("A", "c");
+        assert_eq!(case_map.get("a").unwrap(), b"b");
+        assert_eq!(case_map.get_all("a").collect::<Vec<_>>(), vec![&b"b"[..], &b"c"[..]]);
     }
 
     #[test]
     fn duplicate_insert() {
         let mut case_map = CaseSenseMap::new();
-        case_map.insert("a".to_string(), "b".to_string());
-        case_map.append("A".to_string(), "c".to_string());
+        case_map.insert("a", "b");
+        case_map.append("A", "c");
+        case_map.append("A", "c");
 
-        assert_eq!(case_map.get("a").unwrap(), "[b,c]".to_string());
+        assert_eq!(case_map.get_all("a").count(), 3);
 
-        let out = case_map.insert("a".to_string(), "d".to_string());
+        let out = case_map.insert("a", "d");
 
-        assert_eq!(out.unwrap(), "[b,c]".to_string());
-        assert_eq!(case_map.get("a").unwrap(), "[d]".to_string());
+        assert_eq!(out.unwrap(), vec![b"b".to_vec(), b"c".to_vec(), b"c".to_vec()]);
+        assert_eq!(case_map.get_all("A").collect::<Vec<_>>(), vec![&b"d"[..]]);
     }
 
     #[test]
     fn value_is_capital() {
         let mut case_map = CaseSenseMap::new();
-        case_map.insert("a".to_string(), "b".to_string());
-        case_map.append("A".to_string(), "c".to_string());
-        case_map.append("A".to_string(), "B".to_string());
+        case_map.insert("a", "b");
+        case_map.append("A", "c");
+        case_map.append("A", "B");
+
+        assert_eq!(case_map.get_all("A").collect::<Vec<_>>(), vec![&b"b"[..], &b"c"[..], &b"B"[..]]);
+    }
+
+    #[test]
+    fn keep_case_and_order() {
+        let mut case_map = CaseSenseMap::new();
+        case_map.append("Host", "a.com");
+        case_map.append("X-Foo", "1");
+        case_map.append("x-foo", "2");
+        case_map.append("Content-Type", "text/plain");
+        case_map.insert("X-FOO", "3");
+
+        let entries: Vec<(&str, &[u8])> = case_map.iter().collect();
+        assert_eq!(entries, vec![
+            ("Host", &b"a.com"[..]),
+            ("X-FOO", &b"3"[..]),
+            ("Content-Type", &b"text/plain"[..]),
+        ]);
+        assert!(case_map.contains("content-type"));
+
+        assert_eq!(case_map.remove("HOST").unwrap(), vec![b"a.com".to_vec()]);
+        assert_eq!(case_map.len(), 2);
+    }
+
+    #[test]
+    fn remove_value_ignore_case() {
+        let mut case_map = CaseSenseMap::new();
+        case_map.append("Accept", "a");
+        case_map.append("accept", "b");
+        case_map.append("Other", "a");
+
+        assert!(case_map.remove_value("ACCEPT", b"a"));
+        assert!(!case_map.remove_value("ACCEPT", b"c"));
+        assert_eq!(case_map.get_all("accept").collect::<Vec<_>>(), vec![&b"b"[..]]);
+        assert!(case_map.remove_value("ACCEPT", b"b"));
+        assert!(!case_map.contains("accept"));
+        assert_eq!(case_map.get("other").unwrap(), b"a");
+    }
+
+    #[test]
+    fn keys_keep_first_case() {
+        let mut case_map = CaseSenseMap::new();
+        case_map.append("X-Foo", "1");
+        case_map.append("Host", "a");
+        case_map.append("x-foo", "2");
+        assert_eq!(case_map.keys().collect::<Vec<_>>(), vec!["X-Foo", "Host"]);
+    }
+
+    #[test]
+    fn header_map_conversion() {
+        let mut case_map = CaseSenseMap::new();
+        case_map.append("X-Foo", "1");
+        case_map.append("Host", "a");
+        case_map.append("x-foo", &b"\xff"[..]);
+
+        let header_map = case_map.to_header_map().unwrap();
+        assert_eq!(header_map.get_all("x-foo").iter().count(), 2);
+        assert_eq!(header_map.get("host").unwrap(), "a");
+
+        let back = CaseSenseMap::from(&header_map);
+        assert_eq!(back.get_all("X-FOO").collect::<Vec<_>>(), vec![&b"1"[..], &b"\xff"[..]]);
+        assert_eq!(back.keys().collect::<Vec<_>>(), vec!["x-foo", "host"]);
 
-        assert_eq!(case_map.get("A").unwrap(), "[b,c,B]".to_string());
+        case_map.append("bad name", "1");
+        assert!(HeaderMap::try_from(&case_map).is_err());
     }
-}
\ No newline at end of file
+}
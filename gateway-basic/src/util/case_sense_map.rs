@@ -1,84 +1,183 @@
-use std::collections::{
-    hash_map::{Entry, Iter, IterMut},
-    HashMap, HashSet,
-};
+use std::slice::Iter;
 
+use gateway_error::{ErrTrans, ErrorType, Result};
+use http::{HeaderMap, HeaderName, HeaderValue};
+
+/// Ordered multi-value header map.
+///
+/// every entry keeps the original case of its name and the order it was added in,
+/// duplicate names and values are all kept. Lookups ignore case.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct CaseSenseMap {
-    inner: HashMap<String, HashSet<String>>,
+    inner: Vec<(String, Vec<u8>)>,
+}
+
+/// Iterator over the values of one name, see [CaseSenseMap::get_all].
+pub struct GetAll<'a> {
+    inner: Iter<'a, (String, Vec<u8>)>,
+    name: &'a str,
+}
+
+impl<'a> Iterator for GetAll<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .by_ref()
+            .find(|(k, _)| k.eq_ignore_ascii_case(self.name))
+            .map(|(_, v)| v.as_slice())
+    }
 }
 
 impl CaseSenseMap {
     pub fn new() -> Self {
         Self {
-            inner: HashMap::new(),
+            inner: Vec::new(),
         }
     }
 
-    pub fn get(&self, input: &str) -> Option<String> {
-        Self::format_value(self.inner.get(&input.to_lowercase()))
+    /// the first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.inner
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
     }
 
-    pub fn contains(&self, input: &str) -> bool {
-        self.inner.contains_key(&input.to_lowercase())
+    /// all values of `name` in insertion order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> GetAll<'a> {
+        GetAll {
+            inner: self.inner.iter(),
+            name,
+        }
     }
 
-    pub fn insert(&mut self, mut key: String, value: String) -> Option<String> {
-        key = key.to_lowercase();
-        let mut init_set = HashSet::new();
-        let old_set = self.get(&key);
-        init_set.insert(value);
-        self.inner.insert(key, init_set.clone());
-        old_set
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
     }
 
-    pub fn append(&mut self, key: String, value: String) {
-        let origin_values = self.entry(key).or_insert(HashSet::new());
-        (*origin_values).insert(value);
+    /// replace all values of `key`, the new entry takes the place of the first old one.
+    ///
+    /// return the replaced values.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        let key = key.into();
+        match self.inner.iter().position(|(k, _)| k.eq_ignore_ascii_case(&key)) {
+            Some(first) => {
+                let mut removed = vec![];
+                let mut i = 0;
+                self.inner.retain(|(k, v)| {
+                    let keep = i <= first || !k.eq_ignore_ascii_case(&key);
+                    if !keep {
+                        removed.push(v.clone());
+                    }
+                    i += 1;
+                    keep
+                });
+                let (_, old) = std::mem::replace(&mut self.inner[first], (key, value.into()));
+                removed.insert(0, old);
+                Some(removed)
+            }
+            None => {
+                self.inner.push((key, value.into()));
+                None
+            }
+        }
     }
 
-    pub fn entry(&mut self, key: String) -> Entry<String, HashSet<String>> {
-        self.inner.entry(key.to_lowercase())
+    /// the values of `name` in insertion order, to be changed in place.
+    pub fn get_all_mut<'a>(&'a mut self, name: &'a str) -> impl Iterator<Item = &'a mut Vec<u8>> + 'a {
+        self.inner
+            .iter_mut()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
     }
 
-    pub fn remove(&mut self, key: String) -> Option<HashSet<String>> {
-        self.inner.remove(&key.to_lowercase())
+    /// rename every entry of `key` to `new_key`, keeping the entries where they are.
+    pub fn rename(&mut self, key: &str, new_key: &str) {
+        for (k, _) in self.inner.iter_mut().filter(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            *k = new_key.to_string();
+        }
     }
 
+    pub fn append(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) {
+        self.inner.push((key.into(), value.into()));
+    }
 
-    pub fn remove_value(&mut self, key: String, value: String) {
-        let v = self.inner.entry(key.to_lowercase()).or_default();
-        v.remove(&value);
-        if v.len() == 0 {
-            self.inner.remove(&key);
-        }
+    /// remove all values of `key`.
+    pub fn remove(&mut self, key: &str) -> Option<Vec<Vec<u8>>> {
+        let mut removed = vec![];
+        self.inner.retain(|(k, v)| {
+            if k.eq_ignore_ascii_case(key) {
+                removed.push(v.clone());
+                false
+            } else {
+                true
+            }
+        });
+        (!removed.is_empty()).then_some(removed)
     }
 
-    pub fn iter(&self) -> Iter<String, HashSet<String>> {
-        self.inner.iter()
+    /// remove the entries of `key` holding `value`, return whether any was removed.
+    pub fn remove_value(&mut self, key: &str, value: &[u8]) -> bool {
+        let len = self.inner.len();
+        self.inner
+            .retain(|(k, v)| !(k.eq_ignore_ascii_case(key) && v == value));
+        self.inner.len() != len
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<String, HashSet<String>> {
-        self.inner.iter_mut()
+    /// entries in insertion order, with names in their original case.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.inner.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
     }
 
-    #[inline]
-    fn format_value(value: Option<&HashSet<String>>) -> Option<String> {
-        if let Some(vset) = value {
-            let capacity = vset.iter().map(|s| s.len())
-                .sum::<usize>() + vset.len() + 1;
-            let mut print_str = String::with_capacity(capacity);
-            print_str.push_str("[");
-            for (i, s) in vset.iter().enumerate() {
-                if i > 0 {
-                    print_str.push_str(",");
-                }
-                print_str.push_str(s);
-            }
-            print_str.push_str("]");
-            Some(print_str)
-        } else {
-            None
+    /// distinct names in the case they were first added with.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.inner
+            .iter()
+            .enumerate()
+            .filter(|(i, (k, _))| {
+                !self.inner[..*i].iter().any(|(prev, _)| prev.eq_ignore_ascii_case(k))
+            })
+            .map(|(_, (k, _))| k.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// convert into a [HeaderMap], names are lowercased there.
+    pub fn to_header_map(&self) -> Result<HeaderMap> {
+        let mut header_map = HeaderMap::with_capacity(self.inner.len());
+        for (k, v) in self.inner.iter() {
+            let name = HeaderName::from_bytes(k.as_bytes())
+                .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head name")?;
+            let value = HeaderValue::from_bytes(v)
+                .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head value")?;
+            header_map.append(name, value);
         }
+        Ok(header_map)
     }
 }
 
+impl From<&HeaderMap> for CaseSenseMap {
+    /// the original case is already lost in a [HeaderMap], names stay lowercase.
+    fn from(header_map: &HeaderMap) -> Self {
+        let mut case_map = CaseSenseMap::new();
+        for (k, v) in header_map {
+            case_map.append(k.as_str(), v.as_bytes());
+        }
+        case_map
+    }
+}
+
+impl TryFrom<&CaseSenseMap> for HeaderMap {
+    type Error = Box<gateway_error::Error>;
+
+    fn try_from(case_map: &CaseSenseMap) -> Result<Self> {
+        case_map.to_header_map()
+    }
+}
@@ -0,0 +1,85 @@
+use std::error::Error as ErrorTrait;
+
+use crate::{BErr, Error, ErrorSource, ErrorType, Result, RetryType};
+
+/// Step by step construction of an [Error].
+///
+/// ```ignore
+/// Error::build(ErrorType::ConnectRefused)
+///     .source(ErrorSource::UpStream)
+///     .context(format!("connect {addr}"))
+///     .cause(e)
+///     .retry(true)
+///     .err()
+/// ```
+pub struct ErrorBuilder {
+    error_type: ErrorType,
+    error_source: ErrorSource,
+    error_retry: Option<RetryType>,
+    error_cause: Option<Box<dyn ErrorTrait + Send + Sync>>,
+    error_description: Option<String>,
+}
+
+impl ErrorBuilder {
+    pub(crate) fn new(error_type: ErrorType) -> Self {
+        ErrorBuilder {
+            error_type,
+            error_source: ErrorSource::Undefined,
+            error_retry: None,
+            error_cause: None,
+            error_description: None,
+        }
+    }
+
+    pub fn source(mut self, error_source: ErrorSource) -> Self {
+        self.error_source = error_source;
+        self
+    }
+
+    pub fn context(mut self, description: impl Into<String>) -> Self {
+        self.error_description = Some(description.into());
+        self
+    }
+
+    pub fn cause(mut self, cause: impl Into<Box<dyn ErrorTrait + Send + Sync>>) -> Self {
+        self.error_cause = Some(into_cause(cause));
+        self
+    }
+
+    /// without it the error inherits retryability from its cause.
+    pub fn retry(mut self, retry: impl Into<RetryType>) -> Self {
+        self.error_retry = Some(retry.into());
+        self
+    }
+
+    pub fn finish(self) -> BErr {
+        Box::new(Error::generate_error(
+            self.error_type,
+            self.error_source,
+            self.error_retry,
+            self.error_cause,
+            self.error_description,
+        ))
+    }
+
+    pub fn err<T>(self) -> Result<T> {
+        Err(self.finish())
+    }
+}
+
+impl From<ErrorBuilder> for BErr {
+    fn from(builder: ErrorBuilder) -> Self {
+        builder.finish()
+    }
+}
+
+/// box `cause` as an error cause.
+///
+/// a `Box<Error>` would otherwise be boxed twice and hidden from `downcast_ref::<Error>()`.
+pub(crate) fn into_cause(cause: impl Into<Box<dyn ErrorTrait + Send + Sync>>) -> Box<dyn ErrorTrait + Send + Sync> {
+    let cause = cause.into();
+    match cause.downcast::<BErr>() {
+        Ok(inner) => *inner,
+        Err(cause) => cause,
+    }
+}
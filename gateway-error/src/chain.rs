@@ -0,0 +1,32 @@
+use std::error::Error as ErrorTrait;
+
+use crate::{Error, ErrorType};
+
+/// Iterator over an error and its causes, foreign errors included.
+///
+/// the first item is the error itself, the last one is the root cause.
+pub struct ErrorChain<'a> {
+    next: Option<&'a (dyn ErrorTrait + 'static)>,
+}
+
+impl<'a> ErrorChain<'a> {
+    pub(crate) fn new(start: &'a (dyn ErrorTrait + 'static)) -> Self {
+        ErrorChain { next: Some(start) }
+    }
+
+    /// the first gateway [Error] of the given [ErrorType].
+    pub fn find_type(self, etype: &ErrorType) -> Option<&'a Error> {
+        self.filter_map(|e| e.downcast_ref::<Error>())
+            .find(|e| e.etype() == etype)
+    }
+}
+
+impl<'a> Iterator for ErrorChain<'a> {
+    type Item = &'a (dyn ErrorTrait + 'static);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next?;
+        self.next = current.source();
+        Some(current)
+    }
+}
@@ -1,7 +1,7 @@
 
 use std::error::Error as ErrorTrait;
 
-use crate::{BErr, ErrorType};
+use crate::{BErr, ErrorSource, ErrorType};
 use std::result::Result as StdResult;
 pub type Result<T, E = BErr> = StdResult<T, E>;
 
@@ -15,8 +15,31 @@ pub trait OrErr<T, E> {
         context: &'static str) -> Result<T, BErr>
     where 
         E: Into<Box<dyn ErrorTrait + Send + Sync>>;
+
+    /// `context` is only formatted when `self` is an error.
+    fn or_err_with<C, F>(self, et: ErrorType, context: F) -> Result<T, BErr>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+        C: Into<String>,
+        F: FnOnce() -> C;
+
+    fn or_err_source(self, et: ErrorType, source: ErrorSource,
+        context: impl Into<String>) -> Result<T, BErr>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>;
     
     fn or_fail(self) -> Result<T, BErr>
     where
         E: Into<Box<dyn ErrorTrait + Send + Sync>>;
+}
+
+/// Turn a missing value into an error.
+pub trait OkOrErr<T> {
+    fn or_err(self, et: ErrorType, context: &'static str) -> Result<T, BErr>;
+
+    /// `context` is only formatted when the value is missing.
+    fn or_err_with<C, F>(self, et: ErrorType, context: F) -> Result<T, BErr>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C;
 }
\ No newline at end of file
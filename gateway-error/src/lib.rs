@@ -1,34 +1,49 @@
 mod test_mod;
 pub mod error_trait;
+pub mod chain;
+pub mod builder;
 
 use std::fmt::{self};
 use std::error::Error as ErrorTrait;
 use std::result::Result as StdResult;
 
 pub use error_trait::ErrTrans;
-use error_trait::OrErr;
+pub use chain::ErrorChain;
+pub use builder::ErrorBuilder;
+use builder::into_cause;
+use error_trait::{OkOrErr, OrErr};
 
 #[derive(Debug)]
 pub struct Error {
     error_type: ErrorType,
     error_source: ErrorSource,
-    error_retry: RetryType,
+    error_retry: Option<RetryType>,
     error_cause: Option<Box<(dyn ErrorTrait + Send + Sync)>>,
     error_description: Option<String>,
 }
 
-type BErr = Box<Error>;
+pub type BErr = Box<Error>;
 pub type Result<T, E = BErr> = StdResult<T, E>;
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ErrorType {
     /*----------Writing Request/Response------------ */
     WriteError,
+    /// a write did not finish within the write timeout
+    WriteTimedout,
     /*----------Reading Request/Response------------ */
     ReadError,
+    /// a read did not finish within the read timeout
+    ReadTimedout,
     ConnectionClosed,
     /*----------Connect Problem------------*/
     ConnectTimeout,
     ConnectRefused,
+    /// the upstream host name could not be resolved
+    DnsError,
+    NoUpstream,
+    PerTryTimeout,
+    /// a circuit breaker of the upstream cluster refused the request
+    CircuitBreakerOpen,
     InternalError,
     /*----------Connect Problem------------*/
     BindError,
@@ -41,19 +56,30 @@ pub enum ErrorType {
     Custom(&'static str),
     CustomCode(&'static str, u16),
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ErrorSource {
     UpStream,
     DownStream,
     Internal,
     Undefined,
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RetryType {
     Decided(bool),
+    /// only retry when the failed connection was reused from the pool,
+    /// a stale pooled connection says nothing about the upstream itself.
     ReusedOnly,
 }
 
+impl RetryType {
+    pub fn decide(&self, conn_reused: bool) -> bool {
+        match self {
+            RetryType::Decided(retry) => *retry,
+            RetryType::ReusedOnly => conn_reused,
+        }
+    }
+}
+
 impl Into<RetryType> for bool {
     fn into(self) -> RetryType {
         match self {
@@ -75,51 +101,116 @@ impl Default for Error {
     fn default() -> Self {
         Self { 
             error_type: ErrorType::Custom("null"), 
-            error_source: ErrorSource::DownStream, 
-            error_retry: false.into(), 
+            error_source: ErrorSource::Undefined, 
+            error_retry: None, 
             error_cause: None, 
             error_description: None 
         }
     }
 }
 
-impl ErrorTrait for Error {}
+impl ErrorTrait for Error {
+    fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
+        self.cause().map(|e| e as &(dyn ErrorTrait + 'static))
+    }
+}
 
 impl Error {
     fn chain_display(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}: context: {};", self.error_type,self.error_description.as_ref().unwrap_or(&"non description.".to_string()))?;
-        if let Some(case_error) = self.error_cause.as_ref() {
-            write!(f, " Error is transport To ->\n")?;
-            case_error.downcast_ref::<Error>().unwrap().chain_display(f)
-        } else {
-            Ok(())
+        for (i, e) in self.chain().enumerate() {
+            if i > 0 {
+                writeln!(f, " Error is transport To ->")?;
+            }
+            match e.downcast_ref::<Error>() {
+                Some(ge) => write!(f, "{:?}: context: {};", ge.error_type, ge.description().unwrap_or("non description."))?,
+                None => write!(f, "{e};")?,
+            }
         }
+        Ok(())
     }
 
     pub fn etype(&self) -> &ErrorType {
         &self.error_type
     }
+
+    pub fn esource(&self) -> &ErrorSource {
+        &self.error_source
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.error_description.as_deref()
+    }
+
+    /// the direct cause, which may not be a gateway [Error].
+    pub fn cause(&self) -> Option<&(dyn ErrorTrait + Send + Sync + 'static)> {
+        self.error_cause.as_deref()
+    }
+
+    /// iterate this error and all of its causes.
+    pub fn chain(&self) -> ErrorChain<'_> {
+        ErrorChain::new(self)
+    }
+
+    /// the innermost cause, `self` when there is none.
+    pub fn root_cause(&self) -> &(dyn ErrorTrait + 'static) {
+        self.chain().last().unwrap_or(self)
+    }
+
+    /// the first error of the chain having the given [ErrorType].
+    pub fn find_type(&self, etype: &ErrorType) -> Option<&Error> {
+        self.chain().find_type(etype)
+    }
+
+    /// the [RetryType] in effect for this error.
+    ///
+    /// the first one set along the cause chain wins, an unset chain or a chain
+    /// ending with a foreign error is not retryable.
+    pub fn retry_type(&self) -> RetryType {
+        if let Some(retry) = self.error_retry {
+            return retry;
+        }
+        match self.error_cause.as_ref().and_then(|c| c.downcast_ref::<Error>()) {
+            Some(cause) => cause.retry_type(),
+            None => RetryType::Decided(false),
+        }
+    }
+
+    /// whether the request can be retried, `conn_reused` tells if the failed
+    /// connection came from the pool, see [RetryType::ReusedOnly].
+    pub fn retry(&self, conn_reused: bool) -> bool {
+        self.retry_type().decide(conn_reused)
+    }
+
+    /// start building an error, see [ErrorBuilder].
+    pub fn build(error_type: ErrorType) -> ErrorBuilder {
+        ErrorBuilder::new(error_type)
+    }
+
+    pub fn set_source(&mut self, error_source: ErrorSource) {
+        self.error_source = error_source;
+    }
+
+    /// set retryability of this error, overriding what its causes say.
+    pub fn set_retry(&mut self, retry: impl Into<RetryType>) {
+        self.error_retry = Some(retry.into());
+    }
+
+    /// resolve [RetryType::ReusedOnly] once it is known whether the connection was reused.
+    pub fn decide_reuse(&mut self, conn_reused: bool) {
+        self.error_retry = Some(RetryType::Decided(self.retry(conn_reused)));
+    }
+
     ///generate error with cause.
     /// 
-    ///[RetryType] not always worked, if error_cause cant retry, then [RetryType] is false.
+    ///without an explicit [RetryType] the error inherits retryability from its cause.
     fn generate_error(error_type: ErrorType, error_source: ErrorSource, 
-        error_retry: RetryType, error_cause: Option<Box<(dyn std::error::Error + Send + Sync + 'static)>>, error_description: Option<&str>,) -> Self {
-        
-        let retry = if let Some(cause) = error_cause.as_ref() {
-            if let Some(upper_cause) = cause.downcast_ref::<Error>() {
-                upper_cause.error_retry
-            } else {
-                false.into()
-            }
-        } else {
-            error_retry
-        };
+        error_retry: Option<RetryType>, error_cause: Option<Box<dyn std::error::Error + Send + Sync + 'static>>, error_description: Option<String>,) -> Self {
         Error {
             error_type,
             error_source,
-            error_retry: retry,
+            error_retry,
             error_cause,
-            error_description: error_description.as_deref().map(|strs| strs.to_string()),
+            error_description,
         }
     }
 
@@ -132,7 +223,7 @@ impl Error {
 
     /// new an Error.
     fn new(error_type: ErrorType) -> BErr {
-        Box::new(Error::generate_error(error_type, ErrorSource::DownStream, false.into(), None, None))
+        Box::new(Error::generate_error(error_type, ErrorSource::Undefined, None, None, None))
     }
 
     fn new_with_reason(error_type: ErrorType, error_cause: &str) -> BErr {
@@ -165,6 +256,18 @@ impl Error {
         be
     }
 
+    /// like [Error::generate_error_with_root_raw], overriding the retryability of the cause.
+    pub fn generate_error_with_retry (
+        error_type: ErrorType,
+        error_description: &str,
+        error_retry: impl Into<RetryType>,
+        error_cause: Option<Box<dyn ErrorTrait + Send + Sync>>
+    ) -> BErr {
+        let mut be = Self::generate_error_with_root_raw(error_type, error_description, error_cause);
+        be.set_retry(error_retry);
+        be
+    }
+
     fn because(&mut self, cause: Box<(dyn ErrorTrait + Send + Sync)>) {
         self.error_cause.replace(cause);
     }
@@ -200,6 +303,30 @@ impl ErrorType {
     pub fn new_custom(error_type: &'static str) -> Self {
         Self::Custom(error_type)
     }
+
+    /// a stable name for logs and metric labels, custom types give their own name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorType::WriteError => "WriteError",
+            ErrorType::WriteTimedout => "WriteTimedout",
+            ErrorType::ReadError => "ReadError",
+            ErrorType::ReadTimedout => "ReadTimedout",
+            ErrorType::ConnectionClosed => "ConnectionClosed",
+            ErrorType::ConnectTimeout => "ConnectTimeout",
+            ErrorType::ConnectRefused => "ConnectRefused",
+            ErrorType::DnsError => "DnsError",
+            ErrorType::NoUpstream => "NoUpstream",
+            ErrorType::PerTryTimeout => "PerTryTimeout",
+            ErrorType::CircuitBreakerOpen => "CircuitBreakerOpen",
+            ErrorType::InternalError => "InternalError",
+            ErrorType::BindError => "BindError",
+            ErrorType::SocketError => "SocketError",
+            ErrorType::HttpCode(_) => "HTTPStatus",
+            ErrorType::InvalidHttpHeader => "InvalidHttpHeader",
+            ErrorType::ConnectProxyError => "ConnectProxyError",
+            ErrorType::Custom(name) | ErrorType::CustomCode(name, _) => name,
+        }
+    }
 }
 
 impl<T, E> OrErr<T, E> for StdResult<T, E> {
@@ -207,12 +334,40 @@ impl<T, E> OrErr<T, E> for StdResult<T, E> {
         context: &'static str) -> Result<T, BErr>
     where 
         E: Into<Box<dyn ErrorTrait + Send + Sync>> {
-        self.map_err(|e| Error::generate_error_with_root_raw(et, &context, Some(e.into())))
+        self.map_err(|e| Error::generate_error_with_root_raw(et, context, Some(into_cause(e))))
+    }
+
+    fn or_err_with<C, F>(self, et: ErrorType, context: F) -> Result<T, BErr>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+        C: Into<String>,
+        F: FnOnce() -> C {
+        self.map_err(|e| Error::build(et).context(context()).cause(e).finish())
+    }
+
+    fn or_err_source(self, et: ErrorType, source: ErrorSource,
+        context: impl Into<String>) -> Result<T, BErr>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>> {
+        self.map_err(|e| Error::build(et).source(source).context(context).cause(e).finish())
     }
 
     fn or_fail(self) -> StdResult<T, BErr>
     where
         E: Into<Box<dyn ErrorTrait + Send + Sync>> {
-        self.map_err(|e| Error::generate_error_with_root_raw(ErrorType::InternalError, "", Some(e.into())))
+        self.map_err(|e| Error::generate_error_with_root_raw(ErrorType::InternalError, "", Some(into_cause(e))))
+    }
+}
+
+impl<T> OkOrErr<T> for Option<T> {
+    fn or_err(self, et: ErrorType, context: &'static str) -> Result<T, BErr> {
+        self.ok_or_else(|| Error::new_with_reason(et, context))
+    }
+
+    fn or_err_with<C, F>(self, et: ErrorType, context: F) -> Result<T, BErr>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C {
+        self.ok_or_else(|| Error::build(et).context(context()).finish())
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use crate::{Error, ErrorType};
+    use crate::{Error, ErrorType, RetryType};
 
     #[test]
     fn test_generate_error_withcause() {
@@ -35,4 +35,110 @@ mod tests {
 
         assert_ne!(1, 2);
     }
+
+    #[test]
+    fn test_retry_inherit_from_cause() {
+        let e1 = Error::generate_error_with_retry(ErrorType::ConnectRefused, "refused", true, None);
+        let e2 = Error::generate_error_with_root_raw(ErrorType::ConnectProxyError, "wrap", Some(e1));
+        assert!(e2.retry(false));
+        assert_eq!(e2.retry_type(), RetryType::Decided(true));
+
+        let e3 = Error::generate_error_with_retry(ErrorType::InternalError, "override", false, Some(e2));
+        assert!(!e3.retry(true));
+    }
+
+    #[test]
+    fn test_retry_foreign_cause() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let e1 = Error::generate_error_with_root_raw(ErrorType::ReadError, "read", Some(Box::new(io)));
+        assert!(!e1.retry(true));
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let e2 = Error::generate_error_with_retry(ErrorType::ReadError, "read", true, Some(Box::new(io)));
+        assert!(e2.retry(false));
+    }
+
+    #[test]
+    fn test_retry_reused_only() {
+        let e1 = Error::generate_error_with_retry(ErrorType::ConnectionClosed, "closed", RetryType::ReusedOnly, None);
+        let mut e2 = Error::generate_error_with_root_raw(ErrorType::ReadError, "read", Some(e1));
+        assert!(e2.retry(true));
+        assert!(!e2.retry(false));
+
+        e2.decide_reuse(false);
+        assert_eq!(e2.retry_type(), RetryType::Decided(false));
+        assert!(!e2.retry(true));
+    }
+
+    #[test]
+    fn test_chain_with_foreign_cause() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused by peer");
+        let e1 = Error::generate_error_with_root_raw(ErrorType::ConnectRefused, "connect", Some(Box::new(io)));
+        let e2 = Error::generate_error_with_root_raw(ErrorType::ConnectProxyError, "proxy", Some(e1));
+
+        assert_eq!(e2.chain().count(), 3);
+        assert_eq!(e2.root_cause().to_string(), "refused by peer");
+        assert!(e2.root_cause().downcast_ref::<std::io::Error>().is_some());
+        assert!(std::error::Error::source(e2.as_ref()).is_some());
+
+        let display = e2.to_string();
+        assert!(display.ends_with("refused by peer;"), "{display}");
+    }
+
+    #[test]
+    fn test_find_type() {
+        let e1 = Error::generate_error_with_root_raw(ErrorType::ConnectTimeout, "inner", None);
+        let e2 = Error::generate_error_with_root_raw(ErrorType::ReadError, "outer", Some(e1));
+
+        assert_eq!(e2.find_type(&ErrorType::ConnectTimeout).unwrap().description(), Some("inner"));
+        assert!(e2.find_type(&ErrorType::WriteError).is_none());
+        assert_eq!(e2.root_cause().downcast_ref::<Error>().unwrap().etype(), &ErrorType::ConnectTimeout);
+        assert_eq!(e2.esource(), &crate::ErrorSource::Undefined);
+        assert!(e2.cause().is_some());
+    }
+
+    #[test]
+    fn test_build_error() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        let port = 8080;
+        let e = Error::build(ErrorType::ConnectRefused)
+            .source(crate::ErrorSource::UpStream)
+            .context(format!("connect to port {port}"))
+            .cause(io)
+            .retry(true)
+            .finish();
+        assert_eq!(e.etype(), &ErrorType::ConnectRefused);
+        assert_eq!(e.esource(), &crate::ErrorSource::UpStream);
+        assert_eq!(e.description(), Some("connect to port 8080"));
+        assert!(e.retry(false));
+        assert_eq!(e.root_cause().to_string(), "refused");
+
+        let r: crate::Result<()> = Error::build(ErrorType::InternalError).err();
+        assert!(r.unwrap_err().description().is_none());
+    }
+
+    #[test]
+    fn test_or_err_variants() {
+        use crate::error_trait::{OkOrErr, OrErr};
+
+        let inner: crate::Result<()> = Error::build(ErrorType::ConnectTimeout).retry(true).err();
+        let e = inner.or_err(ErrorType::ReadError, "outer").unwrap_err();
+        assert_eq!(e.chain().count(), 2);
+        assert!(e.find_type(&ErrorType::ConnectTimeout).is_some());
+        assert!(e.retry(false));
+
+        let ok: Result<u8, std::io::Error> = Ok(1);
+        let v = ok.or_err_with(ErrorType::ReadError, || -> String { panic!("formatted eagerly") }).unwrap();
+        assert_eq!(v, 1);
+
+        let bad: Result<u8, std::io::Error> = Err(std::io::ErrorKind::TimedOut.into());
+        let e = bad.or_err_source(ErrorType::ReadError, crate::ErrorSource::UpStream, format!("read {}", 1)).unwrap_err();
+        assert_eq!(e.esource(), &crate::ErrorSource::UpStream);
+        assert_eq!(e.description(), Some("read 1"));
+
+        let none: Option<u8> = None;
+        let e = none.or_err_with(ErrorType::InvalidHttpHeader, || format!("missing {}", "host")).unwrap_err();
+        assert_eq!(e.description(), Some("missing host"));
+        assert_eq!(Some(3).or_err(ErrorType::InvalidHttpHeader, "missing").unwrap(), 3);
+    }
 }
@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::{fmt, sync::Arc, time::{Duration, SystemTime}};
 
 use super::row_connection::ProxyDigest;
 
@@ -6,10 +6,85 @@ use super::row_connection::ProxyDigest;
 pub struct Digest {
     pub timing_digest: Vec<Option<TimingDigest>>,
     pub proxy_digest: Option<Arc<ProxyDigest>>,
+    pub request_timing: RequestTiming,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TimingDigest {
     pub established_ts: SystemTime,
+    /// name resolution before the connection, `None` for a plain address
+    pub resolve_time: Option<Duration>,
+    /// handshake of this layer: TCP connect for l4, the handshake for TLS
+    pub handshake_time: Option<Duration>,
+}
+
+impl TimingDigest {
+    pub fn new(established_ts: SystemTime) -> Self {
+        TimingDigest { established_ts, resolve_time: None, handshake_time: None }
+    }
+}
+
+/// Where the time of one upstream request went, `None` for a phase which did not happen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestTiming {
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// writing the request header
+    pub header_send: Option<Duration>,
+    /// from the request header sent to the first byte of the response
+    pub ttfb: Option<Duration>,
+    /// from the first byte to the end of the response header
+    pub header_receive: Option<Duration>,
+    pub body_send: Option<Duration>,
+    pub body_receive: Option<Duration>,
+    /// from the start of the request to the end of the response body
+    pub total: Option<Duration>,
+}
+
+impl RequestTiming {
+    /// the phases which happened, in order, as `(name, duration)` for logs and metrics.
+    pub fn phases(&self) -> Vec<(&'static str, Duration)> {
+        [
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("tls", self.tls),
+            ("header_send", self.header_send),
+            ("ttfb", self.ttfb),
+            ("header_receive", self.header_receive),
+            ("body_send", self.body_send),
+            ("body_receive", self.body_receive),
+            ("total", self.total),
+        ]
+        .into_iter()
+        .filter_map(|(name, d)| Some((name, d?)))
+        .collect()
+    }
+
+    /// take the connection phases from the layers of a stream, l4 first.
+    pub fn set_connection(&mut self, layers: &[Option<TimingDigest>]) {
+        let mut layers = layers.iter().flatten();
+        if let Some(l4) = layers.next() {
+            self.dns = l4.resolve_time;
+            self.connect = l4.handshake_time;
+        }
+        if let Some(tls) = layers.next() {
+            self.tls = tls.handshake_time;
+        }
+    }
+}
+
+/// `name=milliseconds` pairs, e.g. `connect=1.204 ttfb=35.010 total=40.522`.
+impl fmt::Display for RequestTiming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, d)) in self.phases().into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{name}={:.3}", d.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
 }
 
 pub trait GetTimingDigest {
@@ -26,4 +101,25 @@ pub trait GetTimingDigest {
 pub trait GetProxyDigest {
     fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>>;
     fn set_proxy_digest(&mut self, _digest: ProxyDigest){}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_timing() {
+        let mut l4 = TimingDigest::new(SystemTime::now());
+        l4.resolve_time = Some(Duration::from_micros(1500));
+        l4.handshake_time = Some(Duration::from_millis(2));
+        let mut timing = RequestTiming::default();
+        timing.set_connection(&[Some(l4), None]);
+        timing.ttfb = Some(Duration::from_millis(30));
+        timing.total = Some(Duration::from_millis(40));
+
+        assert_eq!(timing.tls, None);
+        assert_eq!(timing.phases().len(), 4);
+        assert_eq!(timing.to_string(), "dns=1.500 connect=2.000 ttfb=30.000 total=40.000");
+        assert_eq!(RequestTiming::default().to_string(), "");
+    }
+}
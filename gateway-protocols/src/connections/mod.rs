@@ -1,5 +1,7 @@
 use bytes::BufMut;
-use http::HeaderMap;
+use gateway_basic::util::case_sense_map::CaseSenseMap;
+use gateway_error::{ErrTrans, ErrorType, Result};
+use http::{header::Entry, HeaderMap, HeaderName, HeaderValue};
 
 pub mod row_connection;
 pub mod response;
@@ -26,4 +28,88 @@ fn header_to_h1_wire(value_map: &HeaderMap, buf: &mut impl BufMut) {
         buf.put_slice(value.as_bytes());
         buf.put_slice(CRLF);
     }
-}
\ No newline at end of file
+}
+
+#[inline]
+fn case_header_to_h1_wire(case_map: &CaseSenseMap, buf: &mut impl BufMut) {
+    const CRLF: &[u8; 2] = b"\r\n";
+    const HEADER_KV_DELIMITER: &[u8; 2] = b": ";
+
+    for (header, value) in case_map.iter() {
+        buf.put_slice(header.as_bytes());
+        buf.put_slice(HEADER_KV_DELIMITER);
+        buf.put_slice(value);
+        buf.put_slice(CRLF);
+    }
+}
+
+/// mirror a header operation into the case preserving map.
+fn operate_case_header(case_map: &mut CaseSenseMap, name: String, value: &HeaderValue, opt: &Opt) {
+    match opt {
+        Opt::INSERT | Opt::MODIFY => {
+            case_map.insert(name, value.as_bytes());
+        }
+        Opt::APPEND => case_map.append(name, value.as_bytes()),
+        Opt::REMOVE => {
+            case_map.remove(&name);
+        }
+    }
+}
+
+fn parse_header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::from_bytes(name.as_bytes()).to_b_err(ErrorType::InvalidHttpHeader, "invalid http head name")
+}
+
+/// rename every `from` header to `to`, the case map keeps them where they were.
+fn rename_header_in(
+    value_map: &mut HeaderMap,
+    case_map: Option<&mut CaseSenseMap>,
+    from: &str,
+    to: &str,
+) -> Result<()> {
+    let from_name = parse_header_name(from)?;
+    let to_name = parse_header_name(to)?;
+    let values: Vec<HeaderValue> = value_map.get_all(&from_name).iter().cloned().collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+    value_map.remove(&from_name);
+    for value in values {
+        value_map.append(to_name.clone(), value);
+    }
+    if let Some(case_map) = case_map {
+        case_map.rename(from, to);
+    }
+    Ok(())
+}
+
+/// replace every value of `name` with what `f` makes of it, in place and in order.
+fn map_header_values_in(
+    value_map: &mut HeaderMap,
+    case_map: Option<&mut CaseSenseMap>,
+    name: &str,
+    f: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+) -> Result<()> {
+    let header_name = parse_header_name(name)?;
+    let Entry::Occupied(mut entry) = value_map.entry(header_name) else {
+        return Ok(());
+    };
+    let values = entry
+        .iter()
+        .map(|v| HeaderValue::from_bytes(&f(v.as_bytes())))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head value")?;
+    if let Some(case_map) = case_map {
+        for (slot, value) in case_map.get_all_mut(name).zip(&values) {
+            *slot = value.as_bytes().to_vec();
+        }
+    }
+    let mut values = values.into_iter();
+    if let Some(first) = values.next() {
+        entry.insert(first);
+    }
+    for value in values {
+        entry.append(value);
+    }
+    Ok(())
+}
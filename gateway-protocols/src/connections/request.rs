@@ -5,17 +5,43 @@ use gateway_basic::util::case_sense_map::CaseSenseMap;
 use gateway_basic::util::small_case_string::SmallCaseString;
 use http::{HeaderMap, HeaderName, HeaderValue, Uri, Version};
 use http::{request::Parts, Method};
-use gateway_error::ErrorType;
+use gateway_error::{Error, ErrorType};
 use http::request::Builder as ReqBuilder;
 use gateway_error::ErrTrans;
+use gateway_error::error_trait::OkOrErr;
 use gateway_error::Result;
 
-use super::{header_to_h1_wire, Opt};
+use crate::http::common::{hop_by_hop_names, is_upgrade_req, CRLF};
+use crate::util_code::util_code::get_version_str;
+
+use super::{
+    case_header_to_h1_wire, header_to_h1_wire, map_header_values_in, operate_case_header, rename_header_in, Opt,
+};
 
 type ReqParts = Parts;
 type HeadersMap = CaseSenseMap;
+#[derive(Debug, Clone)]
 pub struct RequestHeader {
     base: ReqParts,
+    /// original case and order of the headers, see [RequestHeader::preserve_header_case]
+    header_case: Option<HeadersMap>,
+    /// the request is sent to a forward proxy, see [RequestHeader::set_proxy_mode]
+    proxy_mode: bool,
+    /// the exact path bytes received when `base.uri` had to escape them
+    raw_path_fallback: Vec<u8>,
+}
+
+/// How the request target is written on the request line, see RFC 9112 section 3.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestTarget {
+    /// `GET /where?q=now HTTP/1.1`
+    Origin,
+    /// `GET http://www.example.org/where?q=now HTTP/1.1`
+    Absolute,
+    /// `CONNECT www.example.com:80 HTTP/1.1`
+    Authority,
+    /// `OPTIONS * HTTP/1.1`
+    Asterisk,
 }
 
 
@@ -39,22 +65,89 @@ impl RequestHeader {
         let raw_parts = ReqBuilder::new().body(()).unwrap().into_parts().0;
         Self {
             base: raw_parts,
+            header_case: None,
+            proxy_mode: false,
+            raw_path_fallback: Vec::new(),
         }
     }
 
+    /// build with the exact request target bytes.
+    ///
+    /// bytes beyond ASCII (non UTF-8 included) are kept as received and only escaped
+    /// in the parsed [Uri], other bytes not allowed in a uri are rejected.
     pub fn build_with_method_path(
         method: impl TryInto<Method>,
         path: &[u8],
+    ) -> Result<Self> {
+        Self::build_with_method_path_inner(method, path, false)
+    }
+
+    /// like [RequestHeader::build_with_method_path], also accepting the non-conforming
+    /// bytes some clients send (`<`, `>`, `` ` ``, DEL and control characters other than
+    /// NUL, CR and LF).
+    pub fn build_with_method_path_lenient(
+        method: impl TryInto<Method>,
+        path: &[u8],
+    ) -> Result<Self> {
+        Self::build_with_method_path_inner(method, path, true)
+    }
+
+    fn build_with_method_path_inner(
+        method: impl TryInto<Method>,
+        path: &[u8],
+        lenient: bool,
     ) -> Result<Self> {
         let mut raw_req = Self::new();
         raw_req.base.method = method.try_into()
             .to_b_err(ErrorType::InvalidHttpHeader, "invalid method")?;
-        if let Ok(_) = std::str::from_utf8(path) {
-            let uri = Uri::builder()
-                .path_and_query(path)
-                .build()
-                .to_b_err(ErrorType::InvalidHttpHeader, "invalid path")?;
-                raw_req.base.uri = uri;
+        let escaped = escape_path(path, lenient)?;
+        let uri = Uri::builder()
+            .path_and_query(escaped.as_slice())
+            .build()
+            .to_b_err(ErrorType::InvalidHttpHeader, "invalid path")?;
+        raw_req.base.uri = uri;
+        if escaped != path {
+            raw_req.raw_path_fallback = path.to_vec();
+        }
+        Ok(raw_req)
+    }
+
+    /// build from a request head parsed by httparse.
+    ///
+    /// with `preserve_case` the headers are written back in their received case and order.
+    pub fn build_from_httparse(
+        req: &httparse::Request<'_, '_>,
+        preserve_case: bool,
+    ) -> Result<Self> {
+        Self::build_from_httparse_inner(req, preserve_case, false)
+    }
+
+    /// like [RequestHeader::build_from_httparse], with the request target parsed as in
+    /// [RequestHeader::build_with_method_path_lenient].
+    pub fn build_from_httparse_lenient(
+        req: &httparse::Request<'_, '_>,
+        preserve_case: bool,
+    ) -> Result<Self> {
+        Self::build_from_httparse_inner(req, preserve_case, true)
+    }
+
+    fn build_from_httparse_inner(
+        req: &httparse::Request<'_, '_>,
+        preserve_case: bool,
+        lenient: bool,
+    ) -> Result<Self> {
+        let method = req.method.or_err(ErrorType::InvalidHttpHeader, "missing method")?;
+        let path = req.path.or_err(ErrorType::InvalidHttpHeader, "missing path")?;
+        let mut raw_req = Self::build_with_method_path_inner(method, path.as_bytes(), lenient)?;
+        raw_req.set_version(match req.version {
+            Some(0) => Version::HTTP_10,
+            _ => Version::HTTP_11,
+        });
+        if preserve_case {
+            raw_req.preserve_header_case();
+        }
+        for header in req.headers.iter() {
+            raw_req.append_header(header.name, header.value)?;
         }
         Ok(raw_req)
     }
@@ -64,7 +157,10 @@ impl RequestHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::APPEND);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -78,7 +174,10 @@ impl RequestHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::REMOVE);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -92,7 +191,10 @@ impl RequestHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::INSERT);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -106,7 +208,10 @@ impl RequestHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::MODIFY);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -115,6 +220,16 @@ impl RequestHeader {
         )        
     }
 
+    /// rename every `from` header to `to`, keeping values and wire position.
+    pub fn rename_header(&mut self, from: &str, to: &str) -> Result<()> {
+        rename_header_in(&mut self.base.headers, self.header_case.as_mut(), from, to)
+    }
+
+    /// replace every value of `name` with what `f` makes of it, keeping case and wire position.
+    pub fn map_header_values(&mut self, name: &str, f: &mut dyn FnMut(&[u8]) -> Vec<u8>) -> Result<()> {
+        map_header_values_in(&mut self.base.headers, self.header_case.as_mut(), name, f)
+    }
+
     fn operate_header_value(
         value_map: &mut HeaderMap<HeaderValue>,
         key: HeaderName,   //-0 add -1 modify
@@ -141,36 +256,170 @@ impl RequestHeader {
     fn handle_name_value(
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
-    ) -> Result<(HeaderName, HeaderValue)> {
+    ) -> Result<(HeaderName, HeaderValue, String)> {
         let header_value = value
             .try_into()
             .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head value")?;
+        let origin_name = name.to_string();
         let header_name = name.into_small_case_header()
             .as_slice()
             .try_into()
             .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head name")?;
-        Ok((header_name, header_value))
+        Ok((header_name, header_value, origin_name))
     }
 
     pub fn set_version(&mut self, version: Version) {
         self.base.version = version;
     }
 
+    /// replace the uri, the raw path received before is dropped.
+    pub fn set_uri(&mut self, uri: Uri) {
+        self.base.uri = uri;
+        self.raw_path_fallback.clear();
+    }
+
+    /// write the request target in absolute-form, as a forward proxy expects it.
+    pub fn set_proxy_mode(&mut self, proxy_mode: bool) {
+        self.proxy_mode = proxy_mode;
+    }
+
+    /// path and query exactly as received, `/` when the uri has none.
     pub fn raw_path(&self) -> &[u8] {
+        if !self.raw_path_fallback.is_empty() {
+            return &self.raw_path_fallback;
+        }
         self.base
             .uri
             .path_and_query()
-            .as_ref()
-            .unwrap()
-            .as_str()
-            .as_bytes()
+            .map(|pq| pq.as_str().as_bytes())
+            .unwrap_or(b"/")
+    }
+
+    /// remove the hop-by-hop headers before forwarding, see [hop_by_hop_names].
+    ///
+    /// an upgrade request keeps `Upgrade` and is sent with `Connection: upgrade`.
+    pub fn strip_hop_by_hop(&mut self) -> Result<()> {
+        let upgrade = is_upgrade_req(self);
+        for name in hop_by_hop_names(&self.base.headers, upgrade) {
+            self.remove_header(name, "")?;
+        }
+        if upgrade {
+            self.insert_header(http::header::CONNECTION, "upgrade")?;
+        }
+        Ok(())
+    }
+
+    /// the first value of `name` as raw bytes, in the received case when preserved.
+    pub fn header_raw(&self, name: &str) -> Option<&[u8]> {
+        match self.header_case.as_ref() {
+            Some(header_case) => header_case.get(name),
+            None => self.base.headers.get(name).map(|v| v.as_bytes()),
+        }
+    }
+
+    /// the form of the request target decided by method and proxy mode.
+    pub fn target_form(&self) -> RequestTarget {
+        if self.base.method == Method::CONNECT {
+            RequestTarget::Authority
+        } else if self.base.method == Method::OPTIONS && self.raw_path() == b"*" {
+            RequestTarget::Asterisk
+        } else if self.proxy_mode {
+            RequestTarget::Absolute
+        } else {
+            RequestTarget::Origin
+        }
+    }
+
+    /// the authority of the uri, or the `Host` header when the uri has none.
+    fn target_authority(&self) -> Option<&[u8]> {
+        self.base
+            .uri
+            .authority()
+            .map(|authority| authority.as_str().as_bytes())
+            .or_else(|| self.base.headers.get(http::header::HOST).map(|host| host.as_bytes()))
+    }
+
+    /// write the request target, falling back to origin-form when the
+    /// authority needed by absolute-form or authority-form is unknown.
+    pub fn target_to_h1_wire(&self, buf: &mut impl BufMut) {
+        match (self.target_form(), self.target_authority()) {
+            (RequestTarget::Asterisk, _) => buf.put_u8(b'*'),
+            (RequestTarget::Authority, Some(authority)) => buf.put_slice(authority),
+            (RequestTarget::Absolute, Some(authority)) => {
+                buf.put_slice(self.base.uri.scheme_str().unwrap_or("http").as_bytes());
+                buf.put_slice(b"://");
+                buf.put_slice(authority);
+                buf.put_slice(self.raw_path());
+            }
+            _ => buf.put_slice(self.raw_path()),
+        }
+    }
+
+    /// write `method SP request-target SP version CRLF`.
+    pub fn request_line_to_h1_wire(&self, buf: &mut impl BufMut) {
+        buf.put_slice(self.base.method.as_str().as_bytes());
+        buf.put_u8(b' ');
+        self.target_to_h1_wire(buf);
+        buf.put_u8(b' ');
+        buf.put_slice(get_version_str(&self.base.version).as_bytes());
+        buf.put_slice(CRLF);
+    }
+
+    /// write the whole request head: request line, headers and the empty line.
+    pub fn to_h1_wire(&self, buf: &mut impl BufMut) {
+        self.request_line_to_h1_wire(buf);
+        self.header_to_h1_wire(buf);
+        buf.put_slice(CRLF);
+    }
+
+    /// keep the original case and order of headers added from now on,
+    /// headers already present are recorded lowercase.
+    pub fn preserve_header_case(&mut self) {
+        if self.header_case.is_none() {
+            self.header_case = Some(CaseSenseMap::from(&self.base.headers));
+        }
+    }
+
+    pub fn header_case_preserved(&self) -> bool {
+        self.header_case.is_some()
     }
 
     pub fn header_to_h1_wire(&self, buf: &mut impl BufMut) {
-        header_to_h1_wire(&self.base.headers, buf)
+        match self.header_case.as_ref() {
+            Some(header_case) => case_header_to_h1_wire(header_case, buf),
+            None => header_to_h1_wire(&self.base.headers, buf),
+        }
     }
 }
 
+/// percent-encode the bytes of `path` which [Uri] does not accept.
+///
+/// NUL, CR, LF and space are never accepted, the other ASCII bytes [Uri] refuses
+/// only in `lenient` mode.
+fn escape_path(path: &[u8], lenient: bool) -> Result<Vec<u8>> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut escaped = Vec::with_capacity(path.len());
+    for &b in path {
+        match b {
+            b'\0' | b'\r' | b'\n' | b' ' => {
+                return Error::generate_error_with_root(ErrorType::InvalidHttpHeader,
+                    &format!("invalid byte {b:#04x} in path"), None);
+            }
+            0x80..=0xff => {}
+            b'<' | b'>' | b'`' | 0x7f | 0x01..=0x1f if lenient => {}
+            b'<' | b'>' | b'`' | 0x7f | 0x01..=0x1f => {
+                return Error::generate_error_with_root(ErrorType::InvalidHttpHeader,
+                    &format!("invalid byte {b:#04x} in path"), None);
+            }
+            _ => {
+                escaped.push(b);
+                continue;
+            }
+        }
+        escaped.extend_from_slice(&[b'%', HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+    }
+    Ok(escaped)
+}
 
 #[cfg(test)]
 mod tests {
@@ -236,4 +485,166 @@ mod tests {
         req.header_to_h1_wire(&mut buf);
         assert_eq!(buf, b"foo: bar\r\ncontent-type: down\r\n");          
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_preserve_header_case() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/icbc/biom").unwrap();
+        req.preserve_header_case();
+
+        req.append_header("Content-Type", "text/plain").unwrap();
+        req.append_header("X-Foo", "1").unwrap();
+        req.append_header("content-TYPE", "text/html").unwrap();
+        req.insert_header("x-FOO", "2").unwrap();
+        assert_eq!(req.headers.get("content-type").unwrap(), "text/plain");
+
+        let mut buf = vec![];
+        req.header_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"Content-Type: text/plain\r\nx-FOO: 2\r\ncontent-TYPE: text/html\r\n");
+
+        req.remove_header("CONTENT-type", "").unwrap();
+        let mut buf = vec![];
+        req.header_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"x-FOO: 2\r\n");
+    }
+
+    #[test]
+    fn test_build_from_httparse() {
+        let input = b"GET /a HTTP/1.0\r\nHost: a.com\r\nX-Id: 1\r\nhost: b.com\r\n\r\n";
+        let mut headers = [httparse::EMPTY_HEADER; 8];
+        let mut parsed = httparse::Request::new(&mut headers);
+        parsed.parse(input).unwrap();
+
+        let req = RequestHeader::build_from_httparse(&parsed, true).unwrap();
+        assert_eq!(req.version, http::Version::HTTP_10);
+        assert_eq!(req.raw_path(), b"/a");
+        let mut buf = vec![];
+        req.header_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"Host: a.com\r\nX-Id: 1\r\nhost: b.com\r\n");
+
+        let req = RequestHeader::build_from_httparse(&parsed, false).unwrap();
+        let mut buf = vec![];
+        req.header_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"host: a.com\r\nhost: b.com\r\nx-id: 1\r\n");
+    }
+
+    #[test]
+    fn test_origin_form() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a/b?c=1").unwrap();
+        req.insert_header("Host", "a.com").unwrap();
+        let mut buf = vec![];
+        req.to_h1_wire(&mut buf);
+        assert_eq!(buf, b"GET /a/b?c=1 HTTP/1.1\r\nhost: a.com\r\n\r\n");
+    }
+
+    #[test]
+    fn test_absolute_form() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a?c=1").unwrap();
+        req.set_proxy_mode(true);
+        let mut buf = vec![];
+        req.request_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"GET /a?c=1 HTTP/1.1\r\n");
+
+        req.insert_header("Host", "a.com:8080").unwrap();
+        let mut buf = vec![];
+        req.request_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"GET http://a.com:8080/a?c=1 HTTP/1.1\r\n");
+
+        req.set_uri("https://b.com/x".parse().unwrap());
+        assert_eq!(req.target_form(), super::RequestTarget::Absolute);
+        let mut buf = vec![];
+        req.request_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"GET https://b.com/x HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_authority_form() {
+        let mut req = RequestHeader::build_with_method_path("CONNECT", b"/").unwrap();
+        req.set_uri("a.com:443".parse().unwrap());
+        assert_eq!(req.raw_path(), b"/");
+        assert_eq!(req.target_form(), super::RequestTarget::Authority);
+        let mut buf = vec![];
+        req.request_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"CONNECT a.com:443 HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_asterisk_form() {
+        let mut req = RequestHeader::build_with_method_path("OPTIONS", b"*").unwrap();
+        req.set_proxy_mode(true);
+        req.set_version(http::Version::HTTP_10);
+        let mut buf = vec![];
+        req.request_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"OPTIONS * HTTP/1.0\r\n");
+    }
+
+    #[test]
+    fn test_non_utf8_path() {
+        let req = RequestHeader::build_with_method_path("GET", b"/caf\xe9/\xe4\xbd\xa0?q=\xff").unwrap();
+        assert_eq!(req.raw_path(), b"/caf\xe9/\xe4\xbd\xa0?q=\xff");
+        assert_eq!(req.uri.path(), "/caf%E9/%E4%BD%A0");
+        assert_eq!(req.uri.query(), Some("q=%FF"));
+
+        let mut buf = vec![];
+        req.request_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"GET /caf\xe9/\xe4\xbd\xa0?q=\xff HTTP/1.1\r\n");
+
+        let mut req = req;
+        req.set_uri("/new".parse().unwrap());
+        assert_eq!(req.raw_path(), b"/new");
+    }
+
+    #[test]
+    fn test_lenient_path() {
+        assert!(RequestHeader::build_with_method_path("GET", b"/a<b>").is_err());
+        assert!(RequestHeader::build_with_method_path("GET", b"/a b").is_err());
+
+        let req = RequestHeader::build_with_method_path_lenient("GET", b"/a<b>`\x7f").unwrap();
+        assert_eq!(req.raw_path(), b"/a<b>`\x7f");
+        assert_eq!(req.uri.path(), "/a%3Cb%3E%60%7F");
+        assert!(RequestHeader::build_with_method_path_lenient("GET", b"/a\0").is_err());
+
+        let req = RequestHeader::build_with_method_path("GET", b"/plain?x=1").unwrap();
+        assert_eq!(req.raw_path(), b"/plain?x=1");
+    }
+
+    #[test]
+    fn test_raw_header_value() {
+        let input = b"GET /x HTTP/1.1\r\nX-Name: caf\xe9\r\n\r\n";
+        let mut headers = [httparse::EMPTY_HEADER; 4];
+        let mut parsed = httparse::Request::new(&mut headers);
+        parsed.parse(input).unwrap();
+
+        let req = RequestHeader::build_from_httparse(&parsed, true).unwrap();
+        assert_eq!(req.header_raw("x-name").unwrap(), b"caf\xe9");
+        let mut buf = vec![];
+        req.to_h1_wire(&mut buf);
+        assert_eq!(buf, &input[..]);
+
+        let req = RequestHeader::build_from_httparse(&parsed, false).unwrap();
+        assert_eq!(req.header_raw("X-NAME").unwrap(), b"caf\xe9");
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        req.insert_header("Host", "a").unwrap();
+        req.insert_header("Connection", "keep-alive, X-Secret, content-length").unwrap();
+        req.insert_header("Keep-Alive", "timeout=5").unwrap();
+        req.insert_header("Proxy-Connection", "keep-alive").unwrap();
+        req.insert_header("TE", "trailers").unwrap();
+        req.insert_header("X-Secret", "1").unwrap();
+        req.insert_header("Content-Length", "0").unwrap();
+        req.strip_hop_by_hop().unwrap();
+        let names: Vec<_> = req.headers.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["host", "content-length"]);
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/ws").unwrap();
+        req.preserve_header_case();
+        req.insert_header("Connection", "Upgrade, Keep-Alive").unwrap();
+        req.insert_header("Upgrade", "websocket").unwrap();
+        req.strip_hop_by_hop().unwrap();
+        let mut buf = vec![];
+        req.header_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"Upgrade: websocket\r\nconnection: upgrade\r\n");
+    }
+}
@@ -1,13 +1,19 @@
 use std::ops::Deref;
 
 use bytes::BufMut;
+use gateway_basic::util::case_sense_map::CaseSenseMap;
 use gateway_basic::util::small_case_string::SmallCaseString;
-use gateway_error::{ErrTrans, ErrorType};
+use gateway_error::{error_trait::OkOrErr, ErrTrans, Error, ErrorType};
 use http::{response, StatusCode};
 use http::{response::Parts, HeaderMap, HeaderName, HeaderValue, Version};
 use http::response::Builder as ReqBuilder;
 use gateway_error::Result;
-use super::{header_to_h1_wire, Opt};
+use crate::http::common::{hop_by_hop_names, CRLF};
+use crate::util_code::util_code::get_version_str;
+
+use super::{
+    case_header_to_h1_wire, header_to_h1_wire, map_header_values_in, operate_case_header, rename_header_in, Opt,
+};
 
 
 type ReqParts = Parts;
@@ -16,6 +22,8 @@ type ReqParts = Parts;
 pub struct ResponseHeader {
     base: ReqParts,
     reason_phrase: Option<String>,
+    /// original case and order of the headers, see [ResponseHeader::preserve_header_case]
+    header_case: Option<CaseSenseMap>,
 }
 
 
@@ -38,7 +46,8 @@ impl Clone for ResponseHeader {
     fn clone(&self) -> Self {
         Self { 
             base: self.base.clone(),
-            reason_phrase: self.reason_phrase.clone()
+            reason_phrase: self.reason_phrase.clone(),
+            header_case: self.header_case.clone(),
         }
     }
 }
@@ -49,6 +58,7 @@ impl ResponseHeader {
         ResponseHeader {
             base,
             reason_phrase: None,
+            header_case: None,
         }
     }
 
@@ -56,9 +66,30 @@ impl ResponseHeader {
         status_code: impl TryInto<StatusCode>,
     ) -> Result<Self> {
         let mut raw_resp = Self::new();
-        raw_resp.base.status = status_code
-            .try_into()
-            .to_b_err(ErrorType::InvalidHttpHeader, "invalid response Status")?;
+        raw_resp.base.status = Self::handle_status(status_code)?;
+        Ok(raw_resp)
+    }
+
+    /// build from a response head parsed by httparse.
+    ///
+    /// with `preserve_case` the headers are written back in their received case and order.
+    pub fn build_from_httparse(
+        resp: &httparse::Response<'_, '_>,
+        preserve_case: bool,
+    ) -> Result<Self> {
+        let code = resp.code.or_err(ErrorType::InvalidHttpHeader, "missing status code")?;
+        let mut raw_resp = Self::build_with_status_code(code)?;
+        raw_resp.set_reason_phrase(resp.reason)?;
+        raw_resp.set_version(match resp.version {
+            Some(0) => Version::HTTP_10,
+            _ => Version::HTTP_11,
+        });
+        if preserve_case {
+            raw_resp.preserve_header_case();
+        }
+        for header in resp.headers.iter() {
+            raw_resp.append_header(header.name, header.value)?;
+        }
         Ok(raw_resp)
     }
 
@@ -67,7 +98,10 @@ impl ResponseHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::APPEND);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -81,7 +115,10 @@ impl ResponseHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::REMOVE);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -95,7 +132,10 @@ impl ResponseHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::INSERT);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -109,7 +149,10 @@ impl ResponseHeader {
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
     ) -> Result<()> {
-        let (header_name, header_value) = Self::handle_name_value(name, value)?;
+        let (header_name, header_value, origin_name) = Self::handle_name_value(name, value)?;
+        if let Some(header_case) = self.header_case.as_mut() {
+            operate_case_header(header_case, origin_name, &header_value, &Opt::MODIFY);
+        }
         Self::operate_header_value(
             &mut self.base.headers,
             header_name,
@@ -118,6 +161,16 @@ impl ResponseHeader {
         )        
     }
 
+    /// rename every `from` header to `to`, keeping values and wire position.
+    pub fn rename_header(&mut self, from: &str, to: &str) -> Result<()> {
+        rename_header_in(&mut self.base.headers, self.header_case.as_mut(), from, to)
+    }
+
+    /// replace every value of `name` with what `f` makes of it, keeping case and wire position.
+    pub fn map_header_values(&mut self, name: &str, f: &mut dyn FnMut(&[u8]) -> Vec<u8>) -> Result<()> {
+        map_header_values_in(&mut self.base.headers, self.header_case.as_mut(), name, f)
+    }
+
     fn operate_header_value(
         value_map: &mut HeaderMap<HeaderValue>,
         key: HeaderName,   //-0 add -1 modify
@@ -144,15 +197,16 @@ impl ResponseHeader {
     fn handle_name_value(
         name: impl SmallCaseString,
         value: impl TryInto<HeaderValue>
-    ) -> Result<(HeaderName, HeaderValue)> {
+    ) -> Result<(HeaderName, HeaderValue, String)> {
         let header_value = value
             .try_into()
             .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head value")?;
+        let origin_name = name.to_string();
         let header_name = name.into_small_case_header()
             .as_slice()
             .try_into()
             .to_b_err(ErrorType::InvalidHttpHeader, "invalid http head name")?;
-        Ok((header_name, header_value))
+        Ok((header_name, header_value, origin_name))
     }
 
     pub fn set_version(&mut self, version: Version) {
@@ -161,17 +215,35 @@ impl ResponseHeader {
 
     pub fn set_status(&mut self, status: impl TryInto<StatusCode>)
     -> Result<()> {
-        self.base.status = status
-            .try_into()
-            .to_b_err(ErrorType::InvalidHttpHeader, "invalid status")?;
+        self.base.status = Self::handle_status(status)?;
         Ok(())
     }
 
+    /// only 100 to 599 are defined, see RFC 9110 section 15
+    fn handle_status(status: impl TryInto<StatusCode>) -> Result<StatusCode> {
+        let status = status
+            .try_into()
+            .to_b_err(ErrorType::InvalidHttpHeader, "invalid response Status")?;
+        if status.as_u16() > 599 {
+            return Error::generate_error_with_root(ErrorType::InvalidHttpHeader,
+                &format!("invalid response Status {}", status.as_u16()), None);
+        }
+        Ok(status)
+    }
+
+    /// override the reason phrase, `None` or the canonical one means using the canonical one.
     pub fn set_reason_phrase(&mut self, reason_phrase: Option<&str>) -> Result<()> {
         if reason_phrase == self.base.status.canonical_reason() {
             self.reason_phrase = None;
             return Ok(());
         }
+        if let Some(reason) = reason_phrase {
+            // reason-phrase = *( HTAB / SP / VCHAR / obs-text )
+            if reason.bytes().any(|b| b != b'\t' && (b < b' ' || b == 0x7f)) {
+                return Error::generate_error_with_root(ErrorType::InvalidHttpHeader,
+                    "invalid reason phrase", None);
+            }
+        }
         self.reason_phrase = reason_phrase.map(str::to_string);
         Ok(())
     }
@@ -182,8 +254,58 @@ impl ResponseHeader {
             .or_else(|| self.base.status.canonical_reason())
     }
 
+    /// keep the original case and order of headers added from now on,
+    /// headers already present are recorded lowercase.
+    pub fn preserve_header_case(&mut self) {
+        if self.header_case.is_none() {
+            self.header_case = Some(CaseSenseMap::from(&self.base.headers));
+        }
+    }
+
+    pub fn header_case_preserved(&self) -> bool {
+        self.header_case.is_some()
+    }
+
+    /// remove the hop-by-hop headers before forwarding, see [hop_by_hop_names].
+    ///
+    /// a `101 Switching Protocols` keeps `Upgrade` and is sent with `Connection: upgrade`.
+    pub fn strip_hop_by_hop(&mut self) -> Result<()> {
+        let upgrade = self.base.status == StatusCode::SWITCHING_PROTOCOLS;
+        for name in hop_by_hop_names(&self.base.headers, upgrade) {
+            self.remove_header(name, "")?;
+        }
+        if upgrade {
+            self.insert_header(http::header::CONNECTION, "upgrade")?;
+        }
+        Ok(())
+    }
+
+    /// write `version SP status-code SP reason-phrase CRLF`.
+    ///
+    /// the reason phrase may be empty for a status without canonical reason.
+    pub fn status_line_to_h1_wire(&self, buf: &mut impl BufMut) {
+        buf.put_slice(get_version_str(&self.base.version).as_bytes());
+        buf.put_u8(b' ');
+        buf.put_slice(self.base.status.as_str().as_bytes());
+        buf.put_u8(b' ');
+        if let Some(reason) = self.get_reason_phrase() {
+            buf.put_slice(reason.as_bytes());
+        }
+        buf.put_slice(CRLF);
+    }
+
+    /// write the whole response head: status line, headers and the empty line.
+    pub fn to_h1_wire(&self, buf: &mut impl BufMut) {
+        self.status_line_to_h1_wire(buf);
+        self.header_to_h1_wire(buf);
+        buf.put_slice(CRLF);
+    }
+
     pub fn header_to_h1_wire(&self, buf: &mut impl BufMut) {
-        header_to_h1_wire(&self.base.headers, buf)
+        match self.header_case.as_ref() {
+            Some(header_case) => case_header_to_h1_wire(header_case, buf),
+            None => header_to_h1_wire(&self.base.headers, buf),
+        }
     }
 }
 
@@ -208,4 +330,77 @@ mod tests {
         let req = ResponseHeader::build_with_status_code("999");
         assert!(req.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_build_from_httparse_preserve_case() {
+        let input = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nSet-Cookie: a=1\r\nX-Id: 1\r\nSet-Cookie: b=2\r\n\r\n";
+        let mut headers = [httparse::EMPTY_HEADER; 8];
+        let mut parsed = httparse::Response::new(&mut headers);
+        parsed.parse(input).unwrap();
+
+        let mut resp = ResponseHeader::build_from_httparse(&parsed, true).unwrap();
+        assert_eq!(resp.status, 200);
+        resp.append_header("Via", "crab").unwrap();
+        let mut buf = vec![];
+        resp.header_to_h1_wire(&mut buf);
+        assert_eq!(
+            buf,
+            b"Content-Type: text/plain\r\nSet-Cookie: a=1\r\nX-Id: 1\r\nSet-Cookie: b=2\r\nVia: crab\r\n"
+        );
+    }
+
+    #[test]
+    fn test_status_line() {
+        let mut resp = ResponseHeader::build_with_status_code(404).unwrap();
+        resp.insert_header("Content-Length", 0).unwrap();
+        let mut buf = vec![];
+        resp.to_h1_wire(&mut buf);
+        assert_eq!(buf, b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n");
+
+        resp.set_version(http::Version::HTTP_10);
+        resp.set_reason_phrase(Some("Nothing Here")).unwrap();
+        let mut buf = vec![];
+        resp.status_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"HTTP/1.0 404 Nothing Here\r\n");
+
+        assert!(resp.set_reason_phrase(Some("bad\r\nx-injected: 1")).is_err());
+
+        let resp = ResponseHeader::build_with_status_code(599).unwrap();
+        let mut buf = vec![];
+        resp.status_line_to_h1_wire(&mut buf);
+        assert_eq!(buf, b"HTTP/1.1 599 \r\n");
+    }
+
+    #[test]
+    fn test_keep_upstream_reason_phrase() {
+        let input = b"HTTP/1.1 200 Everything Fine\r\nServer: up\r\n\r\n";
+        let mut headers = [httparse::EMPTY_HEADER; 4];
+        let mut parsed = httparse::Response::new(&mut headers);
+        parsed.parse(input).unwrap();
+
+        let resp = ResponseHeader::build_from_httparse(&parsed, true).unwrap();
+        assert_eq!(resp.get_reason_phrase(), Some("Everything Fine"));
+        let mut buf = vec![];
+        resp.to_h1_wire(&mut buf);
+        assert_eq!(buf, &input[..]);
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut resp = ResponseHeader::build_with_status_code(200).unwrap();
+        resp.insert_header("Connection", "close, X-Internal").unwrap();
+        resp.insert_header("X-Internal", "1").unwrap();
+        resp.insert_header("Upgrade", "h2c").unwrap();
+        resp.insert_header("Server", "up").unwrap();
+        resp.strip_hop_by_hop().unwrap();
+        let names: Vec<_> = resp.headers.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["server"]);
+
+        let mut resp = ResponseHeader::build_with_status_code(101).unwrap();
+        resp.insert_header("Connection", "Upgrade").unwrap();
+        resp.insert_header("Upgrade", "websocket").unwrap();
+        resp.strip_hop_by_hop().unwrap();
+        assert_eq!(resp.headers.get("upgrade").unwrap(), "websocket");
+        assert_eq!(resp.headers.get("connection").unwrap(), "upgrade");
+    }
+}
@@ -1,9 +1,9 @@
 
 use bytes::{BufMut, BytesMut};
-use gateway_error::{Error as Error, ErrorType, Result};
+use gateway_error::{Error as Error, ErrorSource, ErrorType, Result};
 use http::{HeaderName, HeaderValue};
 use gateway_error::error_trait::OrErr;
-use crate::{connections::response::ResponseHeader, http::common::*, util_code::util_code::get_version_str};
+use crate::{connections::response::ResponseHeader, http::{common::*, error_resp::PROXY_STATUS}, util_code::util_code::get_version_str};
 
 use http::request::Parts as ReqHeader;
 
@@ -34,7 +34,6 @@ impl std::error::Error for ConnectProxyError {}
 
 impl std::fmt::Display for ConnectProxyError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        const PROXY_STATUS: &str = "proxy-status";
         let reason = self
             .response
             .headers
@@ -52,8 +51,11 @@ impl std::fmt::Display for ConnectProxyError {
 #[inline]
 fn validate_connect_response(resp: Box<ResponseHeader>) -> Result<ProxyDigest> {
     if !resp.status.is_success() {
-        return Error::generate_error_with_root(ErrorType::ConnectProxyError, &format!("Not STATUS 200 BUT {}", resp.status.as_str()),
-    Some(ConnectProxyError::boxed_new(resp)));
+        return Error::build(ErrorType::ConnectProxyError)
+            .source(ErrorSource::UpStream)
+            .context(format!("Not STATUS 200 BUT {}", resp.status.as_str()))
+            .cause(ConnectProxyError::boxed_new(resp))
+            .err();
     }
     Ok(ProxyDigest::new(resp))
 }
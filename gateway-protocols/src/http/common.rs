@@ -1,6 +1,7 @@
 use core::fmt::Debug;
-use std::{any::Any, time::Duration};
-use http::header;
+use std::{any::Any, future::Future, time::Duration};
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use http::{header, HeaderMap, HeaderName, HeaderValue};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::connections::{digest::{GetProxyDigest, GetTimingDigest}, request::RequestHeader};
@@ -50,6 +51,96 @@ pub trait IO:
 }
 
 #[inline]
-pub(super) fn is_upgrade_req(req: &RequestHeader) -> bool {
+pub(crate) fn is_upgrade_req(req: &RequestHeader) -> bool {
     req.version == http::Version::HTTP_11 && req.headers.get(header::UPGRADE).is_some()
-}
\ No newline at end of file
+}
+
+#[inline]
+pub(super) fn is_chunked_encoding(te_value: Option<&HeaderValue>) -> bool {
+    match te_value {
+        Some(value) => value
+            .as_bytes()
+            .rsplit(|b| *b == b',')
+            .next()
+            .map(|last| last.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// run `fut` within `timeout`, failing with `etype` from `source` once it is reached.
+///
+/// `None` waits forever.
+pub(super) async fn with_timeout<T, F>(
+    timeout: Option<Duration>,
+    etype: ErrorType,
+    source: ErrorSource,
+    context: &'static str,
+    fut: F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let Some(timeout) = timeout else {
+        return fut.await;
+    };
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(result) => result,
+        Err(_) => Error::build(etype)
+            .source(source)
+            .context(format!("{context}, timeout: {timeout:?}"))
+            .err(),
+    }
+}
+
+#[inline]
+pub(super) fn header_value_content_length(cl_value: Option<&HeaderValue>) -> Option<usize> {
+    cl_value
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+}
+
+/// headers only meaningful for a single connection, see RFC 9110 section 7.6.1
+pub const HOP_BY_HOP_HEADERS: [HeaderName; 5] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::TE,
+    header::UPGRADE,
+];
+
+/// headers which a `Connection` option must never remove, dropping them would
+/// change how the message is framed or routed.
+const PROTECTED_HEADERS: [HeaderName; 3] = [
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+];
+
+/// The hop-by-hop headers present in `headers`, including the ones listed in `Connection`.
+///
+/// `Upgrade` is left out when `keep_upgrade`.
+pub fn hop_by_hop_names(headers: &HeaderMap, keep_upgrade: bool) -> Vec<HeaderName> {
+    let mut names: Vec<HeaderName> = HOP_BY_HOP_HEADERS
+        .iter()
+        .filter(|name| !(keep_upgrade && **name == header::UPGRADE))
+        .filter(|name| headers.contains_key(*name))
+        .cloned()
+        .collect();
+    for value in headers.get_all(header::CONNECTION) {
+        for option in value.as_bytes().split(|b| *b == b',') {
+            let Ok(name) = HeaderName::from_bytes(option.trim_ascii()) else {
+                continue;
+            };
+            if (keep_upgrade && name == header::UPGRADE)
+                || PROTECTED_HEADERS.contains(&name)
+                || names.contains(&name)
+                || !headers.contains_key(&name)
+            {
+                continue;
+            }
+            names.push(name);
+        }
+    }
+    names
+}
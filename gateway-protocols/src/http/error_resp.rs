@@ -0,0 +1,233 @@
+use bytes::Bytes;
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use http::StatusCode;
+
+use crate::connections::response::ResponseHeader;
+
+pub const PROXY_STATUS: &str = "proxy-status";
+
+/// Pick the downstream status for an error.
+///
+/// `HttpCode` and `CustomCode` carry their own status, everything else is judged by
+/// the [ErrorType] and which side the error came from. A status outside `100..=599`
+/// can't be sent, so it becomes 500.
+pub fn error_status(e: &Error) -> StatusCode {
+    let code = match e.etype() {
+        ErrorType::HttpCode(code) => *code,
+        ErrorType::CustomCode(_, code) => *code,
+        ErrorType::ConnectTimeout | ErrorType::PerTryTimeout => 504,
+        ErrorType::ConnectRefused
+        | ErrorType::DnsError
+        | ErrorType::NoUpstream
+        | ErrorType::ConnectProxyError => 502,
+        ErrorType::CircuitBreakerOpen => 503,
+        ErrorType::InvalidHttpHeader => match e.esource() {
+            ErrorSource::UpStream => 502,
+            _ => 400,
+        },
+        ErrorType::ReadTimedout | ErrorType::WriteTimedout => match e.esource() {
+            ErrorSource::UpStream => 504,
+            ErrorSource::DownStream => 408,
+            _ => 500,
+        },
+        ErrorType::ReadError | ErrorType::WriteError | ErrorType::ConnectionClosed => {
+            match e.esource() {
+                ErrorSource::UpStream => 502,
+                ErrorSource::DownStream => 400,
+                _ => 500,
+            }
+        }
+        ErrorType::InternalError
+        | ErrorType::BindError
+        | ErrorType::SocketError
+        | ErrorType::Custom(_) => 500,
+    };
+    match code {
+        100..=599 => StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The RFC 9209 error type describing `e`.
+pub fn proxy_status_error(e: &Error) -> &'static str {
+    let upstream = *e.esource() == ErrorSource::UpStream;
+    match e.etype() {
+        ErrorType::ConnectTimeout => "connection_timeout",
+        ErrorType::ConnectRefused => "connection_refused",
+        ErrorType::DnsError => "dns_error",
+        ErrorType::NoUpstream | ErrorType::ConnectProxyError => "destination_unavailable",
+        ErrorType::PerTryTimeout => "http_response_timeout",
+        ErrorType::CircuitBreakerOpen => "connection_limit_reached",
+        ErrorType::ConnectionClosed | ErrorType::WriteError if upstream => "connection_terminated",
+        ErrorType::ReadError if upstream => "http_response_incomplete",
+        ErrorType::ReadTimedout | ErrorType::WriteTimedout if upstream => "http_response_timeout",
+        ErrorType::InvalidHttpHeader if upstream => "http_protocol_error",
+        ErrorType::InvalidHttpHeader
+        | ErrorType::ReadError
+        | ErrorType::WriteError
+        | ErrorType::ReadTimedout
+        | ErrorType::WriteTimedout
+        | ErrorType::ConnectionClosed => "http_request_error",
+        ErrorType::HttpCode(_) | ErrorType::CustomCode(_, _) => "proxy_internal_response",
+        ErrorType::InternalError
+        | ErrorType::BindError
+        | ErrorType::SocketError
+        | ErrorType::Custom(_) => "proxy_internal_error",
+    }
+}
+
+/// Generate the `Proxy-Status` header value, e.g.
+///
+/// ```ruby
+/// crab-gateway; error=connection_refused; details="connect upstream"
+/// ```
+///
+/// `details` exposes the error description, leave it off when downstream is not trusted.
+pub fn proxy_status_value(proxy_name: &str, e: &Error, details: bool) -> String {
+    let mut value = format!("{proxy_name}; error={}", proxy_status_error(e));
+    if details {
+        if let Some(description) = e.description() {
+            value.push_str("; details=");
+            push_sf_string(&mut value, description);
+        }
+    }
+    value
+}
+
+/// write `s` as a structured field string, see RFC 8941 section 3.3.3
+fn push_sf_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => out.push('?'),
+        }
+    }
+    out.push('"');
+}
+
+/// Render the body of an error response.
+pub trait ErrorPageRenderer: Send + Sync {
+    fn content_type(&self) -> &'static str;
+
+    fn render(&self, status: StatusCode, e: &Error) -> Bytes;
+}
+
+/// nginx-like html page.
+pub struct HtmlErrorPage;
+
+/// `{"status":502,"reason":"Bad Gateway","error":"connection_refused"}`
+pub struct JsonErrorPage;
+
+impl ErrorPageRenderer for HtmlErrorPage {
+    fn content_type(&self) -> &'static str {
+        "text/html; charset=utf-8"
+    }
+
+    fn render(&self, status: StatusCode, _e: &Error) -> Bytes {
+        let title = format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or(""));
+        Bytes::from(format!(
+            "<html>\r\n<head><title>{title}</title></head>\r\n<body>\r\n<center><h1>{title}</h1></center>\r\n</body>\r\n</html>\r\n"
+        ))
+    }
+}
+
+impl ErrorPageRenderer for JsonErrorPage {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn render(&self, status: StatusCode, e: &Error) -> Bytes {
+        Bytes::from(format!(
+            "{{\"status\":{},\"reason\":\"{}\",\"error\":\"{}\"}}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or(""),
+            proxy_status_error(e)
+        ))
+    }
+}
+
+/// Build the downstream response for `e`, with the `Proxy-Status` header and a rendered body.
+pub fn gen_error_response(
+    e: &Error,
+    proxy_name: &str,
+    renderer: &dyn ErrorPageRenderer,
+) -> Result<(ResponseHeader, Bytes)> {
+    let status = error_status(e);
+    let body = renderer.render(status, e);
+    let mut resp = ResponseHeader::build_with_status_code(status)?;
+    resp.insert_header(http::header::CONTENT_TYPE, renderer.content_type())?;
+    resp.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+    resp.insert_header(http::header::CACHE_CONTROL, "private, no-store")?;
+    resp.insert_header(PROXY_STATUS, proxy_status_value(proxy_name, e, false))?;
+    Ok((resp, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use gateway_error::{Error, ErrorType};
+
+    use super::*;
+
+    #[test]
+    fn test_error_status() {
+        let e = Error::generate_error_with_root_raw(ErrorType::ConnectRefused, "", None);
+        assert_eq!(error_status(&e), 502);
+        let e = Error::generate_error_with_root_raw(ErrorType::ConnectTimeout, "", None);
+        assert_eq!(error_status(&e), 504);
+        let e = Error::generate_error_with_root_raw(ErrorType::InvalidHttpHeader, "", None);
+        assert_eq!(error_status(&e), 400);
+        let e = Error::generate_error_with_root_raw(ErrorType::CircuitBreakerOpen, "", None);
+        assert_eq!(error_status(&e), 503);
+        assert_eq!(proxy_status_error(&e), "connection_limit_reached");
+        let e = Error::build(ErrorType::ReadTimedout).source(ErrorSource::DownStream).finish();
+        assert_eq!(error_status(&e), 408);
+        let e = Error::build(ErrorType::WriteTimedout).source(ErrorSource::UpStream).finish();
+        assert_eq!(error_status(&e), 504);
+        assert_eq!(proxy_status_error(&e), "http_response_timeout");
+        let e = Error::generate_error_with_root_raw(ErrorType::HttpCode(429), "", None);
+        assert_eq!(error_status(&e), 429);
+        let e = Error::generate_error_with_root_raw(ErrorType::new_custom_with_code("teapot", 418), "", None);
+        assert_eq!(error_status(&e), 418);
+        let e = Error::generate_error_with_root_raw(ErrorType::HttpCode(1000), "", None);
+        assert_eq!(error_status(&e), 500);
+    }
+
+    #[test]
+    fn test_proxy_status_value() {
+        let e = Error::generate_error_with_root_raw(ErrorType::ConnectRefused, "say \"no\"\n", None);
+        assert_eq!(
+            proxy_status_value("crab", &e, true),
+            "crab; error=connection_refused; details=\"say \\\"no\\\"?\""
+        );
+        assert_eq!(proxy_status_value("crab", &e, false), "crab; error=connection_refused");
+    }
+
+    #[test]
+    fn test_gen_error_response() {
+        let e = Error::generate_error_with_root_raw(ErrorType::ConnectTimeout, "upstream a", None);
+        let (resp, body) = gen_error_response(&e, "crab", &JsonErrorPage).unwrap();
+        assert_eq!(resp.status, 504);
+        assert_eq!(
+            &body[..],
+            b"{\"status\":504,\"reason\":\"Gateway Timeout\",\"error\":\"connection_timeout\"}"
+        );
+        assert_eq!(resp.headers.get(PROXY_STATUS).unwrap(), "crab; error=connection_timeout");
+        assert_eq!(resp.headers.get(http::header::CONTENT_LENGTH).unwrap(), &body.len().to_string());
+
+        let (resp, body) = gen_error_response(&e, "crab", &HtmlErrorPage).unwrap();
+        assert_eq!(resp.headers.get(http::header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        assert!(String::from_utf8_lossy(&body).contains("<h1>504 Gateway Timeout</h1>"));
+
+        // codes a response can't carry still get an error page
+        for etype in [ErrorType::HttpCode(600), ErrorType::new_custom_with_code("odd", 999)] {
+            let e = Error::generate_error_with_root_raw(etype, "", None);
+            let (resp, _) = gen_error_response(&e, "crab", &JsonErrorPage).unwrap();
+            assert_eq!(resp.status, 500);
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod common;
-pub mod v1;
\ No newline at end of file
+pub mod v1;
+pub mod error_resp;
\ No newline at end of file
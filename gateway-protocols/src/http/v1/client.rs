@@ -1,9 +1,11 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use gateway_error::{error_trait::OrErr, BErr, Error, ErrorSource, ErrorType, Result};
 use http::HeaderMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::{connections::{digest::Digest, request::RequestHeader, response::ResponseHeader}, http::common::{is_upgrade_req, KeepaliveStatus, Stream}, util_code::buf_ref::BufRef};
+use crate::{connections::{digest::{Digest, RequestTiming}, request::RequestHeader, response::ResponseHeader}, http::common::{header_value_content_length, is_chunked_encoding, is_upgrade_req, with_timeout, KeepaliveStatus, Stream, INIT_HEADER_BUF_SIZE, MAX_HEADERS, MAX_HEADER_SIZE}, util_code::buf_ref::BufRef};
 
 use super::body::{BodyReader, BodyWriter};
 
@@ -24,13 +26,21 @@ pub struct HttpSession {
     request_header: Option<Box<RequestHeader>>,
     bytes_sent: usize,
     upgraded: bool,
+    /// when the request header started being written
+    request_start: Option<Instant>,
+    /// when the request header was written
+    header_sent: Option<Instant>,
 }
 
 impl HttpSession {
     pub fn new(stream: Stream) -> Self {
+        let timing_digest = stream.get_timing_digest();
+        let mut request_timing = RequestTiming::default();
+        request_timing.set_connection(&timing_digest);
         let digest = Box::new(Digest {
-            timing_digest: stream.get_timing_digest(),
+            timing_digest,
             proxy_digest: stream.get_proxy_digest(),
+            request_timing,
         });
         HttpSession {
             underlying_stream: stream,
@@ -47,11 +57,229 @@ impl HttpSession {
             digest,
             bytes_sent: 0,
             upgraded: false,
+            request_start: None,
+            header_sent: None,
         }
     }
 
+    /// write the request header, failing with [ErrorType::WriteTimedout] after `write_timeout`.
     pub async fn write_request_header(&mut self, req: Box<RequestHeader>) -> Result<usize> {
-        self.init
+        self.init_req_body_writer(&req);
+        let start = Instant::now();
+        self.request_start = Some(start);
+
+        let mut buf = BytesMut::with_capacity(INIT_HEADER_BUF_SIZE);
+        req.to_h1_wire(&mut buf);
+
+        let stream = &mut self.underlying_stream;
+        let write = async {
+            stream
+                .write_all(&buf)
+                .await
+                .or_err_source(ErrorType::WriteError, ErrorSource::UpStream, "while writing request headers")?;
+            stream
+                .flush()
+                .await
+                .or_err_source(ErrorType::WriteError, ErrorSource::UpStream, "flushing request headers")
+        };
+        with_timeout(
+            self.write_timeout,
+            ErrorType::WriteTimedout,
+            ErrorSource::UpStream,
+            "writing request headers",
+            write,
+        )
+        .await?;
+        self.digest.request_timing.header_send = Some(start.elapsed());
+        self.header_sent = Some(Instant::now());
+        self.request_header = Some(req);
+        Ok(buf.len())
+    }
+
+    /// write a piece of the request body, failing with [ErrorType::WriteTimedout] after `write_timeout`.
+    pub async fn write_body(&mut self, buf: &[u8]) -> Result<Option<usize>> {
+        let start = Instant::now();
+        let write = self.body_writer.write_body(&mut self.underlying_stream, buf);
+        let write = async { write.await.map_err(upstream_error) };
+        let written = with_timeout(
+            self.write_timeout,
+            ErrorType::WriteTimedout,
+            ErrorSource::UpStream,
+            "writing request body",
+            write,
+        )
+        .await?;
+        accumulate(&mut self.digest.request_timing.body_send, start.elapsed());
+        self.bytes_sent += written.unwrap_or(0);
+        Ok(written)
+    }
+
+    /// finish the request body, e.g. write the last chunk.
+    pub async fn finish_body(&mut self) -> Result<Option<usize>> {
+        let start = Instant::now();
+        let finish = self.body_writer.finish(&mut self.underlying_stream);
+        let finish = async { finish.await.map_err(upstream_error) };
+        let written = with_timeout(
+            self.write_timeout,
+            ErrorType::WriteTimedout,
+            ErrorSource::UpStream,
+            "finishing request body",
+            finish,
+        )
+        .await?;
+        accumulate(&mut self.digest.request_timing.body_send, start.elapsed());
+        Ok(written)
+    }
+
+    pub fn bytes_sent(&self) -> usize {
+        self.bytes_sent
+    }
+
+    /// read and parse the response header, failing with [ErrorType::ReadTimedout]
+    /// when it is not complete after `read_timeout`.
+    ///
+    /// return the size of the header.
+    pub async fn read_response(&mut self) -> Result<usize> {
+        let timeout = self.read_timeout;
+        with_timeout(
+            timeout,
+            ErrorType::ReadTimedout,
+            ErrorSource::UpStream,
+            "reading response header",
+            self.do_read_response(),
+        )
+        .await
+    }
+
+    async fn do_read_response(&mut self) -> Result<usize> {
+        let mut buf = BytesMut::with_capacity(INIT_HEADER_BUF_SIZE);
+        let mut first_byte = None;
+        loop {
+            if buf.len() >= MAX_HEADER_SIZE {
+                return Error::build(ErrorType::InvalidHttpHeader)
+                    .source(ErrorSource::UpStream)
+                    .context(format!("response header larger than {MAX_HEADER_SIZE}"))
+                    .err();
+            }
+            let n = self
+                .underlying_stream
+                .read_buf(&mut buf)
+                .await
+                .or_err_source(ErrorType::ReadError, ErrorSource::UpStream, "while reading response headers")?;
+            if n == 0 {
+                return Error::build(ErrorType::ConnectionClosed)
+                    .source(ErrorSource::UpStream)
+                    .context("upstream closed before the response header")
+                    .err();
+            }
+            if first_byte.is_none() {
+                let now = Instant::now();
+                first_byte = Some(now);
+                self.digest.request_timing.ttfb = self.header_sent.map(|sent| now - sent);
+            }
+
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut resp = httparse::Response::new(&mut headers);
+            match resp.parse(&buf) {
+                Ok(httparse::Status::Complete(len)) => {
+                    let header = ResponseHeader::build_from_httparse(&resp, false).map_err(|e| {
+                        Error::build(ErrorType::InvalidHttpHeader)
+                            .source(ErrorSource::UpStream)
+                            .context("invalid response header")
+                            .cause(e)
+                            .finish()
+                    })?;
+                    let buf = buf.freeze();
+                    self.raw_header = Some(BufRef(0, len));
+                    self.preread_body = Some(BufRef(len, buf.len()));
+                    self.buf = buf;
+                    self.init_resp_body_reader(&header);
+                    self.response_header = Some(Box::new(header));
+                    self.digest.request_timing.header_receive = first_byte.map(|t| t.elapsed());
+                    if self.body_reader.body_done() {
+                        self.finish_timing();
+                    }
+                    return Ok(len);
+                }
+                Ok(httparse::Status::Partial) => continue,
+                Err(e) => {
+                    return Error::build(ErrorType::InvalidHttpHeader)
+                        .source(ErrorSource::UpStream)
+                        .context("invalid response header")
+                        .cause(e)
+                        .err();
+                }
+            }
+        }
+    }
+
+    pub fn response_header(&self) -> Option<&ResponseHeader> {
+        self.response_header.as_deref()
+    }
+
+    /// read the next piece of the response body, failing with [ErrorType::ReadTimedout]
+    /// when nothing arrives within `read_timeout`.
+    ///
+    /// `None` once the body is done.
+    pub async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        let start = Instant::now();
+        let read = self.body_reader.do_read_body(&mut self.underlying_stream);
+        let read = async { read.await.map_err(incomplete_response) };
+        let piece = with_timeout(
+            self.read_timeout,
+            ErrorType::ReadTimedout,
+            ErrorSource::UpStream,
+            "reading response body",
+            read,
+        )
+        .await?;
+        if piece.is_some() {
+            accumulate(&mut self.digest.request_timing.body_receive, start.elapsed());
+        }
+        if piece.is_none() || self.body_reader.body_done() {
+            self.finish_timing();
+        }
+        Ok(piece.map(|piece| Bytes::copy_from_slice(self.body_reader.get_body(&piece))))
+    }
+
+    /// the response is complete, stop the total timer once.
+    fn finish_timing(&mut self) {
+        let timing = &mut self.digest.request_timing;
+        if timing.total.is_none() {
+            timing.total = self.request_start.map(|start| start.elapsed());
+        }
+    }
+
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+
+    /// the timing of the current request, connection phases included.
+    pub fn request_timing(&self) -> &RequestTiming {
+        &self.digest.request_timing
+    }
+
+    pub fn is_body_done(&self) -> bool {
+        self.body_reader.body_done()
+    }
+
+    fn init_resp_body_reader(&mut self, resp: &ResponseHeader) {
+        let preread = self.preread_body.as_ref().map(|b| b.get(&self.buf)).unwrap_or_default();
+        let head = self.request_header.as_ref().is_some_and(|req| req.method == http::Method::HEAD);
+        let status = resp.status.as_u16();
+        if status == 101 {
+            self.upgraded = true;
+            self.body_reader.init_http10(preread);
+        } else if head || (100..200).contains(&status) || status == 204 || status == 304 {
+            self.body_reader.init_content_length(0, preread);
+        } else if is_chunked_encoding(resp.headers.get(http::header::TRANSFER_ENCODING)) {
+            self.body_reader.init_chunked(preread);
+        } else {
+            match header_value_content_length(resp.headers.get(http::header::CONTENT_LENGTH)) {
+                Some(length) => self.body_reader.init_content_length(length, preread),
+                None => self.body_reader.init_http10(preread),
+            }
+        }
     }
 
     fn init_req_body_writer(&mut self, header: &RequestHeader) {
@@ -64,6 +292,144 @@ impl HttpSession {
 
     fn init_body_writer_comm(&mut self, headers: &HeaderMap) {
         let te_value = headers.get(http::header::TRANSFER_ENCODING);
-        
+        if is_chunked_encoding(te_value) {
+            self.body_writer.init_chunked();
+        } else {
+            match header_value_content_length(headers.get(http::header::CONTENT_LENGTH)) {
+                Some(length) => self.body_writer.init_content_length(length),
+                None => self.body_writer.init_http10(),
+            }
+        }
+    }
+}
+fn accumulate(total: &mut Option<Duration>, elapsed: Duration) {
+    *total.get_or_insert(Duration::ZERO) += elapsed;
+}
+
+/// the body reader and writer leave the source of their errors open, here it is the upstream.
+fn upstream_error(mut e: BErr) -> BErr {
+    if *e.esource() == ErrorSource::Undefined {
+        e.set_source(ErrorSource::UpStream);
+    }
+    e
+}
+
+/// the upstream closing in the middle of the body leaves the response incomplete.
+fn incomplete_response(e: BErr) -> BErr {
+    if *e.etype() != ErrorType::ConnectionClosed {
+        return upstream_error(e);
+    }
+    Error::build(ErrorType::ReadError)
+        .source(ErrorSource::UpStream)
+        .context("upstream closed before the end of the response body")
+        .cause(e)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{
+        http::error_resp::{error_status, proxy_status_error},
+        l4::stream::Stream as L4Stream,
+    };
+
+    /// a session to a server which writes `response` and then holds the connection open.
+    async fn connect(response: &'static [u8]) -> (HttpSession, tokio::task::JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            s.write_all(response).await.unwrap();
+            s
+        });
+        let stream = L4Stream::from(TcpStream::connect(addr).await.unwrap());
+        (HttpSession::new(Box::new(stream)), server)
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn read_response() {
+        let (mut session, _server) = connect(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+        session.read_timeout = Some(Duration::from_secs(1));
+        let len = session.read_response().await.unwrap();
+        assert_eq!(len, 38);
+        assert_eq!(session.response_header().unwrap().status, 200);
+        assert_eq!(session.read_body().await.unwrap().unwrap(), "hello");
+        assert!(session.read_body().await.unwrap().is_none());
+        assert!(session.is_body_done());
+    }
+
+    #[tokio::test]
+    async fn request_timing() {
+        let (mut session, _server) = connect(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let mut req = RequestHeader::build_with_method_path("POST", b"/").unwrap();
+        req.insert_header("Content-Length", 4).unwrap();
+        session.write_request_header(Box::new(req)).await.unwrap();
+        session.write_body(b"ping").await.unwrap();
+        session.finish_body().await.unwrap();
+        session.read_response().await.unwrap();
+        assert!(session.request_timing().total.is_none());
+        while session.read_body().await.unwrap().is_some() {}
+
+        let timing = *session.request_timing();
+        // a stream made from a TcpStream knows nothing about its connect
+        assert_eq!(timing.connect, None);
+        for phase in [timing.header_send, timing.ttfb, timing.header_receive, timing.body_send, timing.body_receive] {
+            assert!(phase.unwrap() <= timing.total.unwrap());
+        }
+        let names: Vec<&str> = timing.phases().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["header_send", "ttfb", "header_receive", "body_send", "body_receive", "total"]);
+    }
+
+    #[tokio::test]
+    async fn read_timeouts() {
+        let (mut session, _server) = connect(b"HTTP/1.1 200 OK\r\n").await;
+        session.read_timeout = Some(Duration::from_millis(50));
+        let e = session.read_response().await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ReadTimedout);
+        assert_eq!(*e.esource(), ErrorSource::UpStream);
+        assert_eq!(error_status(&e), 504);
+
+        let (mut session, _server) = connect(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc").await;
+        session.read_timeout = Some(Duration::from_millis(50));
+        session.read_response().await.unwrap();
+        assert_eq!(session.read_body().await.unwrap().unwrap(), "abc");
+        let e = session.read_body().await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ReadTimedout);
+    }
+
+    #[tokio::test]
+    async fn upstream_closes_mid_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc").await.unwrap();
+        });
+        let stream = L4Stream::from(TcpStream::connect(addr).await.unwrap());
+        let mut session = HttpSession::new(Box::new(stream));
+        session.read_response().await.unwrap();
+        assert_eq!(session.read_body().await.unwrap().unwrap(), "abc");
+        let e = session.read_body().await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ReadError);
+        assert_eq!(*e.esource(), ErrorSource::UpStream);
+        assert_eq!(error_status(&e), 502);
+        assert_eq!(proxy_status_error(&e), "http_response_incomplete");
+    }
+
+    #[tokio::test]
+    async fn write_timeout() {
+        // the server never reads, so the socket buffers fill up
+        let (mut session, _server) = connect(b"").await;
+        session.write_timeout = Some(Duration::from_millis(100));
+        let size = 64 * 1024 * 1024;
+        let mut req = RequestHeader::build_with_method_path("POST", b"/").unwrap();
+        req.insert_header("Content-Length", size).unwrap();
+        session.write_request_header(Box::new(req)).await.unwrap();
+        let e = session.write_body(&vec![b'a'; size]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::WriteTimedout);
+        assert_eq!(*e.esource(), ErrorSource::UpStream);
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    task::JoinSet,
+};
+
+use super::{
+    resolver::{CacheConfig, CachingResolver, HostsResolver, Resolver},
+    stream::Stream,
+};
+
+/// How the [Connector] dials upstream.
+#[derive(Debug, Clone)]
+pub struct ConnectorOptions {
+    /// limit of a single connection attempt
+    pub connect_timeout: Option<Duration>,
+    /// limit of the whole connect, name resolution and every attempt included
+    pub total_connect_timeout: Option<Duration>,
+    /// head start of an attempt before the next address is tried, RFC 8305 section 5
+    pub happy_eyeballs_delay: Duration,
+    pub tcp_nodelay: bool,
+    /// idle time before TCP keepalive probes are sent, `None` disables them
+    pub tcp_keepalive: Option<Duration>,
+    /// local address to bind to, only addresses of its family are dialed
+    pub bind_to: Option<IpAddr>,
+}
+
+impl Default for ConnectorOptions {
+    fn default() -> Self {
+        ConnectorOptions {
+            connect_timeout: Some(Duration::from_secs(5)),
+            total_connect_timeout: Some(Duration::from_secs(10)),
+            happy_eyeballs_delay: Duration::from_millis(250),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            bind_to: None,
+        }
+    }
+}
+
+/// Open TCP connections to upstream, racing the resolved addresses.
+#[derive(Debug, Clone)]
+pub struct Connector {
+    options: ConnectorOptions,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Self::new(ConnectorOptions::default())
+    }
+}
+
+impl Connector {
+    /// names are resolved by the hosts file and `getaddrinfo`, behind a [CachingResolver].
+    pub fn new(options: ConnectorOptions) -> Self {
+        let resolver = CachingResolver::new(Arc::new(HostsResolver::system()), CacheConfig::default());
+        Connector { options, resolver: Arc::new(resolver) }
+    }
+
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn options(&self) -> &ConnectorOptions {
+        &self.options
+    }
+
+    /// resolve `addr` (`host:port`) and connect to one of its addresses.
+    ///
+    /// an IP address is connected to as is, without asking the resolver.
+    pub async fn connect(&self, addr: &str) -> Result<Stream> {
+        self.with_total_timeout(addr, async {
+            if let Ok(addr) = addr.parse::<SocketAddr>() {
+                return self.dial(&addr.to_string(), vec![addr]).await;
+            }
+            let Some((host, port)) = addr.rsplit_once(':').and_then(|(h, p)| Some((h, p.parse::<u16>().ok()?)))
+            else {
+                return Error::build(ErrorType::DnsError)
+                    .source(ErrorSource::Internal)
+                    .context(format!("invalid upstream address {addr}, expected host:port"))
+                    .err();
+            };
+            let start = Instant::now();
+            let resolved = self.resolver.resolve(host).await?;
+            let resolve_time = start.elapsed();
+            let addrs = resolved.addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+            let mut stream = self.dial(addr, addrs).await?;
+            stream.resolve_time = Some(resolve_time);
+            Ok(stream)
+        })
+        .await
+    }
+
+    /// connect to one of `addrs`, no name resolution involved.
+    pub async fn connect_addrs(&self, addrs: &[SocketAddr]) -> Result<Stream> {
+        let name = addrs.first().map(|a| a.to_string()).unwrap_or_default();
+        self.with_total_timeout(&name, self.dial(&name, addrs.to_vec())).await
+    }
+
+    async fn with_total_timeout<F>(&self, name: &str, connect: F) -> Result<Stream>
+    where
+        F: std::future::Future<Output = Result<Stream>>,
+    {
+        let Some(total) = self.options.total_connect_timeout else {
+            return connect.await;
+        };
+        match tokio::time::timeout(total, connect).await {
+            Ok(result) => result,
+            Err(_) => Error::build(ErrorType::ConnectTimeout)
+                .source(ErrorSource::UpStream)
+                .context(format!("total connect timeout of {total:?} to {name} reached"))
+                .err(),
+        }
+    }
+
+    /// happy eyeballs: start an attempt, and the next one whenever the last one
+    /// fails or stays pending for `happy_eyeballs_delay`. The first connection
+    /// wins, the attempts still running are dropped.
+    async fn dial(&self, name: &str, addrs: Vec<SocketAddr>) -> Result<Stream> {
+        let addrs = match self.options.bind_to {
+            Some(local) => addrs.into_iter().filter(|a| a.is_ipv4() == local.is_ipv4()).collect(),
+            None => addrs,
+        };
+        if addrs.is_empty() {
+            return Error::build(ErrorType::ConnectRefused)
+                .source(ErrorSource::UpStream)
+                .context(format!("no address to connect to for {name}"))
+                .err();
+        }
+
+        let start = Instant::now();
+        let mut pending = interleave(addrs).into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_error = None;
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(addr) => {
+                        attempts.spawn(dial_one(addr, self.options.clone()));
+                    }
+                    None => return Err(last_error.expect("at least one attempt failed")),
+                }
+            }
+            let delay = tokio::time::sleep(self.options.happy_eyeballs_delay);
+            tokio::select! {
+                Some(joined) = attempts.join_next() => match joined {
+                    Ok(Ok(stream)) => {
+                        let mut stream = Stream::from(stream);
+                        stream.connect_time = Some(start.elapsed());
+                        return Ok(stream);
+                    }
+                    Ok(Err(e)) => {
+                        last_error = Some(e);
+                        if let Some(addr) = pending.next() {
+                            attempts.spawn(dial_one(addr, self.options.clone()));
+                        }
+                    }
+                    Err(e) => {
+                        return Error::build(ErrorType::InternalError)
+                            .source(ErrorSource::Internal)
+                            .context("connect attempt panicked")
+                            .cause(e)
+                            .err();
+                    }
+                },
+                _ = delay, if pending.len() > 0 => {
+                    let addr = pending.next().unwrap();
+                    attempts.spawn(dial_one(addr, self.options.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// alternate the address families, starting with the family of the first
+/// address, RFC 8305 section 4.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs[0].is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.drain(..), other.drain(..));
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+async fn dial_one(addr: SocketAddr, options: ConnectorOptions) -> Result<TcpStream> {
+    let socket_error = |context: String, e: std::io::Error, etype: ErrorType| {
+        Error::build(etype).source(ErrorSource::UpStream).context(context).cause(e).finish()
+    };
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() }
+        .map_err(|e| socket_error(format!("failed to create socket for {addr}"), e, ErrorType::SocketError))?;
+    if let Some(local) = options.bind_to {
+        socket
+            .bind(SocketAddr::new(local, 0))
+            .map_err(|e| socket_error(format!("failed to bind to {local}"), e, ErrorType::BindError))?;
+    }
+    if let Some(idle) = options.tcp_keepalive {
+        SockRef::from(&socket)
+            .set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))
+            .map_err(|e| socket_error(format!("failed to set keepalive for {addr}"), e, ErrorType::SocketError))?;
+    }
+
+    let stream = with_connect_timeout(options.connect_timeout, addr, socket.connect(addr))
+        .await?
+        .map_err(|e| socket_error(format!("failed to connect to {addr}"), e, ErrorType::ConnectRefused))?;
+
+    stream
+        .set_nodelay(options.tcp_nodelay)
+        .map_err(|e| socket_error(format!("failed to set nodelay for {addr}"), e, ErrorType::SocketError))?;
+    Ok(stream)
+}
+
+/// fail with [ErrorType::ConnectTimeout] once `limit` is reached.
+async fn with_connect_timeout<F>(
+    limit: Option<Duration>,
+    addr: SocketAddr,
+    connect: F,
+) -> Result<std::io::Result<TcpStream>>
+where
+    F: std::future::Future<Output = std::io::Result<TcpStream>>,
+{
+    let Some(limit) = limit else {
+        return Ok(connect.await);
+    };
+    tokio::time::timeout(limit, connect).await.or_else(|_| {
+        Error::build(ErrorType::ConnectTimeout)
+            .source(ErrorSource::UpStream)
+            .context(format!("connect timeout of {limit:?} to {addr} reached"))
+            .err()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::l4::{resolver::StaticResolver, test_util::refusing_socket};
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "[::2]:80", "[::3]:80", "10.0.0.1:80", "10.0.0.2:80"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(ordered, vec!["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80", "[::3]:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            s.write_all(b"hi").await.unwrap();
+        });
+
+        let options = ConnectorOptions { tcp_keepalive: Some(Duration::from_secs(30)), ..Default::default() };
+        let resolver = StaticResolver::new().with("upstream.test", vec!["127.0.0.1".parse().unwrap()]);
+        let connector = Connector::new(options).with_resolver(Arc::new(resolver));
+        let before = std::time::SystemTime::now();
+        let mut stream = connector.connect(&format!("upstream.test:{}", addr.port())).await.unwrap();
+        assert!(stream.established_ts >= before);
+        assert!(stream.resolve_time.is_some());
+        assert!(stream.connect_time.is_some());
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+
+        let e = connector.connect("unknown.test:80").await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::DnsError);
+        let e = connector.connect("upstream.test").await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::DnsError);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = listener.local_addr().unwrap();
+        let (_reserved, down) = refusing_socket();
+        // the refused attempt starts the next one without waiting for the delay
+        let options = ConnectorOptions { happy_eyeballs_delay: Duration::from_secs(10), ..Default::default() };
+        let stream = Connector::new(options).connect_addrs(&[down, up]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), up);
+
+        let e = Connector::default().connect_addrs(&[down]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ConnectRefused);
+        assert_eq!(*e.esource(), ErrorSource::UpStream);
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let e = with_connect_timeout(Some(Duration::from_millis(20)), addr, std::future::pending())
+            .await
+            .unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ConnectTimeout);
+        assert!(e.to_string().contains("192.0.2.1:80"), "{e}");
+
+        let options = ConnectorOptions { total_connect_timeout: Some(Duration::from_millis(20)), ..Default::default() };
+        let e = Connector::new(options)
+            .with_total_timeout("example.com:80", std::future::pending())
+            .await
+            .unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ConnectTimeout);
+        assert_eq!(*e.esource(), ErrorSource::UpStream);
+    }
+
+    #[tokio::test]
+    async fn test_bind_to() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ConnectorOptions { bind_to: "127.0.0.1".parse().ok(), ..Default::default() };
+        let connector = Connector::new(options);
+        let stream = connector.connect_addrs(&[addr]).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+
+        // no address of the bound family
+        let e = connector.connect_addrs(&["[::1]:80".parse().unwrap()]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ConnectRefused);
+    }
+}
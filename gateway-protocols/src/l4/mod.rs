@@ -1 +1,7 @@
-mod stream;
\ No newline at end of file
+pub mod connector;
+pub mod resolver;
+pub mod stream;
+
+/// fixtures shared by the tests of this and the dependent crates
+#[doc(hidden)]
+pub mod test_util;
@@ -0,0 +1,565 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    net::IpAddr,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gateway_error::{error_trait::OrErr, Error, ErrorSource, ErrorType, Result};
+use log::debug;
+use tokio::sync::OnceCell;
+
+pub const HOSTS_FILE: &str = "/etc/hosts";
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<Resolved>> + Send + 'a>>;
+
+/// The addresses of a host and how long they may be cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub addrs: Vec<IpAddr>,
+    pub ttl: Duration,
+}
+
+/// Turn a host name into addresses.
+///
+/// implementations fail with [ErrorType::DnsError] when the host has no address.
+pub trait Resolver: Debug + Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a>;
+}
+
+fn no_address(host: &str) -> Result<Resolved> {
+    Error::build(ErrorType::DnsError)
+        .source(ErrorSource::UpStream)
+        .context(format!("no address found for {host}"))
+        .err()
+}
+
+/// `getaddrinfo` run on the blocking thread pool.
+///
+/// it reports no TTL, every answer is given `ttl`.
+#[derive(Debug, Clone)]
+pub struct SystemResolver {
+    pub ttl: Duration,
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        SystemResolver { ttl: Duration::from_secs(60) }
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let mut addrs: Vec<IpAddr> = Vec::new();
+            for addr in tokio::net::lookup_host((host, 0))
+                .await
+                .or_err_source(ErrorType::DnsError, ErrorSource::UpStream, "getaddrinfo failed")?
+            {
+                if !addrs.contains(&addr.ip()) {
+                    addrs.push(addr.ip());
+                }
+            }
+            if addrs.is_empty() {
+                return no_address(host);
+            }
+            Ok(Resolved { addrs, ttl: self.ttl })
+        })
+    }
+}
+
+/// A fixed map of host names, for tests and hard wired upstreams.
+#[derive(Debug, Clone)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    ttl: Duration,
+}
+
+impl Default for StaticResolver {
+    fn default() -> Self {
+        StaticResolver { hosts: HashMap::new(), ttl: Duration::MAX }
+    }
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// host names are matched case insensitively.
+    pub fn insert(&mut self, host: &str, addrs: Vec<IpAddr>) {
+        self.hosts.insert(host.to_ascii_lowercase(), addrs);
+    }
+
+    pub fn with(mut self, host: &str, addrs: Vec<IpAddr>) -> Self {
+        self.insert(host, addrs);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    fn lookup(&self, host: &str) -> Result<Resolved> {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(addrs) if !addrs.is_empty() => Ok(Resolved { addrs: addrs.clone(), ttl: self.ttl }),
+            _ => no_address(host),
+        }
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move { self.lookup(host) })
+    }
+}
+
+/// Answer from a hosts file first, ask `fallback` for every other name.
+#[derive(Debug)]
+pub struct HostsResolver {
+    hosts: StaticResolver,
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl HostsResolver {
+    /// parse the `/etc/hosts` format: an address followed by its names, `#` comments.
+    ///
+    /// a name listed on several lines gets all of their addresses.
+    pub fn parse(content: &str) -> StaticResolver {
+        let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(Ok(addr)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+            for name in fields {
+                let addrs = hosts.entry(name.to_ascii_lowercase()).or_default();
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        StaticResolver { hosts, ttl: Duration::MAX }
+    }
+
+    pub fn new(hosts: StaticResolver, fallback: Option<Arc<dyn Resolver>>) -> Self {
+        HostsResolver { hosts, fallback }
+    }
+
+    /// [HOSTS_FILE] in front of [SystemResolver], an unreadable hosts file counts as empty.
+    pub fn system() -> Self {
+        let fallback: Arc<dyn Resolver> = Arc::new(SystemResolver::default());
+        Self::from_file(HOSTS_FILE, Some(fallback.clone()))
+            .unwrap_or_else(|_| Self::new(StaticResolver::new(), Some(fallback)))
+    }
+
+    /// read the hosts file at `path`.
+    pub fn from_file(path: impl AsRef<Path>, fallback: Option<Arc<dyn Resolver>>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .or_err_with(ErrorType::InternalError, || format!("failed to read {}", path.display()))?;
+        Ok(Self::new(Self::parse(&content), fallback))
+    }
+}
+
+impl Resolver for HostsResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            match (self.hosts.lookup(host), &self.fallback) {
+                (Ok(resolved), _) => Ok(resolved),
+                (Err(_), Some(fallback)) => fallback.resolve(host).await,
+                (Err(e), None) => Err(e),
+            }
+        })
+    }
+}
+
+/// How long [CachingResolver] keeps answers.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// TTLs of the inner resolver are clamped to `min_ttl..=max_ttl`
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// a failed resolution is remembered this long, and a failed background refresh
+    /// of a stale answer is retried after this long
+    pub negative_ttl: Duration,
+    /// an expired answer is still served this long while it is refreshed in the background
+    pub stale_ttl: Duration,
+    /// hosts cached at most, the entry closest to expiry is evicted beyond that
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(300),
+            negative_ttl: Duration::from_secs(5),
+            stale_ttl: Duration::from_secs(30),
+            max_entries: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CacheEntry {
+    /// no background refresh starts before `refresh_after`
+    Positive { addrs: Vec<IpAddr>, expires: Instant, refresh_after: Instant },
+    Negative { error: String, expires: Instant },
+}
+
+impl CacheEntry {
+    /// the entry is of no use after this.
+    fn usable_until(&self, config: &CacheConfig) -> Instant {
+        match self {
+            CacheEntry::Positive { expires, .. } => *expires + config.stale_ttl,
+            CacheEntry::Negative { expires, .. } => *expires,
+        }
+    }
+}
+
+/// the outcome of one lookup, shared by every caller waiting on it
+type SharedLookup = std::result::Result<Resolved, String>;
+
+#[derive(Debug)]
+struct Cache {
+    inner: Arc<dyn Resolver>,
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    /// lookups in flight, concurrent misses of a host wait for the same one
+    inflight: Mutex<HashMap<String, Arc<OnceCell<SharedLookup>>>>,
+}
+
+impl Cache {
+    async fn refresh(&self, host: &str) -> Result<Resolved> {
+        let lookup = self.inflight.lock().unwrap().entry(host.to_string()).or_default().clone();
+        let shared = lookup
+            .get_or_init(|| async {
+                let result = self.inner.resolve(host).await;
+                self.store(host, &result, Instant::now());
+                let mut inflight = self.inflight.lock().unwrap();
+                if inflight.get(host).is_some_and(|l| Arc::ptr_eq(l, &lookup)) {
+                    inflight.remove(host);
+                }
+                result.map_err(|e| e.to_string())
+            })
+            .await;
+        match shared {
+            Ok(resolved) => Ok(resolved.clone()),
+            Err(error) => Error::build(ErrorType::DnsError)
+                .source(ErrorSource::UpStream)
+                .context(error.clone())
+                .err(),
+        }
+    }
+
+    fn store(&self, host: &str, result: &Result<Resolved>, now: Instant) {
+        let config = &self.config;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match result {
+            Ok(resolved) => {
+                let ttl = resolved.ttl.clamp(config.min_ttl, config.max_ttl);
+                CacheEntry::Positive { addrs: resolved.addrs.clone(), expires: now + ttl, refresh_after: now + ttl }
+            }
+            Err(e) => {
+                // a failed refresh keeps serving the stale answer until it is too old,
+                // without trying again before `negative_ttl`
+                if let Some(CacheEntry::Positive { expires, refresh_after, .. }) = entries.get_mut(host) {
+                    if now < *expires + config.stale_ttl {
+                        *refresh_after = now + config.negative_ttl;
+                        return;
+                    }
+                }
+                CacheEntry::Negative { error: e.to_string(), expires: now + config.negative_ttl }
+            }
+        };
+        if !entries.contains_key(host) && entries.len() >= config.max_entries.max(1) {
+            entries.retain(|_, entry| now < entry.usable_until(config));
+            if entries.len() >= config.max_entries.max(1) {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.usable_until(config))
+                    .map(|(host, _)| host.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(host.to_string(), entry);
+    }
+}
+
+/// Cache the answers of another [Resolver].
+///
+/// failures are cached for `negative_ttl`, and an expired answer younger than
+/// `stale_ttl` is returned right away while a background task refreshes it.
+/// Concurrent misses of the same host share one lookup.
+#[derive(Debug, Clone)]
+pub struct CachingResolver {
+    cache: Arc<Cache>,
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolver>, config: CacheConfig) -> Self {
+        CachingResolver {
+            cache: Arc::new(Cache {
+                inner,
+                config,
+                entries: Mutex::new(HashMap::new()),
+                inflight: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// forget every cached answer.
+    pub fn clear(&self) {
+        self.cache.entries.lock().unwrap().clear();
+    }
+
+    fn cached(&self, host: &str, now: Instant) -> Option<Result<Resolved>> {
+        let config = &self.cache.config;
+        let mut entries = self.cache.entries.lock().unwrap();
+        match entries.get_mut(host)? {
+            CacheEntry::Positive { addrs, expires, .. } if now < *expires => {
+                Some(Ok(Resolved { addrs: addrs.clone(), ttl: *expires - now }))
+            }
+            CacheEntry::Positive { addrs, expires, refresh_after } if now < *expires + config.stale_ttl => {
+                if now >= *refresh_after {
+                    // the refresh moves it again once done
+                    *refresh_after = *expires + config.stale_ttl;
+                    let cache = self.cache.clone();
+                    let host = host.to_string();
+                    tokio::spawn(async move {
+                        if let Err(e) = cache.refresh(&host).await {
+                            debug!("refreshing {host} failed: {e}");
+                        }
+                    });
+                }
+                Some(Ok(Resolved { addrs: addrs.clone(), ttl: Duration::ZERO }))
+            }
+            CacheEntry::Negative { error, expires } if now < *expires => Some(
+                Error::build(ErrorType::DnsError)
+                    .source(ErrorSource::UpStream)
+                    .context(format!("cached failure: {error}"))
+                    .err(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let host = host.to_ascii_lowercase();
+            match self.cached(&host, Instant::now()) {
+                Some(result) => result,
+                None => self.cache.refresh(&host).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::http::error_resp::{error_status, proxy_status_error};
+
+    /// count the lookups reaching a [StaticResolver], which can be swapped.
+    #[derive(Debug)]
+    struct Counting {
+        inner: Mutex<StaticResolver>,
+        lookups: AtomicUsize,
+        /// how long every lookup takes
+        delay: Duration,
+    }
+
+    impl Resolver for Counting {
+        fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let result = self.inner.lock().unwrap().lookup(host);
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                result
+            })
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn counting(resolver: StaticResolver) -> Arc<Counting> {
+        Arc::new(Counting { inner: Mutex::new(resolver), lookups: AtomicUsize::new(0), delay: Duration::ZERO })
+    }
+
+    /// wait until `lookups` reached `inner` and the background refresh is stored.
+    async fn wait_for_refresh(resolver: &CachingResolver, inner: &Counting, lookups: usize) {
+        for _ in 0..100 {
+            if inner.lookups.load(Ordering::Relaxed) == lookups && resolver.cache.inflight.lock().unwrap().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("no refresh after {lookups} lookups");
+    }
+
+    #[test]
+    fn test_parse_hosts() {
+        let hosts = HostsResolver::parse(
+            "127.0.0.1 localhost\n\
+             # a comment\n\
+             ::1\tlocalhost ip6-localhost # trailing\n\
+             10.0.0.1 API.internal api\n\
+             not-an-ip ignored\n",
+        );
+        assert_eq!(hosts.len(), 4);
+        assert_eq!(hosts.lookup("localhost").unwrap().addrs, vec![ip("127.0.0.1"), ip("::1")]);
+        assert_eq!(hosts.lookup("api.INTERNAL").unwrap().addrs, vec![ip("10.0.0.1")]);
+        assert!(hosts.lookup("ignored").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hosts_fallback() {
+        let fallback = StaticResolver::new().with("example.com", vec![ip("192.0.2.1")]);
+        let hosts = HostsResolver::parse("10.0.0.1 api example.com");
+        let resolver = HostsResolver::new(hosts.clone(), Some(Arc::new(fallback)));
+        assert_eq!(resolver.resolve("example.com").await.unwrap().addrs, vec![ip("10.0.0.1")]);
+
+        let resolver = HostsResolver::new(StaticResolver::new(), Some(Arc::new(hosts)));
+        assert_eq!(resolver.resolve("api").await.unwrap().addrs, vec![ip("10.0.0.1")]);
+        let e = resolver.resolve("unknown").await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::DnsError);
+        assert_eq!(error_status(&e), 502);
+        assert_eq!(proxy_status_error(&e), "dns_error");
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let inner = counting(StaticResolver::new().with("a", vec![ip("10.0.0.1")]).with_ttl(Duration::from_secs(60)));
+        let resolver = CachingResolver::new(inner.clone(), CacheConfig::default());
+        for _ in 0..3 {
+            assert_eq!(resolver.resolve("A").await.unwrap().addrs, vec![ip("10.0.0.1")]);
+        }
+        assert_eq!(inner.lookups.load(Ordering::Relaxed), 1);
+
+        // failures are cached too
+        for _ in 0..3 {
+            let e = resolver.resolve("b").await.unwrap_err();
+            assert_eq!(*e.etype(), ErrorType::DnsError);
+        }
+        assert_eq!(inner.lookups.load(Ordering::Relaxed), 2);
+
+        resolver.clear();
+        resolver.resolve("a").await.unwrap();
+        assert_eq!(inner.lookups.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let inner = counting(StaticResolver::new().with("a", vec![ip("10.0.0.1")]).with_ttl(Duration::ZERO));
+        let config = CacheConfig {
+            min_ttl: Duration::ZERO,
+            stale_ttl: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let resolver = CachingResolver::new(inner.clone(), config);
+        resolver.resolve("a").await.unwrap();
+
+        inner.inner.lock().unwrap().insert("a", vec![ip("10.0.0.2")]);
+        // expired: the stale answer is served and refreshed in the background
+        let stale = resolver.resolve("a").await.unwrap();
+        assert_eq!(stale.addrs, vec![ip("10.0.0.1")]);
+        wait_for_refresh(&resolver, &inner, 2).await;
+        let entries = resolver.cache.entries.lock().unwrap();
+        let Some(CacheEntry::Positive { addrs, .. }) = entries.get("a") else {
+            panic!("a is not cached");
+        };
+        assert_eq!(addrs, &vec![ip("10.0.0.2")]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_refresh_backs_off() {
+        let inner = counting(StaticResolver::new().with("a", vec![ip("10.0.0.1")]).with_ttl(Duration::ZERO));
+        let config = CacheConfig {
+            min_ttl: Duration::ZERO,
+            stale_ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let resolver = CachingResolver::new(inner.clone(), config);
+        resolver.resolve("a").await.unwrap();
+
+        // the name server is gone, the stale answer is served and refreshed once
+        *inner.inner.lock().unwrap() = StaticResolver::new();
+        resolver.resolve("a").await.unwrap();
+        wait_for_refresh(&resolver, &inner, 2).await;
+        for _ in 0..10 {
+            assert_eq!(resolver.resolve("a").await.unwrap().addrs, vec![ip("10.0.0.1")]);
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(inner.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_lookup() {
+        let inner = Arc::new(Counting {
+            inner: Mutex::new(StaticResolver::new().with("a", vec![ip("10.0.0.1")])),
+            lookups: AtomicUsize::new(0),
+            delay: Duration::from_millis(50),
+        });
+        let resolver = CachingResolver::new(inner.clone(), CacheConfig::default());
+        let mut tasks = tokio::task::JoinSet::new();
+        for host in ["a", "a", "A", "b", "b"] {
+            let resolver = resolver.clone();
+            tasks.spawn(async move { resolver.resolve(host).await.map(|r| r.addrs) });
+        }
+        let mut found = 0;
+        while let Some(result) = tasks.join_next().await {
+            if let Ok(addrs) = result.unwrap() {
+                assert_eq!(addrs, vec![ip("10.0.0.1")]);
+                found += 1;
+            }
+        }
+        assert_eq!(found, 3);
+        assert_eq!(inner.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_entries() {
+        let hosts = StaticResolver::new()
+            .with("a", vec![ip("10.0.0.1")])
+            .with("b", vec![ip("10.0.0.2")])
+            .with("c", vec![ip("10.0.0.3")]);
+        let inner = counting(hosts.with_ttl(Duration::from_secs(60)));
+        let resolver = CachingResolver::new(inner, CacheConfig { max_entries: 2, ..Default::default() });
+        for host in ["a", "b", "c"] {
+            resolver.resolve(host).await.unwrap();
+        }
+        let entries = resolver.cache.entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key("c"));
+    }
+
+    #[tokio::test]
+    async fn test_system_resolver() {
+        let resolved = SystemResolver::default().resolve("localhost").await.unwrap();
+        assert!(resolved.addrs.iter().all(|a| a.is_loopback()));
+    }
+}
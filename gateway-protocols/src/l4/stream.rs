@@ -1,18 +1,97 @@
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::{
+    any::Any,
+    io,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
 
-use tokio::{io::BufStream, net::TcpStream, time::Instant};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufStream, ReadBuf},
+    net::TcpStream,
+    time::Instant,
+};
 
-use crate::connections::{digest::{GetTimingDigest, TimingDigest}, row_connection::ProxyDigest};
+use crate::{
+    connections::{digest::{GetProxyDigest, GetTimingDigest, TimingDigest}, row_connection::ProxyDigest},
+    http::common::{UniqueID, IO},
+};
 
+#[derive(Debug)]
 enum RawStream {
     Tcp(TcpStream),
 }
 
+impl AsyncRead for RawStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RawStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RawStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RawStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RawStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RawStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RawStream::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            RawStream::Tcp(s) => s.is_write_vectored(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 struct AccumulatedDuration {
     total: Duration,
     last_start: Option<Instant>,
 }
 
+impl AccumulatedDuration {
+    /// account the time an operation stays pending.
+    fn poll<T>(&mut self, poll: Poll<T>) -> Poll<T> {
+        match poll {
+            Poll::Pending => {
+                self.last_start.get_or_insert_with(Instant::now);
+            }
+            Poll::Ready(_) => {
+                if let Some(start) = self.last_start.take() {
+                    self.total += start.elapsed();
+                }
+            }
+        }
+        poll
+    }
+}
+
 // Large read buffering helps reducing syscalls with little trade-off
 // Ssl layer always does "small" reads in 16k (TLS record size) so L4 read buffer helps a lot.
 const BUF_READ_SIZE: usize = 64 * 1024;
@@ -28,6 +107,10 @@ pub struct Stream {
     proxy_digest: Option<Arc<ProxyDigest>>,
     // when this connection is established
     pub established_ts: SystemTime,
+    /// time spent resolving the upstream name
+    pub resolve_time: Option<Duration>,
+    /// time spent in the TCP connect, happy eyeballs attempts included
+    pub connect_time: Option<Duration>,
     read_pending_time: AccumulatedDuration,
     write_pending_time: AccumulatedDuration,
 }
@@ -39,6 +122,8 @@ impl GetTimingDigest for Stream {
         digest.push(Some(
             TimingDigest {
                 established_ts: self.established_ts,
+                resolve_time: self.resolve_time,
+                handshake_time: self.connect_time,
             }
         ));
         digest
@@ -51,4 +136,144 @@ impl GetTimingDigest for Stream {
     fn get_write_pending_time(&self) -> std::time::Duration {
        self.write_pending_time.total
     }
-}
\ No newline at end of file
+}
+
+impl From<TcpStream> for Stream {
+    /// `established_ts` is set to now.
+    fn from(s: TcpStream) -> Self {
+        Stream {
+            stream: BufStream::with_capacity(BUF_READ_SIZE, BUF_WRITE_SIZE, RawStream::Tcp(s)),
+            buffer_write: true,
+            proxy_digest: None,
+            established_ts: SystemTime::now(),
+            resolve_time: None,
+            connect_time: None,
+            read_pending_time: AccumulatedDuration::default(),
+            write_pending_time: AccumulatedDuration::default(),
+        }
+    }
+}
+
+impl Stream {
+    /// writes are sent right away instead of being buffered up to an MSS.
+    pub fn set_buffer_write(&mut self, buffer_write: bool) {
+        self.buffer_write = buffer_write;
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self.stream.get_ref() {
+            RawStream::Tcp(s) => s,
+        }
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.tcp().set_nodelay(nodelay)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+}
+
+impl std::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Stream")
+            .field("stream", self.stream.get_ref())
+            .field("established_ts", &self.established_ts)
+            .finish()
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        let poll = Pin::new(&mut me.stream).poll_read(cx, buf);
+        me.read_pending_time.poll(poll)
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        let poll = if me.buffer_write {
+            Pin::new(&mut me.stream).poll_write(cx, buf)
+        } else {
+            // whatever was buffered before goes out first to keep the byte order
+            match Pin::new(&mut me.stream).poll_flush(cx) {
+                Poll::Ready(Ok(())) => Pin::new(me.stream.get_mut()).poll_write(cx, buf),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        };
+        me.write_pending_time.poll(poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        let poll = Pin::new(&mut me.stream).poll_flush(cx);
+        me.write_pending_time.poll(poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl UniqueID for Stream {
+    fn id(&self) -> i32 {
+        self.tcp().as_raw_fd()
+    }
+}
+
+impl GetProxyDigest for Stream {
+    fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+        self.proxy_digest.clone()
+    }
+
+    fn set_proxy_digest(&mut self, digest: ProxyDigest) {
+        self.proxy_digest = Some(Arc::new(digest));
+    }
+}
+
+impl IO for Stream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unbuffered_write_keeps_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let mut stream = Stream::from(TcpStream::connect(addr).await.unwrap());
+        stream.write_all(b"ab").await.unwrap();
+        stream.set_buffer_write(false);
+        stream.write_all(b"cd").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(server.await.unwrap(), b"abcd");
+    }
+}
@@ -0,0 +1,15 @@
+use std::net::SocketAddr;
+
+use tokio::net::TcpSocket;
+
+/// A socket bound to a free local port but not listening.
+///
+/// connects to the address are refused, and since the port stays bound no other
+/// test can take it. `listen()` turns it into a listener on the same address.
+pub fn refusing_socket() -> (TcpSocket, SocketAddr) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_reuseaddr(true).unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = socket.local_addr().unwrap();
+    (socket, addr)
+}
@@ -1,4 +1,4 @@
-mod connections;
-mod http;
-mod util_code;
-mod l4;
\ No newline at end of file
+pub mod connections;
+pub mod http;
+pub mod util_code;
+pub mod l4;
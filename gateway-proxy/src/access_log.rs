@@ -0,0 +1,587 @@
+use std::{
+    borrow::Cow,
+    fmt::Write as _,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use gateway_error::{error_trait::OrErr, Error, ErrorType, Result};
+use gateway_protocols::{
+    connections::{
+        digest::{Digest, RequestTiming},
+        request::RequestHeader,
+        response::ResponseHeader,
+    },
+    util_code::util_code::get_version_str,
+};
+use http::{
+    header::{REFERER, USER_AGENT},
+    HeaderMap,
+};
+use log::{error, warn};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    common::{json_string, INVALID_CONFIG},
+    router::request_host,
+};
+
+/// `$remote_addr - [$time_local] "$request" $status $bytes_sent "$http_referer" "$http_user_agent" $request_time`
+pub const COMBINED_FORMAT: &str =
+    r#"$remote_addr - [$time_local] "$request" $status $bytes_sent "$http_referer" "$http_user_agent" $request_time"#;
+
+/// Everything logged about one request.
+#[derive(Debug, Clone)]
+pub struct AccessLogRecord {
+    pub time: SystemTime,
+    pub client_ip: Option<IpAddr>,
+    pub method: String,
+    /// path and query as received
+    pub uri: String,
+    pub version: http::Version,
+    pub host: Option<String>,
+    pub request_headers: HeaderMap,
+    /// `None` when no response was sent
+    pub status: Option<u16>,
+    pub response_headers: HeaderMap,
+    pub bytes_sent: usize,
+    pub upstream_addr: Option<String>,
+    pub retries: usize,
+    pub error: Option<ErrorType>,
+    pub timing: RequestTiming,
+}
+
+impl AccessLogRecord {
+    pub fn new(req: &RequestHeader) -> Self {
+        AccessLogRecord {
+            time: SystemTime::now(),
+            client_ip: None,
+            method: req.method.to_string(),
+            uri: String::from_utf8_lossy(req.raw_path()).into_owned(),
+            version: req.version,
+            host: request_host(req),
+            request_headers: req.headers.clone(),
+            status: None,
+            response_headers: HeaderMap::new(),
+            bytes_sent: 0,
+            upstream_addr: None,
+            retries: 0,
+            error: None,
+            timing: RequestTiming::default(),
+        }
+    }
+
+    pub fn client_ip(mut self, ip: IpAddr) -> Self {
+        self.client_ip = Some(ip);
+        self
+    }
+
+    pub fn response(mut self, resp: &ResponseHeader) -> Self {
+        self.status = Some(resp.status.as_u16());
+        self.response_headers = resp.headers.clone();
+        self
+    }
+
+    /// see [gateway_protocols::http::v1::client::HttpSession::bytes_sent].
+    pub fn bytes_sent(mut self, bytes: usize) -> Self {
+        self.bytes_sent = bytes;
+        self
+    }
+
+    pub fn upstream(mut self, addr: impl Into<String>) -> Self {
+        self.upstream_addr = Some(addr.into());
+        self
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn error(mut self, e: &Error) -> Self {
+        self.error = Some(e.etype().clone());
+        self
+    }
+
+    pub fn digest(mut self, digest: &Digest) -> Self {
+        self.timing = digest.request_timing;
+        self
+    }
+
+    /// the raw value of a template variable, `None` renders as `-`.
+    ///
+    /// `$http_<name>` and `$sent_http_<name>` are request and response headers,
+    /// `_` standing for `-` in the name.
+    pub fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        let secs = |d: Option<Duration>| d.map(|d| Cow::Owned(format!("{:.3}", d.as_secs_f64())));
+        let value = match name {
+            "remote_addr" => Cow::Owned(self.client_ip?.to_string()),
+            "time_local" => Cow::Owned(format_time(self.time, TimeFormat::Local)),
+            "time_iso8601" => Cow::Owned(format_time(self.time, TimeFormat::Iso8601)),
+            "msec" => Cow::Owned(format!("{:.3}", unix_time(self.time).as_secs_f64())),
+            "request" => Cow::Owned(format!("{} {} {}", self.method, self.uri, get_version_str(&self.version))),
+            "request_method" => Cow::Borrowed(self.method.as_str()),
+            "request_uri" => Cow::Borrowed(self.uri.as_str()),
+            "server_protocol" => Cow::Owned(get_version_str(&self.version)),
+            "host" => Cow::Borrowed(self.host.as_deref()?),
+            "status" => Cow::Owned(self.status?.to_string()),
+            "bytes_sent" => Cow::Owned(self.bytes_sent.to_string()),
+            "upstream_addr" => Cow::Borrowed(self.upstream_addr.as_deref()?),
+            "upstream_retries" => Cow::Owned(self.retries.to_string()),
+            "error" => Cow::Borrowed(self.error.as_ref()?.as_str()),
+            "request_time" => return secs(self.timing.total),
+            "upstream_dns_time" => return secs(self.timing.dns),
+            "upstream_connect_time" => return secs(self.timing.connect),
+            "upstream_tls_time" => return secs(self.timing.tls),
+            "upstream_header_time" => return secs(self.timing.ttfb),
+            _ => {
+                let (headers, header) = match name.strip_prefix("sent_http_") {
+                    Some(header) => (&self.response_headers, header),
+                    None => (&self.request_headers, name.strip_prefix("http_")?),
+                };
+                let value = headers.get(header.replace('_', "-").as_str())?;
+                String::from_utf8_lossy(value.as_bytes())
+            }
+        };
+        Some(value)
+    }
+
+    /// one JSON object, timings in milliseconds.
+    pub fn to_json(&self) -> String {
+        let mut out = String::with_capacity(512);
+        out.push('{');
+        let mut field = |key: &str, value: Option<&str>, quote: bool| {
+            if out.len() > 1 {
+                out.push(',');
+            }
+            json_string(&mut out, key);
+            out.push(':');
+            match value {
+                Some(v) if quote => json_string(&mut out, v),
+                Some(v) => out.push_str(v),
+                None => out.push_str("null"),
+            }
+        };
+        field("time", Some(&format_time(self.time, TimeFormat::Iso8601)), true);
+        field("remote_addr", self.client_ip.map(|ip| ip.to_string()).as_deref(), true);
+        field("method", Some(&self.method), true);
+        field("uri", Some(&self.uri), true);
+        field("protocol", Some(&get_version_str(&self.version)), true);
+        field("host", self.host.as_deref(), true);
+        field("status", self.status.map(|s| s.to_string()).as_deref(), false);
+        field("bytes_sent", Some(&self.bytes_sent.to_string()), false);
+        field("upstream_addr", self.upstream_addr.as_deref(), true);
+        field("retries", Some(&self.retries.to_string()), false);
+        field("error", self.error.as_ref().map(|e| e.as_str()), true);
+        field("user_agent", self.request_headers.get(USER_AGENT).and_then(|v| v.to_str().ok()), true);
+        field("referer", self.request_headers.get(REFERER).and_then(|v| v.to_str().ok()), true);
+
+        let mut timing = String::from("{");
+        for (i, (phase, d)) in self.timing.phases().into_iter().enumerate() {
+            if i > 0 {
+                timing.push(',');
+            }
+            let _ = write!(timing, "\"{phase}\":{:.3}", d.as_secs_f64() * 1000.0);
+        }
+        timing.push('}');
+        field("timing_ms", Some(&timing), false);
+        out.push('}');
+        out
+    }
+}
+
+enum TimeFormat {
+    /// `10/Oct/2000:13:55:36 +0000`
+    Local,
+    /// `2000-10-10T13:55:36.123Z`
+    Iso8601,
+}
+
+fn unix_time(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// UTC, the offset is always `+0000`.
+fn format_time(time: SystemTime, format: TimeFormat) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let since_epoch = unix_time(time);
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    match format {
+        TimeFormat::Local => format!(
+            "{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000",
+            MONTHS[month as usize - 1]
+        ),
+        TimeFormat::Iso8601 => format!(
+            "{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{:03}Z",
+            since_epoch.subsec_millis()
+        ),
+    }
+}
+
+/// the variables of [AccessLogRecord::var] besides the `http_` and `sent_http_` headers.
+const VARIABLES: [&str; 19] = [
+    "remote_addr",
+    "time_local",
+    "time_iso8601",
+    "msec",
+    "request",
+    "request_method",
+    "request_uri",
+    "server_protocol",
+    "host",
+    "status",
+    "bytes_sent",
+    "upstream_addr",
+    "upstream_retries",
+    "error",
+    "request_time",
+    "upstream_dns_time",
+    "upstream_connect_time",
+    "upstream_tls_time",
+    "upstream_header_time",
+];
+
+fn is_variable(name: &str) -> bool {
+    let header = name.strip_prefix("sent_http_").or_else(|| name.strip_prefix("http_"));
+    VARIABLES.contains(&name) || header.is_some_and(|h| !h.is_empty())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Var(String),
+}
+
+/// An nginx style `log_format`: `$name` or `${name}` variables between literal text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogTemplate {
+    pieces: Vec<Piece>,
+}
+
+impl LogTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => match braced.split_once('}') {
+                    Some((name, after)) => (name, after),
+                    None => {
+                        return Error::build(INVALID_CONFIG)
+                            .context(format!("unclosed ${{ in log format {template:?}"))
+                            .err();
+                    }
+                },
+                None => {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            if name.is_empty() {
+                return Error::build(INVALID_CONFIG)
+                    .context(format!("empty variable in log format {template:?}"))
+                    .err();
+            }
+            let name = name.to_ascii_lowercase();
+            // a typo would silently log `-` forever, nginx refuses it at config load too
+            if !is_variable(&name) {
+                return Error::build(INVALID_CONFIG)
+                    .context(format!("unknown variable ${name} in log format {template:?}"))
+                    .err();
+            }
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Var(name));
+            rest = after;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(LogTemplate { pieces })
+    }
+
+    pub fn render(&self, record: &AccessLogRecord) -> String {
+        let mut out = String::with_capacity(256);
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => out.push_str(s),
+                Piece::Var(name) => match record.var(name) {
+                    Some(value) => escape_value(&mut out, &value),
+                    None => out.push('-'),
+                },
+            }
+        }
+        out
+    }
+}
+
+/// escape `"`, `\` and bytes outside printable ASCII as `\xXX`, as nginx does by default,
+/// so a header value can't forge fields of the line.
+fn escape_value(out: &mut String, value: &str) {
+    for &b in value.as_bytes() {
+        match b {
+            b'"' | b'\\' | ..=0x1f | 0x7f.. => {
+                let _ = write!(out, "\\x{b:02X}");
+            }
+            _ => out.push(b as char),
+        }
+    }
+}
+
+/// How a record turns into a line.
+#[derive(Debug, Clone)]
+pub enum LogFormat {
+    Json,
+    Template(LogTemplate),
+}
+
+impl LogFormat {
+    pub fn render(&self, record: &AccessLogRecord) -> String {
+        match self {
+            LogFormat::Json => record.to_json(),
+            LogFormat::Template(template) => template.render(record),
+        }
+    }
+}
+
+enum Message {
+    Line(String),
+    Reopen(oneshot::Sender<Result<()>>),
+}
+
+/// Write access log lines to a file from a background task.
+///
+/// logging never waits on the disk: when the queue is full the line is
+/// dropped and counted.
+pub struct AccessLog {
+    format: LogFormat,
+    tx: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+    writer: JoinHandle<()>,
+}
+
+impl AccessLog {
+    /// append to `path`, queueing up to `capacity` lines. Must be called within a tokio runtime.
+    pub async fn open(path: impl Into<PathBuf>, format: LogFormat, capacity: usize) -> Result<Self> {
+        let path = path.into();
+        let file = open_append(&path).await?;
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let writer = tokio::spawn(write_loop(path, file, rx));
+        Ok(AccessLog { format, tx, dropped: Arc::new(AtomicU64::new(0)), writer })
+    }
+
+    pub fn log(&self, record: &AccessLogRecord) {
+        let mut line = self.format.render(record);
+        line.push('\n');
+        if self.tx.try_send(Message::Line(line)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// lines lost because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// flush and reopen the file at the same path, after it was moved away by log rotation.
+    pub async fn reopen(&self) -> Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.tx
+            .send(Message::Reopen(ack))
+            .await
+            .or_err(ErrorType::InternalError, "access log writer is gone")?;
+        done.await.or_err(ErrorType::InternalError, "access log writer is gone")?
+    }
+
+    /// reopen the file every time the process receives `kind`, e.g. `SIGUSR1` like nginx.
+    pub fn reopen_on_signal(self: &Arc<Self>, kind: SignalKind) -> Result<JoinHandle<()>> {
+        let mut signals = signal(kind).or_err(ErrorType::InternalError, "failed to listen for the reopen signal")?;
+        let log = Arc::downgrade(self);
+        Ok(tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                let Some(log) = log.upgrade() else {
+                    return;
+                };
+                if let Err(e) = log.reopen().await {
+                    error!("failed to reopen the access log: {e}");
+                }
+            }
+        }))
+    }
+
+    /// write out every queued line and stop the writer.
+    pub async fn close(self) {
+        drop(self.tx);
+        let _ = self.writer.await;
+    }
+}
+
+async fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .or_err_with(ErrorType::InternalError, || format!("failed to open access log {}", path.display()))
+}
+
+async fn write_loop(path: PathBuf, file: File, mut rx: mpsc::Receiver<Message>) {
+    let mut out = BufWriter::new(file);
+    while let Some(message) = rx.recv().await {
+        match message {
+            Message::Line(line) => {
+                if let Err(e) = out.write_all(line.as_bytes()).await {
+                    warn!("failed to write access log {}: {e}", path.display());
+                }
+            }
+            Message::Reopen(ack) => {
+                let _ = out.flush().await;
+                let result = open_append(&path).await.map(|file| out = BufWriter::new(file));
+                let _ = ack.send(result);
+            }
+        }
+        // batch the writes of a burst, flush once the queue is drained
+        if rx.is_empty() {
+            if let Err(e) = out.flush().await {
+                warn!("failed to flush access log {}: {e}", path.display());
+            }
+        }
+    }
+    let _ = out.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use gateway_error::ErrorSource;
+
+    use super::*;
+
+    fn record() -> AccessLogRecord {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/search?q=a%20b").unwrap();
+        req.insert_header("Host", "Example.com:8080").unwrap();
+        req.insert_header("User-Agent", "curl/8.0 \"quoted\"").unwrap();
+        req.insert_header("X-Request-Id", "abc").unwrap();
+        let mut resp = ResponseHeader::build_with_status_code(502).unwrap();
+        resp.insert_header("Content-Type", "text/html").unwrap();
+        let e = Error::build(ErrorType::ConnectTimeout).source(ErrorSource::UpStream).finish();
+        let mut record = AccessLogRecord::new(&req)
+            .client_ip("192.0.2.7".parse().unwrap())
+            .response(&resp)
+            .bytes_sent(1234)
+            .upstream("10.0.0.1:80")
+            .retries(2)
+            .error(&e);
+        record.time = UNIX_EPOCH + Duration::from_millis(971_186_136_123);
+        record.timing.connect = Some(Duration::from_millis(3));
+        record.timing.total = Some(Duration::from_micros(1_500_250));
+        record
+    }
+
+    #[test]
+    fn test_time_format() {
+        let time = UNIX_EPOCH + Duration::from_millis(971_186_136_123);
+        assert_eq!(format_time(time, TimeFormat::Local), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(format_time(time, TimeFormat::Iso8601), "2000-10-10T13:55:36.123Z");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(format_time(leap, TimeFormat::Iso8601), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_template() {
+        let template = LogTemplate::parse(COMBINED_FORMAT).unwrap();
+        assert_eq!(
+            template.render(&record()),
+            r#"192.0.2.7 - [10/Oct/2000:13:55:36 +0000] "GET /search?q=a%20b HTTP/1.1" 502 1234 "-" "curl/8.0 \x22quoted\x22" 1.500"#
+        );
+
+        let template = LogTemplate::parse(
+            "${host}|$upstream_addr|$upstream_retries|$error|$upstream_connect_time|$upstream_tls_time|$http_x_request_id|$sent_http_content_type",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&record()),
+            "example.com|10.0.0.1:80|2|ConnectTimeout|0.003|-|abc|text/html"
+        );
+
+        let mut req = RequestHeader::build_with_method_path("GET", "/café".as_bytes()).unwrap();
+        req.insert_header("Referer", "a\\b\tc").unwrap();
+        let template = LogTemplate::parse("$request_uri \"$http_referer\"").unwrap();
+        assert_eq!(
+            template.render(&AccessLogRecord::new(&req)),
+            r#"/caf\xC3\xA9 "a\x5Cb\x09c""#
+        );
+
+        assert!(LogTemplate::parse("${status").is_err());
+        assert!(LogTemplate::parse("cost: $ 5").is_err());
+        let e = LogTemplate::parse("$upstream_adr").unwrap_err();
+        assert_eq!(e.etype(), &INVALID_CONFIG);
+        assert!(LogTemplate::parse("$http_").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let json = record().to_json();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"time":"2000-10-10T13:55:36.123Z","remote_addr":"192.0.2.7","method":"GET","#,
+                r#""uri":"/search?q=a%20b","protocol":"HTTP/1.1","host":"example.com","status":502,"#,
+                r#""bytes_sent":1234,"upstream_addr":"10.0.0.1:80","retries":2,"error":"ConnectTimeout","#,
+                r#""user_agent":"curl/8.0 \"quoted\"","referer":null,"timing_ms":{"connect":3.000,"total":1500.250}}"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn write_and_reopen() {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotated = dir.join("access.log.1");
+        let _ = std::fs::remove_file(&path);
+
+        let template = LogTemplate::parse("$request_method $status").unwrap();
+        let log = AccessLog::open(&path, LogFormat::Template(template), 16).await.unwrap();
+        log.log(&record());
+        log.reopen().await.unwrap();
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen().await.unwrap();
+        log.log(&record().response(&ResponseHeader::build_with_status_code(200).unwrap()));
+        log.close().await;
+
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), "GET 502\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "GET 200\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
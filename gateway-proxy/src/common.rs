@@ -0,0 +1,46 @@
+use std::fmt::Write as _;
+
+use gateway_error::{Error, ErrorType, Result};
+use gateway_protocols::connections::request::RequestHeader;
+use http::header::HOST;
+use regex::Regex;
+
+/// Error type of configuration that fails to build: rules, templates, endpoints.
+pub const INVALID_CONFIG: ErrorType = ErrorType::Custom("InvalidConfig");
+
+/// compile a regex from configuration, failing with [INVALID_CONFIG].
+pub(crate) fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|e| {
+        Error::generate_error_with_root_raw(INVALID_CONFIG,
+            &format!("invalid regex {pattern:?}"), Some(Box::new(e)))
+    })
+}
+
+/// the authority the request is for, port included.
+///
+/// an absolute-form target wins over `Host`, which must be ignored then, RFC 9112 section 3.2.2.
+pub(crate) fn request_authority(req: &RequestHeader) -> Option<&str> {
+    req.uri
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers.get(HOST).and_then(|h| h.to_str().ok()))
+}
+
+/// append `s` to `out` as a quoted JSON string.
+pub(crate) fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,332 @@
+use std::{net::IpAddr, str::FromStr};
+
+use gateway_error::{Error, Result};
+use gateway_protocols::connections::{request::RequestHeader, response::ResponseHeader};
+use http::{header::{FORWARDED, VIA}, HeaderName, Version};
+
+use crate::common::{request_authority, INVALID_CONFIG};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// A network like `10.0.0.0/8` or `2001:db8::/32`, a bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_match(u32::from(net) as u128, u32::from(ip) as u128, self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_match(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_match(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || (net >> shift) == (ip >> shift)
+}
+
+impl FromStr for IpCidr {
+    type Err = Box<Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let invalid = || Error::build(INVALID_CONFIG).context(format!("invalid cidr {s:?}")).finish();
+        let addr = IpAddr::from_str(addr.trim()).map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(IpCidr { addr, prefix })
+    }
+}
+
+/// What the proxy knows about the downstream connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ForwardContext {
+    /// the address of the direct peer
+    pub client_ip: IpAddr,
+    /// the downstream connection is TLS
+    pub tls: bool,
+}
+
+/// How requests and responses are prepared before crossing the proxy.
+///
+/// Incoming `X-Forwarded-*` and `Forwarded` headers are only kept when the direct
+/// peer is in `trusted_proxies`, otherwise they are dropped as possibly spoofed.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardConfig {
+    /// the pseudonym written in `Via`, no `Via` when `None`
+    pub via: Option<String>,
+    /// write `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    pub x_forwarded: bool,
+    /// write the RFC 7239 `Forwarded` header
+    pub forwarded: bool,
+    /// the `by` parameter of `Forwarded`
+    pub forwarded_by: Option<String>,
+    pub trusted_proxies: Vec<IpCidr>,
+}
+
+impl ForwardConfig {
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// The original client: the right-most `X-Forwarded-For` entry which is not a
+    /// trusted proxy, or the direct peer when it is not trusted itself.
+    pub fn real_client_ip(&self, req: &RequestHeader, ctx: &ForwardContext) -> IpAddr {
+        if !self.is_trusted(&ctx.client_ip) {
+            return ctx.client_ip;
+        }
+        let mut client = ctx.client_ip;
+        let forwarded_for: Vec<IpAddr> = req
+            .headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|ip| IpAddr::from_str(ip.trim()).ok())
+            .collect();
+        for ip in forwarded_for.into_iter().rev() {
+            client = ip;
+            if !self.is_trusted(&ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// strip hop-by-hop headers and add the forwarding headers to a request going upstream.
+    pub fn prepare_upstream_request(&self, req: &mut RequestHeader, ctx: &ForwardContext) -> Result<()> {
+        req.strip_hop_by_hop()?;
+        let trusted = self.is_trusted(&ctx.client_ip);
+        let proto = if ctx.tls { "https" } else { "http" };
+        let host = request_authority(req).map(str::to_string);
+
+        if self.x_forwarded {
+            let xff = match joined(req, &X_FORWARDED_FOR).filter(|_| trusted) {
+                Some(prev) => format!("{prev}, {}", ctx.client_ip),
+                None => ctx.client_ip.to_string(),
+            };
+            req.insert_header(X_FORWARDED_FOR, xff)?;
+            if !(trusted && req.headers.contains_key(X_FORWARDED_PROTO)) {
+                req.insert_header(X_FORWARDED_PROTO, proto)?;
+            }
+            if !(trusted && req.headers.contains_key(X_FORWARDED_HOST)) {
+                match host.as_deref() {
+                    Some(host) => req.insert_header(X_FORWARDED_HOST, host)?,
+                    None => req.remove_header(X_FORWARDED_HOST, "")?,
+                }
+            }
+        } else if !trusted {
+            for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST] {
+                req.remove_header(name, "")?;
+            }
+        }
+
+        if self.forwarded {
+            let element = forwarded_element(ctx, proto, host.as_deref(), self.forwarded_by.as_deref());
+            let value = match joined(req, &FORWARDED).filter(|_| trusted) {
+                Some(prev) => format!("{prev}, {element}"),
+                None => element,
+            };
+            req.insert_header(FORWARDED, value)?;
+        } else if !trusted {
+            req.remove_header(FORWARDED, "")?;
+        }
+
+        if let Some(via) = self.via.as_deref() {
+            add_via(req.version, via, |v| req.append_header(VIA, v))?;
+        }
+        Ok(())
+    }
+
+    /// strip hop-by-hop headers and add `Via` to a response going downstream.
+    pub fn prepare_downstream_response(&self, resp: &mut ResponseHeader) -> Result<()> {
+        resp.strip_hop_by_hop()?;
+        if let Some(via) = self.via.as_deref() {
+            add_via(resp.version, via, |v| resp.append_header(VIA, v))?;
+        }
+        Ok(())
+    }
+}
+
+/// all values of `name` as one comma separated list.
+fn joined(req: &RequestHeader, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn add_via(version: Version, via: &str, append: impl FnOnce(String) -> Result<()>) -> Result<()> {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    append(format!("{protocol} {via}"))
+}
+
+/// `for=192.0.2.1;host=example.com;proto=https`, see RFC 7239 section 4
+fn forwarded_element(ctx: &ForwardContext, proto: &str, host: Option<&str>, by: Option<&str>) -> String {
+    let mut element = format!("for={}", forwarded_node(&ctx.client_ip));
+    if let Some(by) = by {
+        element.push_str(";by=");
+        element.push_str(&forwarded_value(by));
+    }
+    if let Some(host) = host {
+        element.push_str(";host=");
+        element.push_str(&forwarded_value(host));
+    }
+    element.push_str(";proto=");
+    element.push_str(proto);
+    element
+}
+
+/// IPv6 nodes are bracketed and so must be quoted.
+fn forwarded_node(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+/// quote `value` unless it is a token.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ForwardConfig {
+        ForwardConfig {
+            via: Some("crab".into()),
+            x_forwarded: true,
+            forwarded: true,
+            forwarded_by: None,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+        }
+    }
+
+    fn request() -> RequestHeader {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        req.insert_header("Host", "example.com:8080").unwrap();
+        req.insert_header("Connection", "keep-alive").unwrap();
+        req.insert_header("X-Forwarded-For", "198.51.100.9").unwrap();
+        req.insert_header("X-Forwarded-Proto", "https").unwrap();
+        req.insert_header("Forwarded", "for=198.51.100.9").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(net.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        let net: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+        let all: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"1.2.3.4".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        let e = "nope".parse::<IpCidr>().unwrap_err();
+        assert_eq!(e.etype(), &INVALID_CONFIG);
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let mut req = request();
+        let ctx = ForwardContext { client_ip: "203.0.113.5".parse().unwrap(), tls: false };
+        config().prepare_upstream_request(&mut req, &ctx).unwrap();
+        assert!(req.headers.get("connection").is_none());
+        assert_eq!(req.headers.get("x-forwarded-for").unwrap(), "203.0.113.5");
+        assert_eq!(req.headers.get("x-forwarded-proto").unwrap(), "http");
+        assert_eq!(req.headers.get("x-forwarded-host").unwrap(), "example.com:8080");
+        assert_eq!(
+            req.headers.get("forwarded").unwrap(),
+            "for=203.0.113.5;host=\"example.com:8080\";proto=http"
+        );
+        assert_eq!(req.headers.get("via").unwrap(), "1.1 crab");
+
+        // the host of an absolute-form target, not the conflicting Host header
+        let mut req = request();
+        req.set_uri("http://admin.internal/".parse().unwrap());
+        config().prepare_upstream_request(&mut req, &ctx).unwrap();
+        assert_eq!(req.headers.get("x-forwarded-host").unwrap(), "admin.internal");
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        let config = config();
+        let mut req = request();
+        req.append_header("X-Forwarded-For", "10.0.0.7").unwrap();
+        let ctx = ForwardContext { client_ip: "10.0.0.2".parse().unwrap(), tls: true };
+        assert_eq!(config.real_client_ip(&req, &ctx), "198.51.100.9".parse::<IpAddr>().unwrap());
+
+        config.prepare_upstream_request(&mut req, &ctx).unwrap();
+        assert_eq!(
+            req.headers.get("x-forwarded-for").unwrap(),
+            "198.51.100.9, 10.0.0.7, 10.0.0.2"
+        );
+        assert_eq!(req.headers.get("x-forwarded-proto").unwrap(), "https");
+        assert_eq!(
+            req.headers.get("forwarded").unwrap(),
+            "for=198.51.100.9, for=10.0.0.2;host=\"example.com:8080\";proto=https"
+        );
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        let ctx = ForwardContext { client_ip: "::1".parse().unwrap(), tls: false };
+        let config = ForwardConfig { forwarded: true, trusted_proxies: vec![], ..Default::default() };
+        config.prepare_upstream_request(&mut req, &ctx).unwrap();
+        assert_eq!(req.headers.get("forwarded").unwrap(), "for=\"[::1]\";proto=http");
+        assert!(req.headers.get("via").is_none());
+    }
+
+    #[test]
+    fn test_response() {
+        let mut resp = ResponseHeader::build_with_status_code(200).unwrap();
+        resp.insert_header("Keep-Alive", "timeout=5").unwrap();
+        resp.insert_header("Via", "1.0 origin").unwrap();
+        config().prepare_downstream_response(&mut resp).unwrap();
+        assert!(resp.headers.get("keep-alive").is_none());
+        let via: Vec<_> = resp.headers.get_all("via").iter().collect();
+        assert_eq!(via, vec!["1.0 origin", "1.1 crab"]);
+    }
+}
@@ -0,0 +1,484 @@
+use std::{collections::HashMap, net::IpAddr, ops::RangeInclusive};
+
+use gateway_error::{Error, Result};
+use gateway_protocols::connections::{request::RequestHeader, response::ResponseHeader};
+use http::{HeaderMap, Method};
+use regex::Regex;
+
+use crate::{
+    common::{compile, INVALID_CONFIG},
+    router::request_host,
+};
+
+/// The headers a rule works on, implemented by [RequestHeader] and [ResponseHeader].
+pub trait HeaderOps {
+    fn header_map(&self) -> &HeaderMap;
+
+    fn insert(&mut self, name: &str, value: Vec<u8>) -> Result<()>;
+
+    fn append(&mut self, name: &str, value: Vec<u8>) -> Result<()>;
+
+    fn remove(&mut self, name: &str) -> Result<()>;
+
+    /// rename every `from` header to `to`, keeping them where they are on the wire.
+    fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+
+    /// replace every value of `name` in place.
+    fn map_values(&mut self, name: &str, f: &mut dyn FnMut(&[u8]) -> Vec<u8>) -> Result<()>;
+
+    /// all values of `name` in order.
+    fn values(&self, name: &str) -> Vec<Vec<u8>> {
+        self.header_map()
+            .get_all(name)
+            .iter()
+            .map(|v| v.as_bytes().to_vec())
+            .collect()
+    }
+}
+
+impl HeaderOps for RequestHeader {
+    fn header_map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: &str, value: Vec<u8>) -> Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: &str, value: Vec<u8>) -> Result<()> {
+        self.append_header(name, value)
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        self.remove_header(name, "")
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.rename_header(from, to)
+    }
+
+    fn map_values(&mut self, name: &str, f: &mut dyn FnMut(&[u8]) -> Vec<u8>) -> Result<()> {
+        self.map_header_values(name, f)
+    }
+}
+
+impl HeaderOps for ResponseHeader {
+    fn header_map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: &str, value: Vec<u8>) -> Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: &str, value: Vec<u8>) -> Result<()> {
+        self.append_header(name, value)
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        self.remove_header(name, "")
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.rename_header(from, to)
+    }
+
+    fn map_values(&mut self, name: &str, f: &mut dyn FnMut(&[u8]) -> Vec<u8>) -> Result<()> {
+        self.map_header_values(name, f)
+    }
+}
+
+/// What the rules know about the exchange, for conditions and `${var}` values.
+#[derive(Debug, Clone, Default)]
+pub struct RuleContext {
+    pub method: Method,
+    pub path: String,
+    pub host: Option<String>,
+    /// upstream status, only known on the response path
+    pub status: Option<u16>,
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<String>,
+    /// extra variables, looked up after the built-in ones
+    pub vars: HashMap<String, String>,
+}
+
+impl RuleContext {
+    pub fn from_request(req: &RequestHeader) -> Self {
+        RuleContext {
+            method: req.method.clone(),
+            path: req.uri.path().to_string(),
+            host: request_host(req),
+            ..Default::default()
+        }
+    }
+
+    pub fn client_ip(mut self, client_ip: IpAddr) -> Self {
+        self.client_ip = Some(client_ip);
+        self
+    }
+
+    pub fn request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// unknown variables expand to nothing.
+    fn lookup(&self, name: &str) -> Option<String> {
+        match name {
+            "method" => Some(self.method.to_string()),
+            "path" => Some(self.path.clone()),
+            "host" => self.host.clone(),
+            "status" => self.status.map(|s| s.to_string()),
+            "client_ip" => self.client_ip.map(|ip| ip.to_string()),
+            "request_id" => self.request_id.clone(),
+            _ => self.vars.get(name).cloned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Var(String),
+}
+
+/// A header value with `${var}` placeholders, `$$` writes a single `$`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut pieces = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(pos) = rest.find('$') {
+            literal.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+            let Some(body) = rest.strip_prefix('{') else {
+                return Error::generate_error_with_root(INVALID_CONFIG,
+                    &format!("`$` must be followed by `{{` or `$` in {s:?}"), None);
+            };
+            let Some(end) = body.find('}') else {
+                return Error::generate_error_with_root(INVALID_CONFIG,
+                    &format!("unterminated variable in {s:?}"), None);
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Var(body[..end].trim().to_string()));
+            rest = &body[end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template { pieces })
+    }
+
+    pub fn render(&self, ctx: &RuleContext) -> String {
+        let mut out = String::new();
+        for piece in self.pieces.iter() {
+            match piece {
+                Piece::Literal(s) => out.push_str(s),
+                Piece::Var(name) => {
+                    if let Some(value) = ctx.lookup(name) {
+                        out.push_str(&value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// When a rule applies.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    PathPrefix(String),
+    PathRegex(Regex),
+    Method(Vec<Method>),
+    /// upstream status within the range, never true on the request path
+    Status(RangeInclusive<u16>),
+    /// the headers being rewritten contain `name`
+    HeaderPresent(String),
+    Not(Box<Condition>),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn path_regex(pattern: &str) -> Result<Self> {
+        Ok(Condition::PathRegex(compile(pattern)?))
+    }
+
+    fn matches(&self, ctx: &RuleContext, headers: &HeaderMap) -> bool {
+        match self {
+            Condition::PathPrefix(prefix) => ctx.path.starts_with(prefix.as_str()),
+            Condition::PathRegex(re) => re.is_match(&ctx.path),
+            Condition::Method(methods) => methods.contains(&ctx.method),
+            Condition::Status(range) => ctx.status.is_some_and(|s| range.contains(&s)),
+            Condition::HeaderPresent(name) => headers.contains_key(name.as_str()),
+            Condition::Not(c) => !c.matches(ctx, headers),
+            Condition::All(cs) => cs.iter().all(|c| c.matches(ctx, headers)),
+            Condition::Any(cs) => cs.iter().any(|c| c.matches(ctx, headers)),
+        }
+    }
+}
+
+/// What a rule does to the headers.
+#[derive(Debug, Clone)]
+pub enum HeaderAction {
+    /// replace all values of the header
+    Set { name: String, value: Template },
+    /// add a value, keeping the existing ones
+    Append { name: String, value: Template },
+    Remove { name: String },
+    /// move all values to another name
+    Rename { from: String, to: String },
+    /// regex replace in every value, `replacement` may refer to captures as `$1`
+    Replace { name: String, pattern: Regex, replacement: String },
+}
+
+impl HeaderAction {
+    pub fn set(name: impl Into<String>, value: &str) -> Result<Self> {
+        Ok(HeaderAction::Set { name: name.into(), value: Template::parse(value)? })
+    }
+
+    pub fn append(name: impl Into<String>, value: &str) -> Result<Self> {
+        Ok(HeaderAction::Append { name: name.into(), value: Template::parse(value)? })
+    }
+
+    pub fn remove(name: impl Into<String>) -> Self {
+        HeaderAction::Remove { name: name.into() }
+    }
+
+    pub fn rename(from: impl Into<String>, to: impl Into<String>) -> Self {
+        HeaderAction::Rename { from: from.into(), to: to.into() }
+    }
+
+    pub fn replace(name: impl Into<String>, pattern: &str, replacement: impl Into<String>) -> Result<Self> {
+        Ok(HeaderAction::Replace {
+            name: name.into(),
+            pattern: compile(pattern)?,
+            replacement: replacement.into(),
+        })
+    }
+
+    fn apply<H: HeaderOps>(&self, headers: &mut H, ctx: &RuleContext) -> Result<()> {
+        match self {
+            HeaderAction::Set { name, value } => headers.insert(name, value.render(ctx).into_bytes()),
+            HeaderAction::Append { name, value } => headers.append(name, value.render(ctx).into_bytes()),
+            HeaderAction::Remove { name } => headers.remove(name),
+            HeaderAction::Rename { from, to } => headers.rename(from, to),
+            HeaderAction::Replace { name, pattern, replacement } => headers.map_values(name, &mut |value| {
+                // values which are not UTF-8 are left untouched
+                match std::str::from_utf8(value) {
+                    Ok(s) => pattern.replace_all(s, replacement.as_str()).into_owned().into_bytes(),
+                    Err(_) => value.to_vec(),
+                }
+            }),
+        }
+    }
+}
+
+/// An action with an optional condition.
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub condition: Option<Condition>,
+    pub action: HeaderAction,
+}
+
+impl HeaderRule {
+    pub fn new(action: HeaderAction) -> Self {
+        HeaderRule { condition: None, action }
+    }
+
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+}
+
+/// Rules for both directions, applied in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    pub request: Vec<HeaderRule>,
+    pub response: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_request(mut self, rule: HeaderRule) -> Self {
+        self.request.push(rule);
+        self
+    }
+
+    pub fn on_response(mut self, rule: HeaderRule) -> Self {
+        self.response.push(rule);
+        self
+    }
+
+    /// rewrite the request before it goes upstream.
+    pub fn apply_request(&self, req: &mut RequestHeader, ctx: &RuleContext) -> Result<()> {
+        apply_rules(&self.request, req, ctx)
+    }
+
+    /// rewrite the upstream response, `ctx.status` is taken from `resp`.
+    pub fn apply_response(&self, resp: &mut ResponseHeader, ctx: &RuleContext) -> Result<()> {
+        if self.response.is_empty() {
+            return Ok(());
+        }
+        let mut ctx = ctx.clone();
+        ctx.status = Some(resp.status.as_u16());
+        apply_rules(&self.response, resp, &ctx)
+    }
+}
+
+fn apply_rules<H: HeaderOps>(rules: &[HeaderRule], headers: &mut H, ctx: &RuleContext) -> Result<()> {
+    for rule in rules {
+        let matched = rule
+            .condition
+            .as_ref()
+            .is_none_or(|c| c.matches(ctx, headers.header_map()));
+        if matched {
+            rule.action.apply(headers, ctx)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> RequestHeader {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/api/v1/users").unwrap();
+        req.insert_header("Host", "example.com").unwrap();
+        req.insert_header("X-Old", "1").unwrap();
+        req.append_header("X-Old", "2").unwrap();
+        req.insert_header("Cookie", "session=abc; theme=dark").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_template() {
+        let ctx = RuleContext::default()
+            .client_ip("10.0.0.1".parse().unwrap())
+            .request_id("r-1")
+            .var("zone", "eu");
+        let t = Template::parse("${client_ip}/${request_id}/${ zone }/${missing}/$$5").unwrap();
+        assert_eq!(t.render(&ctx), "10.0.0.1/r-1/eu//$5");
+        assert!(Template::parse("${open").is_err());
+        assert!(Template::parse("$x").is_err());
+    }
+
+    #[test]
+    fn test_request_rules() {
+        let rules = HeaderRules::new()
+            .on_request(HeaderRule::new(HeaderAction::set("X-Real-IP", "${client_ip}").unwrap()))
+            .on_request(HeaderRule::new(HeaderAction::append("X-Trace", "${request_id}@${host}").unwrap()))
+            .on_request(HeaderRule::new(HeaderAction::rename("X-Old", "X-New")))
+            .on_request(HeaderRule::new(
+                HeaderAction::replace("Cookie", r"session=\w+", "session=redacted").unwrap(),
+            ))
+            .on_request(
+                HeaderRule::new(HeaderAction::set("X-Admin", "1").unwrap())
+                    .when(Condition::PathPrefix("/admin".into())),
+            )
+            .on_request(
+                HeaderRule::new(HeaderAction::remove("Cookie"))
+                    .when(Condition::All(vec![
+                        Condition::Method(vec![Method::GET]),
+                        Condition::path_regex("^/api/v[0-9]+/").unwrap(),
+                        Condition::HeaderPresent("x-new".into()),
+                    ])),
+            );
+
+        let mut req = request();
+        let ctx = RuleContext::from_request(&req)
+            .client_ip("192.0.2.7".parse().unwrap())
+            .request_id("abc");
+        rules.apply_request(&mut req, &ctx).unwrap();
+
+        assert_eq!(req.headers.get("x-real-ip").unwrap(), "192.0.2.7");
+        assert_eq!(req.headers.get("x-trace").unwrap(), "abc@example.com");
+        assert!(req.headers.get("x-old").is_none());
+        let new: Vec<_> = req.headers.get_all("x-new").iter().collect();
+        assert_eq!(new, vec!["1", "2"]);
+        assert!(req.headers.get("x-admin").is_none());
+        assert!(req.headers.get("cookie").is_none());
+    }
+
+    #[test]
+    fn test_replace_keeps_case() {
+        let rules = HeaderRules::new()
+            .on_request(HeaderRule::new(
+                HeaderAction::replace("Cookie", r"theme=(\w+)", "theme=$1-v2").unwrap(),
+            ))
+            .on_request(HeaderRule::new(HeaderAction::rename("x-old", "X-New")));
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        req.preserve_header_case();
+        req.insert_header("Host", "example.com").unwrap();
+        req.insert_header("COOKIE", "session=abc; theme=dark").unwrap();
+        req.insert_header("x-OLD", "1").unwrap();
+        req.insert_header("Accept", "*/*").unwrap();
+        req.append_header("x-OLD", "2").unwrap();
+        let ctx = RuleContext::from_request(&req);
+        rules.apply_request(&mut req, &ctx).unwrap();
+        let mut buf = vec![];
+        req.header_to_h1_wire(&mut buf);
+        // the headers keep their place and the case they came with
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Host: example.com\r\nCOOKIE: session=abc; theme=dark-v2\r\nX-New: 1\r\nAccept: */*\r\nX-New: 2\r\n"
+        );
+        assert_eq!(req.headers.get_all("x-new").iter().count(), 2);
+        assert!(req.headers.get("x-old").is_none());
+    }
+
+    #[test]
+    fn test_response_rules() {
+        let rules = HeaderRules::new()
+            .on_response(
+                HeaderRule::new(HeaderAction::set("Cache-Control", "no-store").unwrap())
+                    .when(Condition::Status(500..=599)),
+            )
+            .on_response(HeaderRule::new(HeaderAction::remove("Server")))
+            .on_response(HeaderRule::new(HeaderAction::set("X-Upstream-Status", "${status}").unwrap()));
+        let ctx = RuleContext::from_request(&request());
+
+        let mut resp = ResponseHeader::build_with_status_code(503).unwrap();
+        resp.insert_header("Server", "nginx").unwrap();
+        rules.apply_response(&mut resp, &ctx).unwrap();
+        assert_eq!(resp.headers.get("cache-control").unwrap(), "no-store");
+        assert!(resp.headers.get("server").is_none());
+        assert_eq!(resp.headers.get("x-upstream-status").unwrap(), "503");
+
+        let mut resp = ResponseHeader::build_with_status_code(200).unwrap();
+        rules.apply_response(&mut resp, &ctx).unwrap();
+        assert!(resp.headers.get("cache-control").is_none());
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        let e = HeaderAction::replace("X", "(", "").unwrap_err();
+        assert_eq!(*e.etype(), INVALID_CONFIG);
+        let e = HeaderAction::set("X", "${").unwrap_err();
+        assert_eq!(*e.etype(), INVALID_CONFIG);
+    }
+}
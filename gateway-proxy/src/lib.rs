@@ -0,0 +1,10 @@
+pub mod access_log;
+pub mod common;
+pub mod forward;
+pub mod header_rules;
+pub mod metrics;
+pub mod path;
+pub mod retry;
+pub mod router;
+pub mod trace;
+pub mod upstream;
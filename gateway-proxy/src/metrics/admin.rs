@@ -0,0 +1,141 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use gateway_error::{error_trait::OrErr, Error, ErrorSource, ErrorType, Result};
+use gateway_protocols::connections::response::ResponseHeader;
+use http::header::{ALLOW, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE};
+use log::debug;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::Registry;
+
+pub const METRICS_PATH: &str = "/metrics";
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// pause after a failed accept, e.g. out of file descriptors, instead of spinning
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve `GET /metrics` from `registry` on `listener`, one request per connection.
+///
+/// runs until the task is aborted.
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("admin accept failed: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &registry).await {
+                debug!("admin request failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let (method, path) = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            return Error::build(ErrorType::ReadTimedout)
+                .source(ErrorSource::DownStream)
+                .context("reading admin request")
+                .err();
+        }
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    let (status, body) = match (method.as_str(), path) {
+        ("GET" | "HEAD", METRICS_PATH) => (200, registry.gather()),
+        (_, METRICS_PATH) => (405, String::new()),
+        _ => (404, String::new()),
+    };
+
+    let mut resp = ResponseHeader::build_with_status_code(status)?;
+    resp.insert_header(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)?;
+    resp.insert_header(CONTENT_LENGTH, body.len())?;
+    resp.insert_header(CONNECTION, "close")?;
+    if status == 405 {
+        resp.insert_header(ALLOW, "GET, HEAD")?;
+    }
+    let mut buf = BytesMut::new();
+    resp.to_h1_wire(&mut buf);
+    if method != "HEAD" {
+        buf.extend_from_slice(body.as_bytes());
+    }
+    stream
+        .write_all(&buf)
+        .await
+        .or_err_source(ErrorType::WriteError, ErrorSource::DownStream, "writing admin response")?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+/// the method and path of the request head.
+async fn read_request(stream: &mut TcpStream) -> Result<(String, String)> {
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        let n = stream
+            .read_buf(&mut buf)
+            .await
+            .or_err_source(ErrorType::ReadError, ErrorSource::DownStream, "reading admin request")?;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                return Ok((req.method.unwrap_or_default().to_string(), req.path.unwrap_or_default().to_string()));
+            }
+            Ok(httparse::Status::Partial) if n > 0 && buf.len() < MAX_REQUEST_SIZE => continue,
+            _ => {
+                return Error::build(ErrorType::InvalidHttpHeader)
+                    .source(ErrorSource::DownStream)
+                    .context("invalid admin request")
+                    .err();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Counter, Family};
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() {
+        let registry = Arc::new(Registry::new());
+        let requests = registry.register(Family::<Counter>::counter("requests_total", "Requests.", &[]));
+        requests.with(&[]).inc();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, registry));
+
+        let resp = get(addr, "GET /metrics HTTP/1.1\r\nHost: admin\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.to_ascii_lowercase().contains("content-type: text/plain; version=0.0.4"), "{resp}");
+        assert!(resp.ends_with("# TYPE requests_total counter\nrequests_total 1\n"), "{resp}");
+
+        let resp = get(addr, "GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 404"), "{resp}");
+        let resp = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 405"), "{resp}");
+        assert!(resp.to_ascii_lowercase().contains("allow: get, head\r\n"), "{resp}");
+        server.abort();
+    }
+}
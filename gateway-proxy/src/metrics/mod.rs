@@ -0,0 +1,433 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use gateway_error::Error;
+use gateway_protocols::connections::digest::RequestTiming;
+
+pub mod admin;
+
+/// Bucket bounds in seconds, from 1ms to 10s.
+pub const DEFAULT_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// One series of a [Family], encoded in the Prometheus text format.
+pub trait Metric: Send + Sync + 'static {
+    const TYPE: &'static str;
+
+    /// write the sample lines of this series, `labels` is `a="x",b="y"` or empty.
+    fn encode(&self, name: &str, labels: &str, out: &mut String);
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    const TYPE: &'static str = "counter";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        sample(out, name, labels, None, self.get() as f64);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Gauge {
+    const TYPE: &'static str = "gauge";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        sample(out, name, labels, None, self.get() as f64);
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// per bucket, not cumulative; the last one is `+Inf`
+    counts: Vec<AtomicU64>,
+    /// f64 bits
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// `bounds` must be sorted ascending.
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let i = self.bounds.partition_point(|bound| *bound < v);
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| Some((f64::from_bits(sum) + v).to_bits()));
+    }
+
+    pub fn observe_duration(&self, d: Duration) {
+        self.observe(d.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+}
+
+impl Metric for Histogram {
+    const TYPE: &'static str = "histogram";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        let bucket = format!("{name}_bucket");
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            sample(out, &bucket, labels, Some(&le), cumulative as f64);
+        }
+        sample(out, &format!("{name}_sum"), labels, None, self.sum());
+        sample(out, &format!("{name}_count"), labels, None, cumulative as f64);
+    }
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, le: Option<&str>, value: f64) {
+    out.push_str(name);
+    match (labels.is_empty(), le) {
+        (true, None) => {}
+        (true, Some(le)) => {
+            let _ = write!(out, "{{le=\"{le}\"}}");
+        }
+        (false, None) => {
+            let _ = write!(out, "{{{labels}}}");
+        }
+        (false, Some(le)) => {
+            let _ = write!(out, "{{{labels},le=\"{le}\"}}");
+        }
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Something a [Registry] can encode.
+pub trait Collector: Send + Sync {
+    fn encode(&self, out: &mut String);
+}
+
+/// A metric split into series by label values.
+pub struct Family<M> {
+    name: String,
+    help: String,
+    label_names: Vec<&'static str>,
+    make: Box<dyn Fn() -> M + Send + Sync>,
+    series: RwLock<BTreeMap<Vec<String>, Arc<M>>>,
+}
+
+impl<M: Metric> Family<M> {
+    fn new(name: &str, help: &str, label_names: &[&'static str], make: Box<dyn Fn() -> M + Send + Sync>) -> Self {
+        Family {
+            name: name.to_string(),
+            help: help.to_string(),
+            label_names: label_names.to_vec(),
+            make,
+            series: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// the series of `values`, one per label name, created on first use.
+    pub fn with(&self, values: &[&str]) -> Arc<M> {
+        assert_eq!(values.len(), self.label_names.len(), "label values of {}", self.name);
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        if let Some(series) = self.series.read().unwrap().get(&key) {
+            return series.clone();
+        }
+        self.series.write().unwrap().entry(key).or_insert_with(|| Arc::new((self.make)())).clone()
+    }
+}
+
+impl Family<Counter> {
+    pub fn counter(name: &str, help: &str, label_names: &[&'static str]) -> Self {
+        Self::new(name, help, label_names, Box::new(Counter::default))
+    }
+}
+
+impl Family<Gauge> {
+    pub fn gauge(name: &str, help: &str, label_names: &[&'static str]) -> Self {
+        Self::new(name, help, label_names, Box::new(Gauge::default))
+    }
+}
+
+impl Family<Histogram> {
+    pub fn histogram(name: &str, help: &str, label_names: &[&'static str], bounds: &[f64]) -> Self {
+        let bounds = bounds.to_vec();
+        Self::new(name, help, label_names, Box::new(move || Histogram::new(&bounds)))
+    }
+}
+
+impl<M: Metric> Collector for Family<M> {
+    fn encode(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help.replace('\\', "\\\\").replace('\n', "\\n"));
+        let _ = writeln!(out, "# TYPE {} {}", self.name, M::TYPE);
+        for (values, metric) in self.series.read().unwrap().iter() {
+            let labels = self
+                .label_names
+                .iter()
+                .zip(values)
+                .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            metric.encode(&self.name, &labels, out);
+        }
+    }
+}
+
+/// The metrics served by the admin endpoint, encoded in registration order.
+#[derive(Default)]
+pub struct Registry {
+    collectors: Mutex<Vec<Arc<dyn Collector>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// register `collector` and hand it back.
+    pub fn register<C: Collector + 'static>(&self, collector: C) -> Arc<C> {
+        let collector = Arc::new(collector);
+        self.collectors.lock().unwrap().push(collector.clone());
+        collector
+    }
+
+    /// everything in the Prometheus text format.
+    pub fn gather(&self) -> String {
+        let mut out = String::new();
+        for collector in self.collectors.lock().unwrap().iter() {
+            collector.encode(&mut out);
+        }
+        out
+    }
+}
+
+/// Keep a downstream connection counted in its listener gauge until dropped.
+pub struct ConnectionGuard(Arc<Gauge>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// The metrics of the proxy.
+pub struct ProxyMetrics {
+    requests: Arc<Family<Counter>>,
+    request_duration: Arc<Family<Histogram>>,
+    upstream_phases: Arc<Family<Histogram>>,
+    connections: Arc<Family<Gauge>>,
+    connections_total: Arc<Family<Counter>>,
+    pool: Arc<Family<Counter>>,
+    connect_errors: Arc<Family<Counter>>,
+    body_bytes: Arc<Family<Counter>>,
+}
+
+impl ProxyMetrics {
+    pub fn new(registry: &Registry) -> Self {
+        ProxyMetrics {
+            requests: registry.register(Family::counter(
+                "gateway_requests_total",
+                "Requests by route, status and upstream.",
+                &["route", "status", "upstream"],
+            )),
+            request_duration: registry.register(Family::histogram(
+                "gateway_request_duration_seconds",
+                "Total time of a request.",
+                &["route"],
+                &DEFAULT_BUCKETS,
+            )),
+            upstream_phases: registry.register(Family::histogram(
+                "gateway_upstream_phase_seconds",
+                "Time of each phase of an upstream request, from the timing digest.",
+                &["upstream", "phase"],
+                &DEFAULT_BUCKETS,
+            )),
+            connections: registry.register(Family::gauge(
+                "gateway_downstream_connections",
+                "Open downstream connections per listener.",
+                &["listener"],
+            )),
+            connections_total: registry.register(Family::counter(
+                "gateway_downstream_connections_total",
+                "Accepted downstream connections per listener.",
+                &["listener"],
+            )),
+            pool: registry.register(Family::counter(
+                "gateway_upstream_pool_total",
+                "Upstream connection pool lookups, result is hit or miss.",
+                &["upstream", "result"],
+            )),
+            connect_errors: registry.register(Family::counter(
+                "gateway_upstream_connect_errors_total",
+                "Failed upstream connects by error type.",
+                &["upstream", "error"],
+            )),
+            body_bytes: registry.register(Family::counter(
+                "gateway_body_bytes_total",
+                "Body bytes by route, direction is received or sent.",
+                &["route", "direction"],
+            )),
+        }
+    }
+
+    /// count a finished request and observe its timing.
+    pub fn record_request(&self, route: &str, status: u16, upstream: &str, timing: &RequestTiming) {
+        self.requests.with(&[route, &status.to_string(), upstream]).inc();
+        if let Some(total) = timing.total {
+            self.request_duration.with(&[route]).observe_duration(total);
+        }
+        for (phase, d) in timing.phases() {
+            if phase != "total" {
+                self.upstream_phases.with(&[upstream, phase]).observe_duration(d);
+            }
+        }
+    }
+
+    /// count an accepted connection, it stays open until the guard is dropped.
+    pub fn connection_opened(&self, listener: &str) -> ConnectionGuard {
+        self.connections_total.with(&[listener]).inc();
+        let gauge = self.connections.with(&[listener]);
+        gauge.inc();
+        ConnectionGuard(gauge)
+    }
+
+    pub fn pool_lookup(&self, upstream: &str, hit: bool) {
+        self.pool.with(&[upstream, if hit { "hit" } else { "miss" }]).inc();
+    }
+
+    pub fn connect_error(&self, upstream: &str, e: &Error) {
+        self.connect_errors.with(&[upstream, e.etype().as_str()]).inc();
+    }
+
+    pub fn body_bytes(&self, route: &str, received: usize, sent: usize) {
+        self.body_bytes.with(&[route, "received"]).inc_by(received as u64);
+        self.body_bytes.with(&[route, "sent"]).inc_by(sent as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gateway_error::ErrorType;
+
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter = registry.register(Family::counter("c_total", "A counter.", &["k"]));
+        counter.with(&["a\"b"]).inc_by(3);
+        let hist = registry.register(Family::histogram("h_seconds", "A histogram.", &[], &[0.1, 1.0]));
+        let h = hist.with(&[]);
+        h.observe(0.05);
+        h.observe(0.5);
+        h.observe(0.5);
+        h.observe(5.0);
+
+        assert_eq!(
+            registry.gather(),
+            "# HELP c_total A counter.\n\
+             # TYPE c_total counter\n\
+             c_total{k=\"a\\\"b\"} 3\n\
+             # HELP h_seconds A histogram.\n\
+             # TYPE h_seconds histogram\n\
+             h_seconds_bucket{le=\"0.1\"} 1\n\
+             h_seconds_bucket{le=\"1\"} 3\n\
+             h_seconds_bucket{le=\"+Inf\"} 4\n\
+             h_seconds_sum 6.05\n\
+             h_seconds_count 4\n"
+        );
+    }
+
+    #[test]
+    fn test_proxy_metrics() {
+        let registry = Registry::new();
+        let metrics = ProxyMetrics::new(&registry);
+        let timing = RequestTiming {
+            connect: Some(Duration::from_millis(2)),
+            ttfb: Some(Duration::from_millis(20)),
+            total: Some(Duration::from_millis(30)),
+            ..Default::default()
+        };
+        metrics.record_request("api", 200, "10.0.0.1:80", &timing);
+        metrics.record_request("api", 200, "10.0.0.1:80", &timing);
+        let guard = metrics.connection_opened("https");
+        metrics.pool_lookup("10.0.0.1:80", false);
+        let e = Error::build(ErrorType::ConnectTimeout).finish();
+        metrics.connect_error("10.0.0.1:80", &e);
+        metrics.body_bytes("api", 10, 2048);
+
+        let text = registry.gather();
+        for line in [
+            "gateway_requests_total{route=\"api\",status=\"200\",upstream=\"10.0.0.1:80\"} 2",
+            "gateway_request_duration_seconds_bucket{route=\"api\",le=\"0.05\"} 2",
+            "gateway_upstream_phase_seconds_count{upstream=\"10.0.0.1:80\",phase=\"ttfb\"} 2",
+            "gateway_downstream_connections{listener=\"https\"} 1",
+            "gateway_upstream_pool_total{upstream=\"10.0.0.1:80\",result=\"miss\"} 1",
+            "gateway_upstream_connect_errors_total{upstream=\"10.0.0.1:80\",error=\"ConnectTimeout\"} 1",
+            "gateway_body_bytes_total{route=\"api\",direction=\"sent\"} 2048",
+        ] {
+            assert!(text.contains(line), "{line} missing in\n{text}");
+        }
+        assert!(!text.contains("phase=\"total\""));
+
+        drop(guard);
+        assert!(registry.gather().contains("gateway_downstream_connections{listener=\"https\"} 0"));
+    }
+}
@@ -0,0 +1,339 @@
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use gateway_protocols::connections::request::{RequestHeader, RequestTarget};
+use http::{uri::PathAndQuery, Uri};
+use regex::Regex;
+
+use crate::common::compile;
+
+/// What to do with `%2F` and `%5C`, which would change the segments when decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodedSlash {
+    /// answer 400
+    Reject,
+    /// leave them encoded, they do not split segments
+    Keep,
+    /// decode them, they split segments like `/`
+    Decode,
+}
+
+/// How the path is normalized before any route matches it.
+#[derive(Debug, Clone)]
+pub struct NormalizePolicy {
+    pub encoded_slash: EncodedSlash,
+    /// reject `%00`
+    pub reject_encoded_nul: bool,
+    /// turn `//` into `/`
+    pub merge_slashes: bool,
+}
+
+impl Default for NormalizePolicy {
+    fn default() -> Self {
+        NormalizePolicy {
+            encoded_slash: EncodedSlash::Reject,
+            reject_encoded_nul: true,
+            merge_slashes: true,
+        }
+    }
+}
+
+fn bad_path(context: &str) -> Box<Error> {
+    Error::build(ErrorType::InvalidHttpHeader)
+        .source(ErrorSource::DownStream)
+        .context(context.to_string())
+        .finish()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[inline]
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Normalize an origin-form path (without query).
+///
+/// unreserved characters are decoded and other escapes uppercased (RFC 3986 section 6.2.2),
+/// then dot segments are removed (section 5.2.4) and slashes merged as the policy says.
+pub fn normalize_path(path: &str, policy: &NormalizePolicy) -> Result<String> {
+    if !path.starts_with('/') {
+        return Err(bad_path("path must start with `/`"));
+    }
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            // copy up to the next escape as is, keeping multi-byte characters whole
+            let end = path[i..].find('%').map_or(path.len(), |n| i + n);
+            decoded.push_str(&path[i..end]);
+            i = end;
+            continue;
+        }
+        let (Some(hi), Some(lo)) = (
+            bytes.get(i + 1).copied().and_then(hex_value),
+            bytes.get(i + 2).copied().and_then(hex_value),
+        ) else {
+            return Err(bad_path("invalid percent-encoding in path"));
+        };
+        let c = hi << 4 | lo;
+        i += 3;
+        match c {
+            0 if policy.reject_encoded_nul => return Err(bad_path("encoded NUL in path")),
+            b'/' | b'\\' => match policy.encoded_slash {
+                EncodedSlash::Reject => return Err(bad_path("encoded slash in path")),
+                EncodedSlash::Decode => decoded.push('/'),
+                EncodedSlash::Keep => decoded.push_str(&format!("%{c:02X}")),
+            },
+            c if is_unreserved(c) => decoded.push(c as char),
+            c => decoded.push_str(&format!("%{c:02X}")),
+        }
+    }
+
+    let trailing_slash = decoded.ends_with('/');
+    let mut segments: Vec<&str> = vec![];
+    let mut raw_segments: Vec<&str> = decoded[1..].split('/').collect();
+    // a final `.` or `..` still denotes a directory
+    let ends_with_dot = matches!(raw_segments.last(), Some(&".") | Some(&".."));
+    if policy.merge_slashes {
+        raw_segments.retain(|s| !s.is_empty());
+    }
+    for segment in raw_segments {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in segments.iter() {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || ((trailing_slash || ends_with_dot) && !normalized.ends_with('/')) {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Replace the path and query of `req`, keeping scheme and authority.
+pub fn set_path_and_query(req: &mut RequestHeader, path: &str, query: Option<&str>) -> Result<()> {
+    let pq = match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri.clone().into_parts();
+    parts.path_and_query = Some(
+        PathAndQuery::try_from(pq).map_err(|e| {
+            Error::generate_error_with_root_raw(ErrorType::InvalidHttpHeader,
+                "invalid rewritten path", Some(Box::new(e)))
+        })?,
+    );
+    let uri = Uri::from_parts(parts).map_err(|e| {
+        Error::generate_error_with_root_raw(ErrorType::InvalidHttpHeader,
+            "invalid rewritten uri", Some(Box::new(e)))
+    })?;
+    req.set_uri(uri);
+    Ok(())
+}
+
+/// Normalize the path of `req` in place, see [normalize_path].
+///
+/// `CONNECT` and `OPTIONS *` have no path and are left alone.
+///
+/// the uri is always rewritten, so the raw bytes of the request line are not what
+/// goes upstream when they differ from the path routing sees.
+pub fn normalize_request(req: &mut RequestHeader, policy: &NormalizePolicy) -> Result<()> {
+    if matches!(req.target_form(), RequestTarget::Authority | RequestTarget::Asterisk) {
+        return Ok(());
+    }
+    let normalized = normalize_path(req.uri.path(), policy)?;
+    let query = req.uri.query().map(str::to_string);
+    set_path_and_query(req, &normalized, query.as_deref())
+}
+
+/// A rewrite of the path or query, applied after normalization.
+#[derive(Debug, Clone)]
+pub enum PathRewrite {
+    /// `/api/users` with prefix `/api` becomes `/users`, the path is untouched
+    /// when it does not start with the prefix
+    StripPrefix(String),
+    /// replace a leading `from` with `to`
+    ReplacePrefix { from: String, to: String },
+    /// replace the first match, `replacement` may refer to captures as `$1` or `${name}`
+    Regex { pattern: Regex, replacement: String },
+    /// append `name=value`, both already percent-encoded
+    AddQuery { name: String, value: String },
+    /// drop every `name` parameter
+    RemoveQuery(String),
+}
+
+impl PathRewrite {
+    pub fn regex(pattern: &str, replacement: impl Into<String>) -> Result<Self> {
+        Ok(PathRewrite::Regex { pattern: compile(pattern)?, replacement: replacement.into() })
+    }
+
+    pub fn apply(&self, req: &mut RequestHeader) -> Result<()> {
+        let path = req.uri.path();
+        let query = req.uri.query();
+        let (new_path, new_query) = match self {
+            PathRewrite::StripPrefix(prefix) => match strip_segment_prefix(path, prefix) {
+                Some("") => ("/".to_string(), query.map(str::to_string)),
+                Some(rest) => (rest.to_string(), query.map(str::to_string)),
+                None => return Ok(()),
+            },
+            PathRewrite::ReplacePrefix { from, to } => match strip_segment_prefix(path, from) {
+                Some(rest) => {
+                    let mut replaced = format!("{}{rest}", to.trim_end_matches('/'));
+                    if !replaced.starts_with('/') {
+                        replaced.insert(0, '/');
+                    }
+                    (replaced, query.map(str::to_string))
+                }
+                None => return Ok(()),
+            },
+            PathRewrite::Regex { pattern, replacement } => {
+                if !pattern.is_match(path) {
+                    return Ok(());
+                }
+                let mut replaced = pattern.replace(path, replacement.as_str()).into_owned();
+                if !replaced.starts_with('/') {
+                    replaced.insert(0, '/');
+                }
+                (replaced, query.map(str::to_string))
+            }
+            PathRewrite::AddQuery { name, value } => {
+                let param = format!("{name}={value}");
+                let query = match query {
+                    Some(q) if !q.is_empty() => format!("{q}&{param}"),
+                    _ => param,
+                };
+                (path.to_string(), Some(query))
+            }
+            PathRewrite::RemoveQuery(name) => {
+                let Some(q) = query else {
+                    return Ok(());
+                };
+                let kept: Vec<&str> = q
+                    .split('&')
+                    .filter(|p| p.split('=').next() != Some(name.as_str()))
+                    .collect();
+                let query = (!kept.is_empty()).then(|| kept.join("&"));
+                (path.to_string(), query)
+            }
+        };
+        set_path_and_query(req, &new_path, new_query.as_deref())
+    }
+}
+
+/// strip `prefix` only at a segment boundary, so `/api` does not match `/apix`.
+fn strip_segment_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// Apply every rewrite in order.
+pub fn rewrite_request(req: &mut RequestHeader, rewrites: &[PathRewrite]) -> Result<()> {
+    for rewrite in rewrites {
+        rewrite.apply(req)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(path: &str) -> Result<String> {
+        normalize_path(path, &NormalizePolicy::default())
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize("/a/b/../c/./d").unwrap(), "/a/c/d");
+        assert_eq!(normalize("/a/b/..").unwrap(), "/a/");
+        assert_eq!(normalize("/../../a").unwrap(), "/a");
+        assert_eq!(normalize("//a///b//").unwrap(), "/a/b/");
+        assert_eq!(normalize("/%7Euser/%61/%2e%2E/x").unwrap(), "/~user/x");
+        assert_eq!(normalize("/a%3fb%c3%a9").unwrap(), "/a%3Fb%C3%A9");
+        assert_eq!(normalize("/café/./%7e/日本").unwrap(), "/café/~/日本");
+        assert_eq!(normalize("/").unwrap(), "/");
+        assert_eq!(normalize("/..").unwrap(), "/");
+
+        assert!(normalize("/a%2Fb").is_err());
+        assert!(normalize("/a%5cb").is_err());
+        assert!(normalize("/a%00").is_err());
+        assert!(normalize("/a%zz").is_err());
+        assert!(normalize("/a%4").is_err());
+        assert!(normalize("a").is_err());
+
+        let policy = NormalizePolicy {
+            encoded_slash: EncodedSlash::Decode,
+            reject_encoded_nul: true,
+            merge_slashes: false,
+        };
+        assert_eq!(normalize_path("/a%2F..%2Fb//c", &policy).unwrap(), "/b//c");
+        let policy = NormalizePolicy { encoded_slash: EncodedSlash::Keep, ..policy };
+        assert_eq!(normalize_path("/a%2fb/../c", &policy).unwrap(), "/c");
+    }
+
+    #[test]
+    fn test_normalize_request() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a/./b/../c?x=/../y").unwrap();
+        normalize_request(&mut req, &NormalizePolicy::default()).unwrap();
+        assert_eq!(req.uri, "/a/c?x=/../y");
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a/%2e%2e/%2F").unwrap();
+        let e = normalize_request(&mut req, &NormalizePolicy::default()).unwrap_err();
+        assert_eq!(gateway_protocols::http::error_resp::error_status(&e), 400);
+
+        // the raw bytes are not forwarded in place of the path that was matched
+        let mut req = RequestHeader::build_with_method_path("GET", b"/caf\xC3\xA9?q").unwrap();
+        let matched = req.uri.to_string();
+        normalize_request(&mut req, &NormalizePolicy::default()).unwrap();
+        assert_eq!(req.raw_path(), matched.as_bytes());
+
+        let mut req = RequestHeader::build_with_method_path("OPTIONS", b"*").unwrap();
+        normalize_request(&mut req, &NormalizePolicy::default()).unwrap();
+        assert_eq!(req.raw_path(), b"*");
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/api/v1/users/42?debug=1&a=b").unwrap();
+        rewrite_request(&mut req, &[
+            PathRewrite::StripPrefix("/api/".into()),
+            PathRewrite::regex(r"^/v1/users/(?P<id>\d+)$", "/users/${id}/profile").unwrap(),
+            PathRewrite::RemoveQuery("debug".into()),
+            PathRewrite::AddQuery { name: "src".into(), value: "gw".into() },
+        ]).unwrap();
+        assert_eq!(req.uri, "/users/42/profile?a=b&src=gw");
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/apix/a").unwrap();
+        PathRewrite::StripPrefix("/api".into()).apply(&mut req).unwrap();
+        assert_eq!(req.uri, "/apix/a");
+        PathRewrite::ReplacePrefix { from: "/apix".into(), to: "/internal/".into() }.apply(&mut req).unwrap();
+        assert_eq!(req.uri, "/internal/a");
+        PathRewrite::StripPrefix("/internal/a".into()).apply(&mut req).unwrap();
+        assert_eq!(req.uri, "/");
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/x?only=1").unwrap();
+        PathRewrite::RemoveQuery("only".into()).apply(&mut req).unwrap();
+        assert_eq!(req.uri, "/x");
+        assert!(PathRewrite::regex("(", "").is_err());
+
+        // a replacement without a leading slash still yields an absolute path
+        let mut req = RequestHeader::build_with_method_path("GET", b"/v1/a?q=1").unwrap();
+        PathRewrite::regex(r"^/v1/", "v2/").unwrap().apply(&mut req).unwrap();
+        assert_eq!(req.uri, "/v2/a?q=1");
+    }
+}
@@ -0,0 +1,593 @@
+use std::{future::Future, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use gateway_protocols::connections::{request::RequestHeader, response::ResponseHeader};
+use http::Method;
+use log::debug;
+
+use crate::upstream::circuit_breaker::{CircuitBreaker, Resource};
+
+/// Why an attempt is treated as failed and may be sent to another upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetryReason {
+    /// upstream answered with a status listed in [RetryPolicy::retry_on_status]
+    Status(u16),
+    /// the attempt failed with an error
+    Error(ErrorType),
+}
+
+/// Pick the upstream for every attempt of a request.
+pub trait UpstreamSelector {
+    type Peer: Clone;
+
+    /// select the upstream for the `attempt`-th try (starting from 0).
+    ///
+    /// return `None` when there is no other upstream to fail over to.
+    fn select(&mut self, attempt: usize) -> Option<Self::Peer>;
+}
+
+/// Try a fixed list of upstreams in order, each of them at most once.
+pub struct FailoverList<P> {
+    peers: Vec<P>,
+}
+
+impl<P> FailoverList<P> {
+    pub fn new(peers: Vec<P>) -> Self {
+        FailoverList { peers }
+    }
+}
+
+impl<P: Clone> UpstreamSelector for FailoverList<P> {
+    type Peer = P;
+
+    fn select(&mut self, attempt: usize) -> Option<P> {
+        self.peers.get(attempt).cloned()
+    }
+}
+
+/// Hook to reshape the request before it is sent to the next upstream.
+pub trait RequestRewrite {
+    /// `attempt` is the number of attempts already made.
+    fn rewrite(&self, req: &mut RequestHeader, attempt: usize, reason: &RetryReason) -> Result<()>;
+}
+
+impl<F> RequestRewrite for F
+where
+    F: Fn(&mut RequestHeader, usize, &RetryReason) -> Result<()>,
+{
+    fn rewrite(&self, req: &mut RequestHeader, attempt: usize, reason: &RetryReason) -> Result<()> {
+        self(req, attempt, reason)
+    }
+}
+
+/// Limit retries to a ratio of the requests in flight.
+///
+/// A retry is allowed while the active retries stay below
+/// `max(min_retry_concurrency, active_requests * ratio)`, so a struggling upstream
+/// cannot multiply the load sent to it.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    min_retry_concurrency: usize,
+    active_requests: AtomicUsize,
+    active_retries: AtomicUsize,
+}
+
+/// Keep a request or a retry accounted in a [RetryBudget] until dropped.
+pub struct BudgetGuard {
+    budget: Arc<RetryBudget>,
+    is_retry: bool,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64, min_retry_concurrency: usize) -> Self {
+        RetryBudget {
+            ratio: ratio.max(0.0),
+            min_retry_concurrency,
+            active_requests: AtomicUsize::new(0),
+            active_retries: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn enter_request(self: &Arc<Self>) -> BudgetGuard {
+        self.active_requests.fetch_add(1, Ordering::Relaxed);
+        BudgetGuard { budget: self.clone(), is_retry: false }
+    }
+
+    /// return `None` when the budget is used up.
+    pub fn try_acquire_retry(self: &Arc<Self>) -> Option<BudgetGuard> {
+        let limit = self.retry_limit();
+        self.active_retries
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |retries| {
+                (retries < limit).then_some(retries + 1)
+            })
+            .ok()
+            .map(|_| BudgetGuard { budget: self.clone(), is_retry: true })
+    }
+
+    pub fn active_retries(&self) -> usize {
+        self.active_retries.load(Ordering::Relaxed)
+    }
+
+    fn retry_limit(&self) -> usize {
+        let by_ratio = (self.active_requests.load(Ordering::Relaxed) as f64 * self.ratio) as usize;
+        by_ratio.max(self.min_retry_concurrency)
+    }
+}
+
+impl Drop for BudgetGuard {
+    fn drop(&mut self) {
+        let counter = if self.is_retry {
+            &self.budget.active_retries
+        } else {
+            &self.budget.active_requests
+        };
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The result of [RetryPolicy::execute].
+pub struct RetryOutcome<P, T> {
+    /// the last attempt's result
+    pub result: Result<T>,
+    /// how many attempts were sent
+    pub attempts: usize,
+    /// the upstream of the last attempt
+    pub peer: Option<P>,
+}
+
+/// Decide whether and how a failed upstream attempt is retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// total attempts including the first one
+    pub max_attempts: usize,
+    /// timeout of every single attempt
+    pub per_try_timeout: Option<Duration>,
+    /// upstream status codes that trigger a retry
+    pub retry_on_status: Vec<u16>,
+    /// retry [ErrorType::ConnectTimeout]/[ErrorType::ConnectRefused], safe for every method
+    /// because nothing has reached the upstream yet
+    pub retry_on_connect_failure: bool,
+    /// retry attempts that hit `per_try_timeout` or a read/write timeout of the upstream
+    pub retry_on_timeout: bool,
+    /// also retry non-idempotent methods after the request may have reached the upstream
+    pub retry_non_idempotent: bool,
+    pub budget: Option<Arc<RetryBudget>>,
+    /// the breaker of the upstream cluster, capping its requests in flight and concurrent retries
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            per_try_timeout: None,
+            retry_on_status: vec![502, 503, 504],
+            retry_on_connect_failure: true,
+            retry_on_timeout: true,
+            retry_non_idempotent: false,
+            budget: None,
+            circuit_breaker: None,
+        }
+    }
+}
+
+/// methods that can be safely sent twice, see RFC 9110 section 9.2.2
+#[inline]
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: usize) -> Self {
+        RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// return the reason to retry when `resp` should not be handed to downstream.
+    pub fn response_retry_reason(&self, resp: &ResponseHeader, idempotent: bool) -> Option<RetryReason> {
+        let status = resp.status.as_u16();
+        (self.retry_on_status.contains(&status) && self.allow_resend(idempotent))
+            .then_some(RetryReason::Status(status))
+    }
+
+    /// return the reason to retry when `e` is worth another upstream.
+    ///
+    /// errors still marked [gateway_error::RetryType::ReusedOnly] are judged as if the
+    /// connection was fresh, senders using pooled connections should call
+    /// [Error::decide_reuse] before handing the error back.
+    pub fn error_retry_reason(&self, e: &Error, idempotent: bool) -> Option<RetryReason> {
+        let retry = match e.etype() {
+            ErrorType::ConnectTimeout | ErrorType::ConnectRefused => self.retry_on_connect_failure,
+            ErrorType::PerTryTimeout | ErrorType::ReadTimedout | ErrorType::WriteTimedout => {
+                self.retry_on_timeout && self.allow_resend(idempotent)
+            }
+            ErrorType::HttpCode(code) => {
+                self.retry_on_status.contains(code) && self.allow_resend(idempotent)
+            }
+            _ => e.retry(false) && self.allow_resend(idempotent),
+        };
+        retry.then(|| RetryReason::Error(e.etype().clone()))
+    }
+
+    #[inline]
+    fn allow_resend(&self, idempotent: bool) -> bool {
+        idempotent || self.retry_non_idempotent
+    }
+
+    /// Send `req` through `send`, failing over to the upstreams from `selector`
+    /// until an attempt succeeds or the policy gives up.
+    ///
+    /// `rewrite` runs on `req` between attempts. When every attempt failed, the outcome
+    /// holds the last response or error. With `max_requests` of the circuit breaker in
+    /// flight the request fails with [ErrorType::CircuitBreakerOpen] before any attempt.
+    pub async fn execute<S, F, Fut, T>(
+        &self,
+        req: &mut RequestHeader,
+        selector: &mut S,
+        rewrite: Option<&dyn RequestRewrite>,
+        mut send: F,
+    ) -> RetryOutcome<S::Peer, T>
+    where
+        S: UpstreamSelector,
+        F: FnMut(Box<RequestHeader>, S::Peer) -> Fut,
+        Fut: Future<Output = Result<T>>,
+        T: AsRef<ResponseHeader>,
+    {
+        let _in_flight = match self.circuit_breaker.as_ref() {
+            Some(breaker) => match breaker.try_acquire(Resource::Request) {
+                Ok(guard) => Some(guard),
+                Err(e) => return RetryOutcome { result: Err(e), attempts: 0, peer: None },
+            },
+            None => None,
+        };
+        let _request_guard = self.budget.as_ref().map(|b| b.enter_request());
+        let idempotent = is_idempotent(&req.method);
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempts = 0;
+        let mut last = None;
+
+        loop {
+            let _retry_guard = match (attempts, self.budget.as_ref()) {
+                (0, _) | (_, None) => None,
+                (_, Some(budget)) => match budget.try_acquire_retry() {
+                    Some(guard) => Some(guard),
+                    None => {
+                        debug!("retry budget exhausted after {attempts} attempts");
+                        break;
+                    }
+                },
+            };
+            let _breaker_guard = match (attempts, self.circuit_breaker.as_ref()) {
+                (0, _) | (_, None) => None,
+                (_, Some(breaker)) => match breaker.try_acquire(Resource::Retry) {
+                    Ok(guard) => Some(guard),
+                    Err(e) => {
+                        debug!("no retry after {attempts} attempts: {e}");
+                        break;
+                    }
+                },
+            };
+            let Some(peer) = selector.select(attempts) else {
+                debug!("no more upstream to retry after {attempts} attempts");
+                break;
+            };
+
+            attempts += 1;
+            let attempt = send(Box::new(req.clone()), peer.clone());
+            let result = match self.per_try_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, attempt).await {
+                    Ok(result) => result,
+                    Err(_) => Error::build(ErrorType::PerTryTimeout)
+                        .source(ErrorSource::UpStream)
+                        .context(format!("upstream attempt {attempts} timed out after {timeout:?}"))
+                        .err(),
+                },
+                None => attempt.await,
+            };
+
+            let reason = match &result {
+                Ok(resp) => self.response_retry_reason(resp.as_ref(), idempotent),
+                Err(e) => self.error_retry_reason(e, idempotent),
+            };
+            let Some(reason) = reason else {
+                return RetryOutcome { result, attempts, peer: Some(peer) };
+            };
+            if attempts >= max_attempts {
+                return RetryOutcome { result, attempts, peer: Some(peer) };
+            }
+            debug!("attempt {attempts} failed with {reason:?}, retrying");
+            if let Some(rewrite) = rewrite {
+                if let Err(e) = rewrite.rewrite(req, attempts, &reason) {
+                    return RetryOutcome { result: Err(e), attempts, peer: Some(peer) };
+                }
+            }
+            last = Some((result, peer));
+        }
+
+        match last {
+            Some((result, peer)) => RetryOutcome { result, attempts, peer: Some(peer) },
+            None => RetryOutcome {
+                result: Error::build(ErrorType::NoUpstream)
+                    .source(ErrorSource::Internal)
+                    .context("no upstream selected")
+                    .err(),
+                attempts,
+                peer: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use gateway_error::{Error, ErrorType, Result, RetryType};
+    use gateway_protocols::connections::{request::RequestHeader, response::ResponseHeader};
+
+    use super::*;
+
+    fn response(status: u16) -> Result<Box<ResponseHeader>> {
+        Ok(Box::new(ResponseHeader::build_with_status_code(status).unwrap()))
+    }
+
+    fn error(etype: ErrorType) -> Result<Box<ResponseHeader>> {
+        Error::generate_error_with_root(etype, "mock upstream", None)
+    }
+
+    #[tokio::test]
+    async fn failover_on_status() {
+        let policy = RetryPolicy::default();
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["a", "b", "c"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, peer| async move {
+                match peer {
+                    "a" => response(502),
+                    _ => response(200),
+                }
+            })
+            .await;
+
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.peer, Some("b"));
+        assert_eq!(outcome.result.unwrap().status, 200);
+    }
+
+    #[tokio::test]
+    async fn give_up_after_max_attempts() {
+        let policy = RetryPolicy::new(2);
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["a", "b", "c"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(503) })
+            .await;
+
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.peer, Some("b"));
+        assert_eq!(outcome.result.unwrap().status, 503);
+
+        let mut peers = FailoverList::new(vec!["a"]);
+        let outcome = RetryPolicy::default()
+            .execute(&mut req, &mut peers, None, |_, _| async { error(ErrorType::ConnectRefused) })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(outcome.result.unwrap_err().etype(), &ErrorType::ConnectRefused);
+    }
+
+    #[test]
+    fn retry_on_io_timeout() {
+        let policy = RetryPolicy::default();
+        for etype in [ErrorType::ReadTimedout, ErrorType::WriteTimedout] {
+            let e = Error::build(etype.clone()).source(ErrorSource::UpStream).finish();
+            assert_eq!(policy.error_retry_reason(&e, true), Some(RetryReason::Error(etype)));
+            assert_eq!(policy.error_retry_reason(&e, false), None);
+        }
+
+        let policy = RetryPolicy { retry_on_timeout: false, ..Default::default() };
+        let e = Error::build(ErrorType::ReadTimedout).source(ErrorSource::UpStream).finish();
+        assert_eq!(policy.error_retry_reason(&e, true), None);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_only_retry_connect_failure() {
+        let policy = RetryPolicy::default();
+        let mut req = RequestHeader::build_with_method_path("POST", b"/a").unwrap();
+
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(503) })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, peer| async move {
+                match peer {
+                    "a" => error(ErrorType::ConnectRefused),
+                    _ => response(200),
+                }
+            })
+            .await;
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.result.unwrap().status, 200);
+
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { error(ErrorType::HttpCode(502)) })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn retry_per_try_timeout() {
+        let policy = RetryPolicy {
+            per_try_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["slow", "fast"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, peer| async move {
+                if peer == "slow" {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+                response(200)
+            })
+            .await;
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.peer, Some("fast"));
+    }
+
+    #[tokio::test]
+    async fn rewrite_between_attempts() {
+        let policy = RetryPolicy::default();
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let seen = Mutex::new(vec![]);
+        let rewrite = |req: &mut RequestHeader, attempt: usize, reason: &RetryReason| {
+            assert_eq!(reason, &RetryReason::Status(502));
+            req.insert_header("x-retry", attempt.to_string())
+        };
+        let outcome = policy
+            .execute(&mut req, &mut peers, Some(&rewrite), |req, _| {
+                seen.lock().unwrap().push(req.headers.get("x-retry").cloned());
+                async { response(502) }
+            })
+            .await;
+
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(*seen.lock().unwrap(), vec![None, Some("1".try_into().unwrap())]);
+    }
+
+    #[tokio::test]
+    async fn budget_limits_retries() {
+        let budget = Arc::new(RetryBudget::new(0.0, 1));
+        let policy = RetryPolicy {
+            budget: Some(budget.clone()),
+            ..Default::default()
+        };
+        let held = budget.try_acquire_retry().unwrap();
+        assert!(budget.try_acquire_retry().is_none());
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(502) })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(outcome.result.unwrap().status, 502);
+
+        drop(held);
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(502) })
+            .await;
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(budget.active_retries(), 0);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_limits_retries() {
+        use crate::upstream::circuit_breaker::CircuitBreakerConfig;
+
+        let config = CircuitBreakerConfig { max_retries: 1, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("c", config));
+        let policy = RetryPolicy {
+            circuit_breaker: Some(breaker.clone()),
+            ..Default::default()
+        };
+        let held = breaker.try_acquire(Resource::Retry).unwrap();
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["a", "b", "c"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(503) })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+
+        drop(held);
+        let mut peers = FailoverList::new(vec!["a", "b", "c"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(503) })
+            .await;
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(breaker.in_use(Resource::Retry), 0);
+        assert_eq!(breaker.in_use(Resource::Request), 0);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_limits_requests() {
+        use crate::upstream::circuit_breaker::CircuitBreakerConfig;
+
+        let config = CircuitBreakerConfig { max_requests: 1, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("c", config));
+        let policy = RetryPolicy {
+            circuit_breaker: Some(breaker.clone()),
+            ..Default::default()
+        };
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::new(vec!["a"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| {
+                assert_eq!(breaker.in_use(Resource::Request), 1);
+                async { response(200) }
+            })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+
+        let held = breaker.try_acquire(Resource::Request).unwrap();
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { response(200) })
+            .await;
+        assert_eq!(outcome.attempts, 0);
+        assert_eq!(outcome.result.unwrap_err().etype(), &ErrorType::CircuitBreakerOpen);
+        drop(held);
+        assert_eq!(breaker.in_use(Resource::Request), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_by_error_retry_type() {
+        let policy = RetryPolicy::default();
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, _| async { error(ErrorType::ReadError) })
+            .await;
+        assert_eq!(outcome.attempts, 1);
+
+        let mut peers = FailoverList::new(vec!["a", "b"]);
+        let outcome = policy
+            .execute(&mut req, &mut peers, None, |_, peer| async move {
+                match peer {
+                    "a" => {
+                        let mut e = Error::generate_error_with_retry(
+                            ErrorType::ConnectionClosed, "stale pooled connection", RetryType::ReusedOnly, None);
+                        e.decide_reuse(true);
+                        Err(e)
+                    }
+                    _ => response(200),
+                }
+            })
+            .await;
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.result.unwrap().status, 200);
+    }
+
+    #[tokio::test]
+    async fn no_upstream() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/a").unwrap();
+        let mut peers = FailoverList::<&str>::new(vec![]);
+        let outcome = RetryPolicy::default()
+            .execute(&mut req, &mut peers, None, |_, _| async { response(200) })
+            .await;
+        assert_eq!(outcome.attempts, 0);
+        assert_eq!(outcome.result.unwrap_err().etype(), &ErrorType::NoUpstream);
+    }
+}
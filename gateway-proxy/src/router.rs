@@ -0,0 +1,366 @@
+use std::{sync::Arc, time::Duration};
+
+use gateway_error::Result;
+use gateway_protocols::connections::request::RequestHeader;
+use http::Method;
+use regex::Regex;
+
+use crate::{common::{compile, request_authority}, header_rules::HeaderRules, path::PathRewrite, retry::RetryPolicy};
+
+/// How the `Host` (or `:authority`) of a request is matched, ports are ignored.
+#[derive(Debug, Clone)]
+pub enum HostMatch {
+    Any,
+    /// case-insensitive
+    Exact(String),
+    /// `*.example.com` matches any subdomain but not `example.com` itself
+    Wildcard(String),
+    Regex(Regex),
+}
+
+impl HostMatch {
+    pub fn exact(host: &str) -> Self {
+        HostMatch::Exact(host.to_ascii_lowercase())
+    }
+
+    /// `pattern` starts with `*.`, or is matched exactly otherwise.
+    pub fn wildcard(pattern: &str) -> Self {
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostMatch::Wildcard(suffix.to_ascii_lowercase()),
+            _ => Self::exact(pattern),
+        }
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(HostMatch::Regex(compile(pattern)?))
+    }
+
+    fn matches(&self, host: Option<&str>) -> bool {
+        match (self, host) {
+            (HostMatch::Any, _) => true,
+            (_, None) => false,
+            (HostMatch::Exact(exact), Some(host)) => exact == host,
+            (HostMatch::Wildcard(suffix), Some(host)) => {
+                host.len() > suffix.len() && host.ends_with(suffix.as_str())
+            }
+            (HostMatch::Regex(re), Some(host)) => re.is_match(host),
+        }
+    }
+
+    /// more specific host matches win among routes of the same priority.
+    fn specificity(&self) -> usize {
+        match self {
+            HostMatch::Exact(_) => 3,
+            HostMatch::Wildcard(_) => 2,
+            HostMatch::Regex(_) => 1,
+            HostMatch::Any => 0,
+        }
+    }
+}
+
+/// How the path of a request is matched, the query is not part of it.
+#[derive(Debug, Clone)]
+pub enum PathMatch {
+    Any,
+    Exact(String),
+    /// plain string prefix, `/api` also matches `/apix`, use `/api/` to avoid it
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathMatch {
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(PathMatch::Regex(compile(pattern)?))
+    }
+
+    /// exact paths first, then longer prefixes.
+    fn specificity(&self) -> (usize, usize) {
+        match self {
+            PathMatch::Exact(_) => (3, 0),
+            PathMatch::Prefix(prefix) => (2, prefix.len()),
+            PathMatch::Regex(_) => (1, 0),
+            PathMatch::Any => (0, 0),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Any => true,
+            PathMatch::Exact(exact) => exact == path,
+            PathMatch::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathMatch::Regex(re) => re.is_match(path),
+        }
+    }
+}
+
+/// How a header or query value is matched.
+#[derive(Debug, Clone)]
+pub enum ValueMatch {
+    Present,
+    Absent,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl ValueMatch {
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(ValueMatch::Regex(compile(pattern)?))
+    }
+
+    /// with several values, any of them may match.
+    fn matches<'a>(&self, mut values: impl Iterator<Item = &'a str>) -> bool {
+        match self {
+            ValueMatch::Present => values.next().is_some(),
+            ValueMatch::Absent => values.next().is_none(),
+            ValueMatch::Exact(exact) => values.any(|v| v == exact),
+            ValueMatch::Regex(re) => values.any(|v| re.is_match(v)),
+        }
+    }
+}
+
+/// Per-route behavior, shared by every request matching the route.
+#[derive(Debug, Clone, Default)]
+pub struct RouteSettings {
+    /// total time allowed for the upstream exchange
+    pub timeout: Option<Duration>,
+    pub retry: Option<RetryPolicy>,
+    pub header_rules: HeaderRules,
+    pub rewrites: Vec<PathRewrite>,
+}
+
+/// A set of conditions resolving to an upstream cluster.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    /// the upstream cluster requests are sent to
+    pub cluster: String,
+    /// higher first, see [Router] for ties
+    pub priority: i32,
+    host: HostMatch,
+    path: PathMatch,
+    methods: Vec<Method>,
+    headers: Vec<(String, ValueMatch)>,
+    query: Vec<(String, ValueMatch)>,
+    pub settings: Arc<RouteSettings>,
+}
+
+impl Route {
+    /// a route matching every request.
+    pub fn new(name: impl Into<String>, cluster: impl Into<String>) -> Self {
+        Route {
+            name: name.into(),
+            cluster: cluster.into(),
+            priority: 0,
+            host: HostMatch::Any,
+            path: PathMatch::Any,
+            methods: vec![],
+            headers: vec![],
+            query: vec![],
+            settings: Arc::new(RouteSettings::default()),
+        }
+    }
+
+    pub fn host(mut self, host: HostMatch) -> Self {
+        self.host = host;
+        self
+    }
+
+    pub fn path(mut self, path: PathMatch) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// allow `method`, every method is allowed until the first call.
+    pub fn method(mut self, method: Method) -> Self {
+        self.methods.push(method);
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: ValueMatch) -> Self {
+        self.headers.push((name.into(), value));
+        self
+    }
+
+    pub fn query(mut self, name: impl Into<String>, value: ValueMatch) -> Self {
+        self.query.push((name.into(), value));
+        self
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn settings(mut self, settings: RouteSettings) -> Self {
+        self.settings = Arc::new(settings);
+        self
+    }
+
+    fn order_key(&self) -> (i32, usize, (usize, usize)) {
+        (self.priority, self.host.specificity(), self.path.specificity())
+    }
+
+    pub fn matches(&self, req: &RequestHeader) -> bool {
+        let host = request_host(req);
+        self.host.matches(host.as_deref())
+            && self.path.matches(req.uri.path())
+            && (self.methods.is_empty() || self.methods.contains(&req.method))
+            && self.headers.iter().all(|(name, value)| {
+                value.matches(req.headers.get_all(name.as_str()).iter().filter_map(|v| v.to_str().ok()))
+            })
+            && self.query.iter().all(|(name, value)| {
+                value.matches(query_values(req.uri.query().unwrap_or(""), name))
+            })
+    }
+}
+
+/// the lowercase host of [request_authority], without port.
+pub(crate) fn request_host(req: &RequestHeader) -> Option<String> {
+    let host = request_authority(req)?;
+    let host = match host.rfind(':') {
+        // keep the colons of a bracketed IPv6 address
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// the raw values of parameter `name`, `a&a=` yields two empty values.
+fn query_values<'a>(query: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .filter_map(move |p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (k == name).then_some(v)
+        })
+}
+
+/// Resolve a request to the first matching [Route].
+///
+/// routes are tried by priority, then by how specific their host and path matches are,
+/// then in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, route: Route) {
+        let pos = self.routes.partition_point(|r| r.order_key() >= route.order_key());
+        self.routes.insert(pos, route);
+    }
+
+    pub fn route(&self, req: &RequestHeader) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matches(req))
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, host: &str, path: &[u8]) -> RequestHeader {
+        let mut req = RequestHeader::build_with_method_path(method, path).unwrap();
+        req.insert_header("Host", host).unwrap();
+        req
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.add(Route::new("fallback", "default"));
+        router.add(Route::new("any-api", "api").path(PathMatch::Prefix("/api/".into())));
+        router.add(
+            Route::new("wild", "tenants")
+                .host(HostMatch::wildcard("*.example.com"))
+                .path(PathMatch::Prefix("/api/".into())),
+        );
+        router.add(
+            Route::new("exact", "www")
+                .host(HostMatch::exact("WWW.example.com"))
+                .path(PathMatch::Prefix("/api/".into())),
+        );
+        router.add(
+            Route::new("canary", "api-canary")
+                .priority(10)
+                .path(PathMatch::regex(r"^/api/v\d+/").unwrap())
+                .method(Method::GET)
+                .header("x-canary", ValueMatch::Exact("1".into()))
+                .query("debug", ValueMatch::Absent),
+        );
+        router
+    }
+
+    fn route_name<'a>(router: &'a Router, req: &RequestHeader) -> Option<&'a str> {
+        router.route(req).map(|r| r.name.as_str())
+    }
+
+    #[test]
+    fn test_host_match() {
+        let router = router();
+        assert_eq!(route_name(&router, &request("GET", "www.example.com:8080", b"/api/a")), Some("exact"));
+        assert_eq!(route_name(&router, &request("GET", "a.example.com", b"/api/a")), Some("wild"));
+        assert_eq!(route_name(&router, &request("GET", "example.com", b"/api/a")), Some("any-api"));
+        assert_eq!(route_name(&router, &request("GET", "example.com", b"/other")), Some("fallback"));
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        req.set_uri("http://B.Example.com/api/x".parse().unwrap());
+        assert_eq!(route_name(&router, &req), Some("wild"));
+        // the absolute-form target wins over a conflicting Host
+        req.insert_header("Host", "www.example.com").unwrap();
+        assert_eq!(request_host(&req).unwrap(), "b.example.com");
+        assert_eq!(route_name(&router, &req), Some("wild"));
+
+        let re = HostMatch::regex(r"^api-\d+\.internal$").unwrap();
+        assert!(re.matches(Some("api-12.internal")));
+        assert!(!re.matches(None));
+        assert_eq!(request_host(&request("GET", "[::1]:80", b"/")).unwrap(), "[::1]");
+    }
+
+    #[test]
+    fn test_priority_and_conditions() {
+        let router = router();
+        let mut req = request("GET", "www.example.com", b"/api/v2/users?x=1");
+        assert_eq!(route_name(&router, &req), Some("exact"));
+
+        req.insert_header("X-Canary", "1").unwrap();
+        assert_eq!(route_name(&router, &req), Some("canary"));
+        assert_eq!(router.route(&req).unwrap().cluster, "api-canary");
+
+        let mut post = request("POST", "www.example.com", b"/api/v2/users");
+        post.insert_header("X-Canary", "1").unwrap();
+        assert_eq!(route_name(&router, &post), Some("exact"));
+
+        let mut debug = request("GET", "www.example.com", b"/api/v2/users?debug");
+        debug.insert_header("X-Canary", "1").unwrap();
+        assert_eq!(route_name(&router, &debug), Some("exact"));
+    }
+
+    #[test]
+    fn test_query_and_settings() {
+        let mut router = Router::new();
+        router.add(
+            Route::new("beta", "beta")
+                .path(PathMatch::Exact("/search".into()))
+                .query("v", ValueMatch::regex("^beta").unwrap())
+                .settings(RouteSettings {
+                    timeout: Some(Duration::from_secs(3)),
+                    retry: Some(RetryPolicy::new(2)),
+                    ..Default::default()
+                }),
+        );
+        let route = router.route(&request("GET", "h", b"/search?q=a&v=stable&v=beta2")).unwrap();
+        assert_eq!(route.settings.timeout, Some(Duration::from_secs(3)));
+        assert_eq!(route.settings.retry.as_ref().unwrap().max_attempts, 2);
+        assert!(router.route(&request("GET", "h", b"/search?v=stable")).is_none());
+        assert!(router.route(&request("GET", "h", b"/search/x?v=beta")).is_none());
+    }
+}
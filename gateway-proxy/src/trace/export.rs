@@ -0,0 +1,306 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use gateway_protocols::{
+    connections::request::RequestHeader,
+    http::v1::client::HttpSession,
+    l4::connector::{Connector, ConnectorOptions},
+};
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HOST},
+    Uri,
+};
+
+use super::{AttributeValue, Span, SpanKind, SpanStatus};
+use crate::common::{json_string, INVALID_CONFIG};
+
+pub type ExportFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Where the [Tracer](super::Tracer) sends finished spans, one batch at a time.
+pub trait SpanExporter: std::fmt::Debug + Send + Sync {
+    fn export<'a>(&'a self, spans: Vec<Span>) -> ExportFuture<'a>;
+}
+
+/// Keep every exported span, for tests.
+#[derive(Debug, Default)]
+pub struct InMemoryExporter {
+    spans: Mutex<Vec<Span>>,
+}
+
+impl InMemoryExporter {
+    /// the spans exported so far, in order.
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+impl SpanExporter for InMemoryExporter {
+    fn export<'a>(&'a self, spans: Vec<Span>) -> ExportFuture<'a> {
+        self.spans.lock().unwrap().extend(spans);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// POST spans as OTLP JSON to a collector, e.g. `http://localhost:4318/v1/traces`.
+#[derive(Debug)]
+pub struct OtlpHttpExporter {
+    authority: String,
+    path: String,
+    service_name: String,
+    connector: Arc<Connector>,
+    /// limit of writing the batch and of reading the answer each
+    pub timeout: Duration,
+}
+
+impl OtlpHttpExporter {
+    /// only plain `http` endpoints are supported.
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self> {
+        let invalid = |context: String| Error::build(INVALID_CONFIG).context(context).finish();
+        let uri: Uri = endpoint
+            .parse()
+            .map_err(|_| invalid(format!("invalid OTLP endpoint {endpoint}")))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(invalid(format!("OTLP endpoint {endpoint} is not http")));
+        }
+        let authority = uri
+            .authority()
+            .ok_or_else(|| invalid(format!("OTLP endpoint {endpoint} has no host")))?;
+        let authority = match authority.port() {
+            Some(_) => authority.to_string(),
+            None => format!("{authority}:80"),
+        };
+        let path = match uri.path_and_query() {
+            Some(p) if p.as_str() != "/" => p.to_string(),
+            _ => "/v1/traces".to_string(),
+        };
+        Ok(OtlpHttpExporter {
+            authority,
+            path,
+            service_name: service_name.to_string(),
+            connector: Arc::new(Connector::new(ConnectorOptions::default())),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    async fn send(&self, body: String) -> Result<()> {
+        let stream = self.connector.connect(&self.authority).await?;
+        let mut session = HttpSession::new(Box::new(stream));
+        session.read_timeout = Some(self.timeout);
+        session.write_timeout = Some(self.timeout);
+
+        let mut req = RequestHeader::build_with_method_path("POST", self.path.as_bytes())?;
+        req.insert_header(HOST, self.authority.as_str())?;
+        req.insert_header(CONTENT_TYPE, "application/json")?;
+        req.insert_header(CONTENT_LENGTH, body.len())?;
+        session.write_request_header(Box::new(req)).await?;
+        session.write_body(body.as_bytes()).await?;
+        session.finish_body().await?;
+        session.read_response().await?;
+        let status = session.response_header().map_or(0, |r| r.status.as_u16());
+        if !(200..300).contains(&status) {
+            return Error::build(ErrorType::HttpCode(status))
+                .source(ErrorSource::UpStream)
+                .context(format!("OTLP collector {} rejected spans", self.authority))
+                .err();
+        }
+        while session.read_body().await?.is_some() {}
+        Ok(())
+    }
+}
+
+impl SpanExporter for OtlpHttpExporter {
+    fn export<'a>(&'a self, spans: Vec<Span>) -> ExportFuture<'a> {
+        Box::pin(self.send(encode_otlp_json(&self.service_name, &spans)))
+    }
+}
+
+/// the OTLP/HTTP JSON encoding of `ExportTraceServiceRequest`.
+pub fn encode_otlp_json(service_name: &str, spans: &[Span]) -> String {
+    let mut out = String::from(r#"{"resourceSpans":[{"resource":{"attributes":["#);
+    encode_attribute(&mut out, "service.name", &AttributeValue::from(service_name));
+    out.push_str(r#"]},"scopeSpans":[{"scope":{"name":"#);
+    json_string(&mut out, env!("CARGO_PKG_NAME"));
+    out.push_str(r#","version":"#);
+    json_string(&mut out, env!("CARGO_PKG_VERSION"));
+    out.push_str(r#"},"spans":["#);
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        encode_span(&mut out, span);
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+fn encode_span(out: &mut String, span: &Span) {
+    let cx = &span.context;
+    let _ = write!(out, r#"{{"traceId":"{}","spanId":"{}""#, cx.trace_id, cx.span_id);
+    if let Some(parent) = span.parent_span_id {
+        let _ = write!(out, r#","parentSpanId":"{parent}""#);
+    }
+    if !cx.trace_state.is_empty() {
+        out.push_str(r#","traceState":"#);
+        json_string(out, &cx.trace_state);
+    }
+    out.push_str(r#","name":"#);
+    json_string(out, &span.name);
+    let kind = match span.kind {
+        SpanKind::Server => 2,
+        SpanKind::Client => 3,
+    };
+    let end = span.end.unwrap_or(span.start);
+    let _ = write!(
+        out,
+        r#","kind":{kind},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+        unix_nanos(span.start),
+        unix_nanos(end)
+    );
+    for (i, (key, value)) in span.attributes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        encode_attribute(out, key, value);
+    }
+    out.push_str(r#"],"status":{"#);
+    match &span.status {
+        SpanStatus::Unset => {}
+        SpanStatus::Ok => out.push_str(r#""code":1"#),
+        SpanStatus::Error(message) => {
+            out.push_str(r#""code":2,"message":"#);
+            json_string(out, message);
+        }
+    }
+    out.push_str("}}");
+}
+
+fn encode_attribute(out: &mut String, key: &str, value: &AttributeValue) {
+    out.push_str(r#"{"key":"#);
+    json_string(out, key);
+    out.push_str(r#","value":{"#);
+    match value {
+        AttributeValue::String(s) => {
+            out.push_str(r#""stringValue":"#);
+            json_string(out, s);
+        }
+        // 64 bit integers are strings in the protobuf JSON mapping
+        AttributeValue::Int(i) => {
+            let _ = write!(out, r#""intValue":"{i}""#);
+        }
+        AttributeValue::Double(d) if d.is_finite() => {
+            let _ = write!(out, r#""doubleValue":{d}"#);
+        }
+        // and the special floats are named ones
+        AttributeValue::Double(d) => {
+            let name = match d {
+                d if d.is_nan() => "NaN",
+                d if *d > 0.0 => "Infinity",
+                _ => "-Infinity",
+            };
+            let _ = write!(out, r#""doubleValue":"{name}""#);
+        }
+        AttributeValue::Bool(b) => {
+            let _ = write!(out, r#""boolValue":{b}"#);
+        }
+    }
+    out.push_str("}}");
+}
+
+fn unix_nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::trace::{SpanContext, SpanId, TraceId};
+
+    fn span() -> Span {
+        let mut span = Span::new(
+            "GET \"api\"".into(),
+            SpanKind::Client,
+            SpanContext {
+                trace_id: TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+                span_id: SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+                sampled: true,
+                trace_state: "a=1".into(),
+            },
+            SpanId::from_hex("a3ce929d0e0e4736"),
+        );
+        span.start = UNIX_EPOCH + Duration::from_secs(1);
+        span.end = Some(UNIX_EPOCH + Duration::from_millis(1500));
+        span.set_attribute("gateway.attempt", 2);
+        span.set_attribute("gateway.timing.ttfb_ms", 1.5);
+        span.set_attribute("retried", true);
+        span.record_status(502);
+        span
+    }
+
+    #[test]
+    fn test_encode() {
+        let json = encode_otlp_json("edge", &[span()]);
+        assert!(json.starts_with(
+            r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"edge"}}]}"#
+        ));
+        assert!(json.ends_with(
+            r#""spans":[{"traceId":"4bf92f3577b34da6a3ce929d0e0e4736","spanId":"00f067aa0ba902b7","parentSpanId":"a3ce929d0e0e4736","traceState":"a=1","name":"GET \"api\"","kind":3,"startTimeUnixNano":"1000000000","endTimeUnixNano":"1500000000","attributes":[{"key":"gateway.attempt","value":{"intValue":"2"}},{"key":"gateway.timing.ttfb_ms","value":{"doubleValue":1.5}},{"key":"retried","value":{"boolValue":true}},{"key":"http.response.status_code","value":{"intValue":"502"}}],"status":{"code":2,"message":"HTTP 502"}}]}]}]}"#
+        ), "{json}");
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let exporter = OtlpHttpExporter::new("http://collector", "edge").unwrap();
+        assert_eq!(exporter.authority, "collector:80");
+        assert_eq!(exporter.path, "/v1/traces");
+        assert!(OtlpHttpExporter::new("https://collector:4318/v1/traces", "edge").is_err());
+        assert!(OtlpHttpExporter::new("not a uri", "edge").is_err());
+    }
+
+    async fn collector(status: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.ends_with(b"]}]}]}") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            let resp = format!("HTTP/1.1 {status}\r\nContent-Length: 2\r\n\r\n{{}}");
+            stream.write_all(resp.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (endpoint, server)
+    }
+
+    #[tokio::test]
+    async fn test_otlp_http_export() {
+        let (endpoint, server) = collector("200 OK").await;
+        let exporter = OtlpHttpExporter::new(&endpoint, "edge").unwrap();
+        exporter.export(vec![span()]).await.unwrap();
+        let request = server.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /v1/traces HTTP/1.1\r\n"), "{head}");
+        assert!(head.to_ascii_lowercase().contains("content-type: application/json"), "{head}");
+        assert_eq!(body, encode_otlp_json("edge", &[span()]));
+
+        let (endpoint, server) = collector("503 Service Unavailable").await;
+        let exporter = OtlpHttpExporter::new(&endpoint, "edge").unwrap();
+        let e = exporter.export(vec![span()]).await.unwrap_err();
+        assert_eq!(e.etype(), &ErrorType::HttpCode(503));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use gateway_error::{error_trait::OrErr, Error, ErrorType, Result};
+use gateway_protocols::connections::{digest::RequestTiming, request::RequestHeader};
+use log::warn;
+use rand::Rng;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{common::INVALID_CONFIG, router::request_host};
+
+pub mod export;
+pub mod propagation;
+
+use export::SpanExporter;
+use propagation::{Propagation, TracePropagation};
+
+/// 16 bytes, never all zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub [u8; 16]);
+
+/// 8 bytes, never all zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanId(pub [u8; 8]);
+
+impl TraceId {
+    pub fn random() -> Self {
+        let mut id = [0u8; 16];
+        while id == [0; 16] {
+            rand::thread_rng().fill(&mut id);
+        }
+        TraceId(id)
+    }
+
+    /// 32 lowercase hex digits, a 16 digit id is zero padded as B3 allows.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let mut id = [0u8; 16];
+        match hex.len() {
+            32 => decode_hex(hex, &mut id)?,
+            16 => decode_hex(hex, &mut id[8..])?,
+            _ => return None,
+        }
+        (id != [0; 16]).then_some(TraceId(id))
+    }
+}
+
+impl SpanId {
+    pub fn random() -> Self {
+        let mut id = [0u8; 8];
+        while id == [0; 8] {
+            rand::thread_rng().fill(&mut id);
+        }
+        SpanId(id)
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let mut id = [0u8; 8];
+        decode_hex(hex, &mut id)?;
+        (id != [0; 8]).then_some(SpanId(id))
+    }
+}
+
+/// exactly `out.len()` bytes of lowercase digits, as both W3C and B3 require.
+fn decode_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+    if hex.len() != out.len() * 2 {
+        return None;
+    }
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(())
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// What is propagated between services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    pub sampled: bool,
+    /// the W3C `tracestate`, passed on as is
+    pub trace_state: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// a downstream request
+    Server,
+    /// an upstream attempt
+    Client,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(v: &str) -> Self {
+        AttributeValue::String(v.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(v: String) -> Self {
+        AttributeValue::String(v)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(v: i64) -> Self {
+        AttributeValue::Int(v)
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(v: f64) -> Self {
+        AttributeValue::Double(v)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(v: bool) -> Self {
+        AttributeValue::Bool(v)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SpanStatus {
+    #[default]
+    Unset,
+    Ok,
+    Error(String),
+}
+
+/// One operation of a trace, handed to [Tracer::finish] once done.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub context: SpanContext,
+    pub parent_span_id: Option<SpanId>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start: SystemTime,
+    pub end: Option<SystemTime>,
+    pub attributes: Vec<(String, AttributeValue)>,
+    pub status: SpanStatus,
+}
+
+impl Span {
+    fn new(name: String, kind: SpanKind, context: SpanContext, parent_span_id: Option<SpanId>) -> Self {
+        Span {
+            context,
+            parent_span_id,
+            name,
+            kind,
+            start: SystemTime::now(),
+            end: None,
+            attributes: Vec::new(),
+            status: SpanStatus::Unset,
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: impl Into<String>, value: impl Into<AttributeValue>) {
+        self.attributes.push((key.into(), value.into()));
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&AttributeValue> {
+        self.attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// a 5xx marks the span failed.
+    pub fn record_status(&mut self, status: u16) {
+        self.set_attribute("http.response.status_code", status as i64);
+        if status >= 500 {
+            self.status = SpanStatus::Error(format!("HTTP {status}"));
+        }
+    }
+
+    pub fn record_error(&mut self, e: &Error) {
+        self.set_attribute("error.type", e.etype().as_str());
+        self.status = SpanStatus::Error(e.to_string());
+    }
+
+    /// every phase of the digest timing as `gateway.timing.<phase>_ms`.
+    pub fn record_timing(&mut self, timing: &RequestTiming) {
+        for (phase, d) in timing.phases() {
+            self.set_attribute(format!("gateway.timing.{phase}_ms"), d.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+/// How spans are sampled and handed to the exporter.
+#[derive(Debug, Clone)]
+pub struct TracerConfig {
+    /// share of new traces recorded; traces coming in keep the decision of the caller
+    pub sample_ratio: f64,
+    /// format injected into upstream requests
+    pub propagation: Propagation,
+    /// finished spans waiting for export, more are dropped
+    pub max_queue: usize,
+    pub max_batch: usize,
+    /// a partial batch is exported after this long
+    pub export_interval: Duration,
+}
+
+impl Default for TracerConfig {
+    fn default() -> Self {
+        TracerConfig {
+            sample_ratio: 1.0,
+            propagation: Propagation::W3c,
+            max_queue: 2048,
+            max_batch: 512,
+            export_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+enum Message {
+    Span(Box<Span>),
+    Flush(oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Start spans for downstream requests and upstream attempts, exporting the
+/// sampled ones in batches from a background task.
+pub struct Tracer {
+    config: TracerConfig,
+    tx: mpsc::Sender<Message>,
+    dropped: AtomicU64,
+    exporter_task: JoinHandle<()>,
+}
+
+impl Tracer {
+    /// must be called within a tokio runtime.
+    ///
+    /// fails when `sample_ratio` is not a number between 0 and 1, or `export_interval` is zero.
+    pub fn new(exporter: Arc<dyn SpanExporter>, config: TracerConfig) -> Result<Self> {
+        if !(0.0..=1.0).contains(&config.sample_ratio) {
+            return Error::build(INVALID_CONFIG)
+                .context(format!("sample ratio {} is not between 0 and 1", config.sample_ratio))
+                .err();
+        }
+        if config.export_interval.is_zero() {
+            return Error::build(INVALID_CONFIG).context("span export interval is zero").err();
+        }
+        let (tx, rx) = mpsc::channel(config.max_queue.max(1));
+        let exporter_task = tokio::spawn(export_loop(exporter, rx, config.max_batch.max(1), config.export_interval));
+        Ok(Tracer { config, tx, dropped: AtomicU64::new(0), exporter_task })
+    }
+
+    /// the server span of a downstream request, continuing the trace it carries.
+    pub fn start_request(&self, req: &RequestHeader) -> Span {
+        let (context, parent) = match req.trace_context() {
+            Some(remote) => (
+                SpanContext { span_id: SpanId::random(), ..remote.clone() },
+                Some(remote.span_id),
+            ),
+            None => (
+                SpanContext {
+                    trace_id: TraceId::random(),
+                    span_id: SpanId::random(),
+                    sampled: rand::thread_rng().gen_bool(self.config.sample_ratio),
+                    trace_state: String::new(),
+                },
+                None,
+            ),
+        };
+        let mut span = Span::new(req.method.to_string(), SpanKind::Server, context, parent);
+        span.set_attribute("http.request.method", req.method.as_str());
+        span.set_attribute("url.path", req.uri.path());
+        if let Some(host) = request_host(req) {
+            span.set_attribute("server.address", host);
+        }
+        span
+    }
+
+    /// the client span of upstream attempt number `attempt` (from 1), injected into `req`.
+    pub fn start_attempt(&self, parent: &Span, req: &mut RequestHeader, upstream: &str, attempt: usize) -> Result<Span> {
+        let context = SpanContext { span_id: SpanId::random(), ..parent.context.clone() };
+        let mut span = Span::new(
+            format!("{} {upstream}", req.method),
+            SpanKind::Client,
+            context,
+            Some(parent.context.span_id),
+        );
+        span.set_attribute("http.request.method", req.method.as_str());
+        span.set_attribute("server.address", upstream);
+        span.set_attribute("gateway.attempt", attempt as i64);
+        req.inject_trace_context(&span.context, self.config.propagation)?;
+        Ok(span)
+    }
+
+    /// end `span` and queue it for export when sampled.
+    pub fn finish(&self, mut span: Span) {
+        if !span.context.sampled {
+            return;
+        }
+        span.end.get_or_insert_with(SystemTime::now);
+        if self.tx.try_send(Message::Span(Box::new(span))).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// spans lost because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// export every queued span now.
+    pub async fn flush(&self) -> Result<()> {
+        self.send_and_wait(Message::Flush).await
+    }
+
+    /// export every queued span and stop the exporter, spans finished later are dropped.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.send_and_wait(Message::Shutdown).await
+    }
+
+    async fn send_and_wait(&self, message: fn(oneshot::Sender<()>) -> Message) -> Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.tx
+            .send(message(ack))
+            .await
+            .or_err(ErrorType::InternalError, "span exporter is gone")?;
+        done.await.or_err(ErrorType::InternalError, "span exporter is gone")
+    }
+}
+
+/// spans still queued are lost, call [Tracer::shutdown] first to export them.
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.exporter_task.abort();
+    }
+}
+
+async fn export_loop(
+    exporter: Arc<dyn SpanExporter>,
+    mut rx: mpsc::Receiver<Message>,
+    max_batch: usize,
+    interval: Duration,
+) {
+    let mut batch = Vec::with_capacity(max_batch);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        let (ack, stop) = tokio::select! {
+            message = rx.recv() => match message {
+                Some(Message::Span(span)) => {
+                    batch.push(*span);
+                    if batch.len() < max_batch {
+                        continue;
+                    }
+                    (None, false)
+                }
+                Some(Message::Flush(ack)) => (Some(ack), false),
+                Some(Message::Shutdown(ack)) => (Some(ack), true),
+                None => (None, true),
+            },
+            _ = ticker.tick() => (None, false),
+        };
+        if !batch.is_empty() {
+            if let Err(e) = exporter.export(std::mem::take(&mut batch)).await {
+                warn!("failed to export spans: {e}");
+            }
+        }
+        if let Some(ack) = ack {
+            let _ = ack.send(());
+        }
+        if stop {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gateway_protocols::connections::response::ResponseHeader;
+
+    use super::*;
+    use crate::retry::{FailoverList, RetryPolicy};
+    use export::InMemoryExporter;
+
+    #[test]
+    fn test_ids() {
+        let id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        assert_eq!(id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        let short = TraceId::from_hex("a3ce929d0e0e4736").unwrap();
+        assert_eq!(short.to_string(), "0000000000000000a3ce929d0e0e4736");
+        assert!(TraceId::from_hex("00000000000000000000000000000000").is_none());
+        assert!(TraceId::from_hex("4BF92F3577B34DA6A3CE929D0E0E4736").is_none());
+        assert!(SpanId::from_hex("00f067aa0ba902b7").is_some());
+        assert!(SpanId::from_hex("00f067aa0ba902").is_none());
+        assert_ne!(SpanId::random(), SpanId::random());
+    }
+
+    #[tokio::test]
+    async fn test_spans_per_attempt() {
+        let exporter = Arc::new(InMemoryExporter::default());
+        let tracer = Tracer::new(exporter.clone(), TracerConfig::default()).unwrap();
+
+        let mut req = RequestHeader::build_with_method_path("GET", b"/api?token=secret").unwrap();
+        req.insert_header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        req.insert_header("tracestate", "vendor=1").unwrap();
+        let mut server = tracer.start_request(&req);
+
+        let policy = RetryPolicy::new(3);
+        let mut upstreams = FailoverList::new(vec!["10.0.0.1:80", "10.0.0.2:80"]);
+        let mut attempt = 0;
+        let outcome = policy
+            .execute(&mut req, &mut upstreams, None, |mut req, peer| {
+                attempt += 1;
+                let span = tracer.start_attempt(&server, &mut req, peer, attempt);
+                let status = if attempt == 1 { 503 } else { 200 };
+                let tracer = &tracer;
+                async move {
+                    let mut span = span?;
+                    assert_eq!(
+                        req.headers.get("traceparent").unwrap().to_str().unwrap(),
+                        format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", span.context.span_id)
+                    );
+                    let timing = RequestTiming { ttfb: Some(Duration::from_millis(12)), ..Default::default() };
+                    span.record_timing(&timing);
+                    span.record_status(status);
+                    tracer.finish(span);
+                    Ok(Box::new(ResponseHeader::build_with_status_code(status).unwrap()))
+                }
+            })
+            .await;
+        server.record_status(outcome.result.unwrap().status.as_u16());
+        let server_span_id = server.context.span_id;
+        tracer.finish(server);
+        tracer.flush().await.unwrap();
+
+        let spans = exporter.spans();
+        assert_eq!(spans.len(), 3);
+        let (attempts, servers): (Vec<&Span>, Vec<&Span>) = spans.iter().partition(|s| s.kind == SpanKind::Client);
+        assert_eq!(servers[0].parent_span_id, SpanId::from_hex("00f067aa0ba902b7"));
+        assert_eq!(servers[0].context.trace_state, "vendor=1");
+        // the query is not part of the path
+        assert_eq!(servers[0].attribute("url.path"), Some(&AttributeValue::from("/api")));
+        assert_eq!(attempts.len(), 2);
+        for (i, span) in attempts.iter().enumerate() {
+            assert_eq!(span.context.trace_id, servers[0].context.trace_id);
+            assert_eq!(span.parent_span_id, Some(server_span_id));
+            assert_eq!(span.attribute("gateway.attempt"), Some(&AttributeValue::Int(i as i64 + 1)));
+            assert_eq!(span.attribute("gateway.timing.ttfb_ms"), Some(&AttributeValue::Double(12.0)));
+        }
+        assert_eq!(attempts[0].status, SpanStatus::Error("HTTP 503".into()));
+        assert_eq!(attempts[1].status, SpanStatus::Unset);
+        assert_eq!(attempts[1].attribute("server.address"), Some(&AttributeValue::from("10.0.0.2:80")));
+    }
+
+    #[tokio::test]
+    async fn test_sampling() {
+        let exporter = Arc::new(InMemoryExporter::default());
+        let config = TracerConfig { sample_ratio: 0.0, ..Default::default() };
+        let tracer = Tracer::new(exporter.clone(), config).unwrap();
+
+        let req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        let span = tracer.start_request(&req);
+        assert!(!span.context.sampled);
+        tracer.finish(span);
+
+        // the caller decided to sample
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        req.insert_header("b3", "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1").unwrap();
+        let span = tracer.start_request(&req);
+        assert!(span.context.sampled);
+        tracer.finish(span);
+        tracer.flush().await.unwrap();
+        assert_eq!(exporter.spans().len(), 1);
+
+        for ratio in [f64::NAN, -0.1, 1.5] {
+            let config = TracerConfig { sample_ratio: ratio, ..Default::default() };
+            let e = Tracer::new(exporter.clone(), config).err().unwrap();
+            assert_eq!(e.etype(), &INVALID_CONFIG);
+        }
+        let config = TracerConfig { export_interval: Duration::ZERO, ..Default::default() };
+        let e = Tracer::new(exporter.clone(), config).err().unwrap();
+        assert_eq!(e.etype(), &INVALID_CONFIG);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_exports_queued_spans() {
+        let exporter = Arc::new(InMemoryExporter::default());
+        let config = TracerConfig { export_interval: Duration::from_secs(3600), ..Default::default() };
+        let tracer = Tracer::new(exporter.clone(), config).unwrap();
+        let req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        for _ in 0..3 {
+            tracer.finish(tracer.start_request(&req));
+        }
+        tracer.shutdown().await.unwrap();
+        assert_eq!(exporter.spans().len(), 3);
+
+        // the exporter is gone
+        tracer.finish(tracer.start_request(&req));
+        assert!(tracer.flush().await.is_err());
+        assert_eq!(exporter.spans().len(), 3);
+    }
+}
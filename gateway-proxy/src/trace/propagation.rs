@@ -0,0 +1,250 @@
+use gateway_error::Result;
+use gateway_protocols::connections::request::RequestHeader;
+
+use super::{SpanContext, SpanId, TraceId};
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+pub const B3: &str = "b3";
+pub const X_B3_TRACE_ID: &str = "x-b3-traceid";
+pub const X_B3_SPAN_ID: &str = "x-b3-spanid";
+pub const X_B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+pub const X_B3_SAMPLED: &str = "x-b3-sampled";
+pub const X_B3_FLAGS: &str = "x-b3-flags";
+
+const ALL_HEADERS: [&str; 8] = [
+    TRACEPARENT,
+    TRACESTATE,
+    B3,
+    X_B3_TRACE_ID,
+    X_B3_SPAN_ID,
+    X_B3_PARENT_SPAN_ID,
+    X_B3_SAMPLED,
+    X_B3_FLAGS,
+];
+
+/// The header format trace context is sent upstream in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// W3C `traceparent` and `tracestate`
+    W3c,
+    /// a single `b3` header
+    B3Single,
+    /// the `X-B3-*` headers
+    B3Multi,
+}
+
+/// Read and write the trace context of a [RequestHeader].
+pub trait TracePropagation {
+    /// the context the request carries, trying W3C, then `b3`, then `X-B3-*`.
+    ///
+    /// malformed headers are ignored, so the request starts a new trace.
+    fn trace_context(&self) -> Option<SpanContext>;
+
+    /// replace whatever trace headers the request has with `cx` in `format`.
+    fn inject_trace_context(&mut self, cx: &SpanContext, format: Propagation) -> Result<()>;
+}
+
+impl TracePropagation for RequestHeader {
+    fn trace_context(&self) -> Option<SpanContext> {
+        let header = |name| self.headers.get(name).and_then(|v| v.to_str().ok());
+        if let Some(traceparent) = header(TRACEPARENT) {
+            if let Some(mut cx) = parse_traceparent(traceparent) {
+                let states: Vec<&str> = self
+                    .headers
+                    .get_all(TRACESTATE)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .collect();
+                cx.trace_state = states.join(",");
+                return Some(cx);
+            }
+        }
+        if let Some(b3) = header(B3).and_then(parse_b3) {
+            return Some(b3);
+        }
+        let trace_id = TraceId::from_hex(header(X_B3_TRACE_ID)?)?;
+        let span_id = SpanId::from_hex(header(X_B3_SPAN_ID)?)?;
+        let sampled = header(X_B3_FLAGS) == Some("1") || header(X_B3_SAMPLED).is_none_or(sampling);
+        Some(SpanContext { trace_id, span_id, sampled, trace_state: String::new() })
+    }
+
+    fn inject_trace_context(&mut self, cx: &SpanContext, format: Propagation) -> Result<()> {
+        for name in ALL_HEADERS {
+            self.remove_header(name, "")?;
+        }
+        let sampled = if cx.sampled { "1" } else { "0" };
+        match format {
+            Propagation::W3c => {
+                self.insert_header(TRACEPARENT, format_traceparent(cx))?;
+                if !cx.trace_state.is_empty() {
+                    self.insert_header(TRACESTATE, cx.trace_state.as_str())?;
+                }
+            }
+            Propagation::B3Single => {
+                self.insert_header(B3, format!("{}-{}-{sampled}", cx.trace_id, cx.span_id))?;
+            }
+            Propagation::B3Multi => {
+                self.insert_header(X_B3_TRACE_ID, cx.trace_id.to_string())?;
+                self.insert_header(X_B3_SPAN_ID, cx.span_id.to_string())?;
+                self.insert_header(X_B3_SAMPLED, sampled)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `version-traceid-parentid-flags`, where versions after `00` may append fields.
+pub fn parse_traceparent(value: &str) -> Option<SpanContext> {
+    let fields: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace_id, span_id, flags, ..] = fields[..] else {
+        return None;
+    };
+    let mut version_byte = [0u8];
+    super::decode_hex(version, &mut version_byte)?;
+    if version_byte[0] == 0xff || (version_byte[0] == 0 && fields.len() != 4) {
+        return None;
+    }
+    let mut flags_byte = [0u8];
+    super::decode_hex(flags, &mut flags_byte)?;
+    Some(SpanContext {
+        trace_id: TraceId::from_hex(trace_id).filter(|_| trace_id.len() == 32)?,
+        span_id: SpanId::from_hex(span_id)?,
+        sampled: flags_byte[0] & 0x01 != 0,
+        trace_state: String::new(),
+    })
+}
+
+pub fn format_traceparent(cx: &SpanContext) -> String {
+    let flags = if cx.sampled { "01" } else { "00" };
+    format!("00-{}-{}-{flags}", cx.trace_id, cx.span_id)
+}
+
+/// `traceid-spanid[-sampling[-parentspanid]]`; a bare sampling decision carries no context.
+pub fn parse_b3(value: &str) -> Option<SpanContext> {
+    let mut fields = value.trim().split('-');
+    let trace_id = TraceId::from_hex(fields.next()?)?;
+    let span_id = SpanId::from_hex(fields.next()?)?;
+    let sampled = fields.next().is_none_or(sampling);
+    if let Some(parent) = fields.next() {
+        SpanId::from_hex(parent)?;
+    }
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(SpanContext { trace_id, span_id, sampled, trace_state: String::new() })
+}
+
+/// a deferred or unknown decision counts as sampled, leaving it to our ratio would break the trace.
+fn sampling(value: &str) -> bool {
+    !matches!(value, "0" | "false")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN: &str = "00f067aa0ba902b7";
+
+    fn req(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let cx = parse_traceparent(&format!("00-{TRACE}-{SPAN}-01")).unwrap();
+        assert_eq!(cx.trace_id.to_string(), TRACE);
+        assert_eq!(cx.span_id.to_string(), SPAN);
+        assert!(cx.sampled);
+        assert_eq!(format_traceparent(&cx), format!("00-{TRACE}-{SPAN}-01"));
+
+        assert!(!parse_traceparent(&format!("00-{TRACE}-{SPAN}-00")).unwrap().sampled);
+        // later versions may add fields
+        assert!(parse_traceparent(&format!("01-{TRACE}-{SPAN}-01-extra")).is_some());
+        for bad in [
+            format!("ff-{TRACE}-{SPAN}-01"),
+            format!("00-{TRACE}-{SPAN}-01-extra"),
+            format!("00-00000000000000000000000000000000-{SPAN}-01"),
+            format!("00-{TRACE}-0000000000000000-01"),
+            format!("00-{}-{SPAN}-01", &TRACE[16..]),
+            format!("00-{TRACE}-{SPAN}"),
+            format!("0-{TRACE}-{SPAN}-01"),
+        ] {
+            assert!(parse_traceparent(&bad).is_none(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_b3() {
+        let cx = parse_b3(&format!("{TRACE}-{SPAN}-0-{SPAN}")).unwrap();
+        assert_eq!(cx.trace_id.to_string(), TRACE);
+        assert!(!cx.sampled);
+        assert!(parse_b3(&format!("{TRACE}-{SPAN}")).unwrap().sampled);
+        assert!(parse_b3(&format!("{TRACE}-{SPAN}-d")).unwrap().sampled);
+        let short = parse_b3(&format!("{}-{SPAN}-1", &TRACE[16..])).unwrap();
+        assert_eq!(short.trace_id.to_string(), format!("0000000000000000{}", &TRACE[16..]));
+        assert!(parse_b3("0").is_none());
+        assert!(parse_b3(&format!("{TRACE}-{SPAN}-1-zz")).is_none());
+    }
+
+    #[test]
+    fn test_extract() {
+        let cx = req(&[
+            ("traceparent", &format!("00-{TRACE}-{SPAN}-01")),
+            ("tracestate", "a=1"),
+            ("tracestate", "b=2"),
+            ("b3", "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1"),
+        ])
+        .trace_context()
+        .unwrap();
+        assert_eq!(cx.trace_id.to_string(), TRACE);
+        assert_eq!(cx.trace_state, "a=1,b=2");
+
+        // a broken traceparent falls back to b3
+        let cx = req(&[("traceparent", "garbage"), ("b3", &format!("{TRACE}-{SPAN}"))])
+            .trace_context()
+            .unwrap();
+        assert_eq!(cx.span_id.to_string(), SPAN);
+
+        let cx = req(&[("X-B3-TraceId", TRACE), ("X-B3-SpanId", SPAN), ("X-B3-Sampled", "0")])
+            .trace_context()
+            .unwrap();
+        assert!(!cx.sampled);
+        let cx = req(&[("X-B3-TraceId", TRACE), ("X-B3-SpanId", SPAN), ("X-B3-Sampled", "0"), ("X-B3-Flags", "1")])
+            .trace_context()
+            .unwrap();
+        assert!(cx.sampled);
+
+        assert!(req(&[]).trace_context().is_none());
+        assert!(req(&[("X-B3-TraceId", TRACE)]).trace_context().is_none());
+    }
+
+    #[test]
+    fn test_inject_round_trip() {
+        let cx = SpanContext {
+            trace_id: TraceId::from_hex(TRACE).unwrap(),
+            span_id: SpanId::random(),
+            sampled: true,
+            trace_state: "vendor=x".into(),
+        };
+        for format in [Propagation::W3c, Propagation::B3Single, Propagation::B3Multi] {
+            let mut r = req(&[("b3", &format!("{TRACE}-{SPAN}")), ("X-B3-SpanId", SPAN)]);
+            r.inject_trace_context(&cx, format).unwrap();
+            let got = r.trace_context().unwrap();
+            assert_eq!(got.trace_id, cx.trace_id, "{format:?}");
+            assert_eq!(got.span_id, cx.span_id, "{format:?}");
+            assert!(got.sampled);
+            // the old headers are gone
+            let b3_left = r.headers.contains_key(B3) || r.headers.contains_key(X_B3_SPAN_ID);
+            assert_eq!(b3_left, format != Propagation::W3c, "{format:?}");
+        }
+        let mut r = req(&[]);
+        r.inject_trace_context(&cx, Propagation::W3c).unwrap();
+        assert_eq!(r.headers.get(TRACESTATE).unwrap(), "vendor=x");
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, RwLock};
+
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+
+/// Limits of a cluster, a request over any of them fails with
+/// [ErrorType::CircuitBreakerOpen] instead of piling up on a slow upstream.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// connections open to all backends, see [super::Backend::acquire]
+    pub max_connections: usize,
+    /// requests waiting for a connection, see [super::Backend::connect]
+    pub max_pending_requests: usize,
+    /// requests in flight, see [crate::retry::RetryPolicy::execute]
+    pub max_requests: usize,
+    /// retries in flight
+    pub max_retries: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            max_connections: 1024,
+            max_pending_requests: 1024,
+            max_requests: 1024,
+            max_retries: 3,
+        }
+    }
+}
+
+/// What a [CircuitBreakerGuard] holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Connection,
+    PendingRequest,
+    Request,
+    Retry,
+}
+
+impl Resource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Resource::Connection => "max_connections",
+            Resource::PendingRequest => "max_pending_requests",
+            Resource::Request => "max_requests",
+            Resource::Retry => "max_retries",
+        }
+    }
+}
+
+/// The counters of one cluster checked against its [CircuitBreakerConfig].
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    cluster: String,
+    config: RwLock<CircuitBreakerConfig>,
+    in_use: [AtomicUsize; 4],
+}
+
+/// Keep a [Resource] of a [CircuitBreaker] taken until dropped.
+pub struct CircuitBreakerGuard {
+    breaker: Arc<CircuitBreaker>,
+    resource: Resource,
+}
+
+impl CircuitBreaker {
+    pub fn new(cluster: impl Into<String>, config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            cluster: cluster.into(),
+            config: RwLock::new(config),
+            in_use: Default::default(),
+        }
+    }
+
+    /// change the limits, the resources already taken stay counted.
+    pub fn set_config(&self, config: CircuitBreakerConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    fn limit(&self, resource: Resource) -> usize {
+        let config = self.config.read().unwrap();
+        match resource {
+            Resource::Connection => config.max_connections,
+            Resource::PendingRequest => config.max_pending_requests,
+            Resource::Request => config.max_requests,
+            Resource::Retry => config.max_retries,
+        }
+    }
+
+    fn counter(&self, resource: Resource) -> &AtomicUsize {
+        &self.in_use[resource as usize]
+    }
+
+    pub fn in_use(&self, resource: Resource) -> usize {
+        self.counter(resource).load(Ordering::Relaxed)
+    }
+
+    /// whether `resource` is used up right now.
+    pub fn is_open(&self, resource: Resource) -> bool {
+        self.in_use(resource) >= self.limit(resource)
+    }
+
+    /// take one `resource`, fail with [ErrorType::CircuitBreakerOpen] over the limit.
+    pub fn try_acquire(self: &Arc<Self>, resource: Resource) -> Result<CircuitBreakerGuard> {
+        let limit = self.limit(resource);
+        self.counter(resource)
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < limit).then_some(n + 1))
+            .map(|_| CircuitBreakerGuard { breaker: self.clone(), resource })
+            .or_else(|_| {
+                Error::build(ErrorType::CircuitBreakerOpen)
+                    .source(ErrorSource::Internal)
+                    .context(format!("{} of cluster {} reached: {limit}", resource.as_str(), self.cluster))
+                    .err()
+            })
+    }
+}
+
+impl CircuitBreakerGuard {
+    pub fn resource(&self) -> Resource {
+        self.resource
+    }
+}
+
+impl Drop for CircuitBreakerGuard {
+    fn drop(&mut self) {
+        self.breaker.counter(self.resource).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gateway_protocols::http::error_resp::error_status;
+
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let config = CircuitBreakerConfig { max_connections: 2, max_requests: 1, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("api", config));
+        let c1 = breaker.try_acquire(Resource::Connection).unwrap();
+        let _c2 = breaker.try_acquire(Resource::Connection).unwrap();
+        assert!(breaker.is_open(Resource::Connection));
+        let e = breaker.try_acquire(Resource::Connection).err().unwrap();
+        assert_eq!(*e.etype(), ErrorType::CircuitBreakerOpen);
+        assert_eq!(error_status(&e), 503);
+        assert!(e.to_string().contains("max_connections of cluster api"), "{e}");
+
+        drop(c1);
+        assert_eq!(breaker.in_use(Resource::Connection), 1);
+        let _c3 = breaker.try_acquire(Resource::Connection).unwrap();
+
+        // resources are counted apart
+        let _r = breaker.try_acquire(Resource::Request).unwrap();
+        assert!(breaker.try_acquire(Resource::Request).is_err());
+        assert!(breaker.try_acquire(Resource::Retry).is_ok());
+        assert!(breaker.try_acquire(Resource::PendingRequest).is_ok());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{ops::RangeInclusive, sync::{Arc, Mutex}, time::Duration};
+
+use gateway_error::{Error, ErrorSource, ErrorType, Result};
+use gateway_protocols::{
+    connections::request::RequestHeader,
+    http::v1::client::HttpSession,
+    l4::connector::Connector,
+};
+use http::header::{CONNECTION, HOST};
+use log::{debug, info};
+use rand::Rng;
+use tokio::{task::JoinSet, time::Instant};
+
+use super::{Backend, Cluster};
+
+/// the most body bytes searched for `body_contains`
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// What a probe does to decide a backend is up.
+#[derive(Debug, Clone)]
+pub enum HealthCheck {
+    /// the TCP handshake succeeds
+    Tcp,
+    /// `req` is answered with an expected status, and a body containing `body_contains`
+    Http {
+        req: Box<RequestHeader>,
+        expected_status: Vec<RangeInclusive<u16>>,
+        body_contains: Option<Vec<u8>>,
+    },
+}
+
+impl HealthCheck {
+    /// `GET path` expecting a 2xx.
+    pub fn http_get(path: &str) -> Result<Self> {
+        let req = RequestHeader::build_with_method_path("GET", path.as_bytes())?;
+        Ok(HealthCheck::Http {
+            req: Box::new(req),
+            expected_status: vec![200..=299],
+            body_contains: None,
+        })
+    }
+}
+
+/// When backends are probed and how many probes flip their state.
+#[derive(Debug, Clone)]
+pub struct HealthCheckConfig {
+    pub interval: Duration,
+    /// a random delay up to `jitter` is added to every interval
+    pub jitter: Duration,
+    /// limit of a single probe, connect included
+    pub timeout: Duration,
+    /// consecutive successes to mark an unhealthy backend healthy
+    pub rise: usize,
+    /// consecutive failures to mark a healthy backend unhealthy
+    pub fall: usize,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            interval: Duration::from_secs(5),
+            jitter: Duration::from_millis(500),
+            timeout: Duration::from_secs(2),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ProbeCounter {
+    successes: usize,
+    failures: usize,
+}
+
+/// Probe every backend of a cluster and update its health.
+pub struct HealthChecker {
+    cluster: Arc<Cluster>,
+    connector: Arc<Connector>,
+    check: HealthCheck,
+    config: HealthCheckConfig,
+    counters: Mutex<Vec<ProbeCounter>>,
+}
+
+impl HealthChecker {
+    /// probes connect with `connector`, the one of the proxy so they dial and resolve alike.
+    pub fn new(
+        cluster: Arc<Cluster>,
+        connector: Arc<Connector>,
+        check: HealthCheck,
+        config: HealthCheckConfig,
+    ) -> Self {
+        let counters = Mutex::new(vec![ProbeCounter::default(); cluster.backends().len()]);
+        HealthChecker { cluster, connector, check, config, counters }
+    }
+
+    /// probe `backend` once, within `timeout` in total.
+    ///
+    /// a failed connect keeps the error of the [Connector], e.g. [ErrorType::DnsError].
+    /// A probe stuck connecting fails with [ErrorType::ConnectTimeout], one stuck
+    /// afterwards with [ErrorType::WriteTimedout] or [ErrorType::ReadTimedout].
+    pub async fn probe(&self, backend: &Backend) -> Result<()> {
+        let deadline = Instant::now() + self.config.timeout;
+        let stream = match tokio::time::timeout_at(deadline, self.connector.connect(&backend.addr)).await {
+            Ok(stream) => stream?,
+            Err(_) => {
+                return Error::build(ErrorType::ConnectTimeout)
+                    .source(ErrorSource::UpStream)
+                    .context(format!("health check connect to {} timed out", backend.addr))
+                    .err();
+            }
+        };
+        let HealthCheck::Http { req, expected_status, body_contains } = &self.check else {
+            return Ok(());
+        };
+
+        let mut req = req.clone();
+        if !req.headers.contains_key(HOST) {
+            req.insert_header(HOST, backend.addr.as_str())?;
+        }
+        req.insert_header(CONNECTION, "close")?;
+        let mut session = HttpSession::new(Box::new(stream));
+        let remaining = || Some(deadline.saturating_duration_since(Instant::now()));
+        session.write_timeout = remaining();
+        session.write_request_header(req).await?;
+        session.read_timeout = remaining();
+        session.read_response().await?;
+
+        let status = session.response_header().map_or(0, |resp| resp.status.as_u16());
+        if !expected_status.iter().any(|range| range.contains(&status)) {
+            return Error::build(ErrorType::HttpCode(status))
+                .source(ErrorSource::UpStream)
+                .context(format!("unexpected health check status from {}", backend.addr))
+                .err();
+        }
+        let Some(expected) = body_contains.as_deref().filter(|e| !e.is_empty()) else {
+            return Ok(());
+        };
+        // only the framed body is read, and no more of it than needed
+        let mut body = Vec::new();
+        while body.len() < MAX_RESPONSE_SIZE {
+            session.read_timeout = remaining();
+            let Some(piece) = session.read_body().await? else {
+                break;
+            };
+            body.extend_from_slice(&piece);
+            if body.windows(expected.len()).any(|w| w == expected) {
+                return Ok(());
+            }
+        }
+        Error::build(ErrorType::HttpCode(status))
+            .source(ErrorSource::UpStream)
+            .context(format!("unexpected health check body from {}", backend.addr))
+            .err()
+    }
+
+    /// count a probe result, flip the backend once `rise` or `fall` is reached.
+    ///
+    /// return whether the health changed.
+    fn record(&self, backend: &Backend, result: &Result<()>) -> bool {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = &mut counters[backend.id];
+        match result {
+            Ok(()) => {
+                counter.successes += 1;
+                counter.failures = 0;
+                if !backend.is_healthy() && counter.successes >= self.config.rise {
+                    info!("backend {} of {} is healthy", backend.addr, self.cluster.name);
+                    return backend.set_healthy(true);
+                }
+            }
+            Err(e) => {
+                counter.failures += 1;
+                counter.successes = 0;
+                debug!("health check of {} failed: {e}", backend.addr);
+                if backend.is_healthy() && counter.failures >= self.config.fall {
+                    info!("backend {} of {} is unhealthy: {e}", backend.addr, self.cluster.name);
+                    return backend.set_healthy(false);
+                }
+            }
+        }
+        false
+    }
+
+    /// probe all backends concurrently once.
+    pub async fn check_once(self: &Arc<Self>) {
+        let mut probes = JoinSet::new();
+        for backend in self.cluster.backends() {
+            let checker = self.clone();
+            let backend = backend.clone();
+            probes.spawn(async move {
+                let result = checker.probe(&backend).await;
+                checker.record(&backend, &result);
+            });
+        }
+        while probes.join_next().await.is_some() {}
+    }
+
+    /// probe forever, spawn it and abort the task to stop.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            self.check_once().await;
+            let jitter = match self.config.jitter.as_millis() as u64 {
+                0 => 0,
+                max => rand::thread_rng().gen_range(0..=max),
+            };
+            tokio::time::sleep(self.config.interval + Duration::from_millis(jitter)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use gateway_protocols::l4::test_util::refusing_socket;
+
+    use super::*;
+    use crate::upstream::lb::LbAlgorithm;
+
+    async fn http_backend(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(response).await;
+                    // ignore `Connection: close` and keep the connection open
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn config() -> HealthCheckConfig {
+        HealthCheckConfig {
+            interval: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            timeout: Duration::from_millis(500),
+            rise: 2,
+            fall: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_check() {
+        let up = http_backend(b"").await;
+        let (_reserved, down) = refusing_socket();
+        let cluster = Arc::new(Cluster::new("c", vec![(up, 1), (down.to_string(), 1)], LbAlgorithm::RoundRobin));
+        let checker = Arc::new(HealthChecker::new(cluster.clone(), Arc::default(), HealthCheck::Tcp, config()));
+
+        checker.check_once().await;
+        assert!(cluster.backends()[1].is_healthy());
+        checker.check_once().await;
+        assert!(cluster.backends()[0].is_healthy());
+        assert!(!cluster.backends()[1].is_healthy());
+        for _ in 0..4 {
+            assert_eq!(cluster.select(None, &[]).unwrap().id, 0);
+        }
+        let e = checker.probe(&cluster.backends()[1]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ConnectRefused);
+    }
+
+    #[tokio::test]
+    async fn probe_keeps_connect_errors() {
+        let cluster = Arc::new(Cluster::new("c", vec![("no-port".into(), 1)], LbAlgorithm::RoundRobin));
+        let checker = HealthChecker::new(cluster.clone(), Arc::default(), HealthCheck::Tcp, config());
+        let e = checker.probe(&cluster.backends()[0]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::DnsError);
+    }
+
+    #[tokio::test]
+    async fn http_check() {
+        let ok = http_backend(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let err = http_backend(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+        let wrong_body = http_backend(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbusy").await;
+        let cluster = Arc::new(Cluster::new(
+            "c",
+            vec![(ok, 1), (err, 1), (wrong_body, 1)],
+            LbAlgorithm::RoundRobin,
+        ));
+        let HealthCheck::Http { req, expected_status, .. } = HealthCheck::http_get("/healthz").unwrap() else {
+            unreachable!()
+        };
+        let check = HealthCheck::Http { req, expected_status, body_contains: Some(b"ok".to_vec()) };
+        let checker = Arc::new(HealthChecker::new(cluster.clone(), Arc::default(), check, config()));
+
+        let e = checker.probe(&cluster.backends()[1]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::HttpCode(503));
+        checker.check_once().await;
+        checker.check_once().await;
+        let health: Vec<bool> = cluster.backends().iter().map(|b| b.is_healthy()).collect();
+        assert_eq!(health, vec![true, false, false]);
+    }
+
+    #[tokio::test]
+    async fn http_check_reads_framed_body_only() {
+        let chunked = http_backend(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n").await;
+        let silent = http_backend(b"").await;
+        let cluster = Arc::new(Cluster::new("c", vec![(chunked, 1), (silent, 1)], LbAlgorithm::RoundRobin));
+        let HealthCheck::Http { req, expected_status, .. } = HealthCheck::http_get("/healthz").unwrap() else {
+            unreachable!()
+        };
+        let check = HealthCheck::Http { req, expected_status, body_contains: Some(b"ok".to_vec()) };
+        let checker = HealthChecker::new(cluster.clone(), Arc::default(), check, config());
+
+        // both backends keep the connection open, only the silent one runs into the timeout
+        let start = Instant::now();
+        checker.probe(&cluster.backends()[0]).await.unwrap();
+        assert!(start.elapsed() < config().timeout);
+        let e = checker.probe(&cluster.backends()[1]).await.unwrap_err();
+        assert_eq!(*e.etype(), ErrorType::ReadTimedout);
+    }
+
+    #[tokio::test]
+    async fn rise_after_recovery() {
+        let (reserved, down) = refusing_socket();
+        let cluster = Arc::new(Cluster::new("c", vec![(down.to_string(), 1)], LbAlgorithm::RoundRobin));
+        let checker = Arc::new(HealthChecker::new(cluster.clone(), Arc::default(), HealthCheck::Tcp, config()));
+        checker.check_once().await;
+        checker.check_once().await;
+        assert!(!cluster.backends()[0].is_healthy());
+
+        let listener = reserved.listen(16).unwrap();
+        let task = tokio::spawn(checker.clone().run());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        task.abort();
+        drop(listener);
+        assert!(cluster.backends()[0].is_healthy());
+    }
+}
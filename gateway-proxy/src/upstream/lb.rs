@@ -0,0 +1,346 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+
+use rand::Rng;
+
+use super::{Backend, MAX_WEIGHT};
+
+/// points per weight unit on the ketama ring
+const KETAMA_POINTS: u32 = 160;
+/// size of the maglev lookup table, a prime well above the number of backends
+const MAGLEV_TABLE_SIZE: usize = 65537;
+/// salted lookups tried before maglev falls back to scanning
+const MAGLEV_PROBES: u64 = 32;
+
+/// How a backend is picked from a [super::Cluster].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LbAlgorithm {
+    RoundRobin,
+    /// smooth weighted round robin, as nginx does it
+    WeightedRoundRobin,
+    /// fewest active connections relative to the weight
+    LeastConnections,
+    /// the less loaded of two random backends
+    RandomTwoChoices,
+    /// consistent hashing on a ring of virtual nodes
+    Ketama,
+    /// consistent hashing with a lookup table, see the Maglev paper
+    Maglev,
+}
+
+impl LbAlgorithm {
+    pub fn is_hashing(&self) -> bool {
+        matches!(self, LbAlgorithm::Ketama | LbAlgorithm::Maglev)
+    }
+}
+
+/// The selection state of one algorithm over a fixed set of backends.
+pub struct LoadBalancer {
+    algorithm: LbAlgorithm,
+    counter: AtomicUsize,
+    /// current weights of the smooth weighted round robin
+    current_weights: Mutex<Vec<i64>>,
+    /// `(point, backend index)` sorted by point
+    ring: Vec<(u64, usize)>,
+    /// backend index of every slot
+    maglev: Vec<usize>,
+}
+
+/// 64-bit FNV-1a followed by the splitmix64 finalizer, stable across builds
+/// so that every gateway instance hashes the same key to the same backend.
+pub fn hash64(data: &[u8], seed: u64) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325 ^ seed;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+impl LoadBalancer {
+    pub fn new(algorithm: LbAlgorithm, backends: &[Arc<Backend>]) -> Self {
+        let ring = match algorithm {
+            LbAlgorithm::Ketama => build_ring(backends),
+            _ => vec![],
+        };
+        let maglev = match algorithm {
+            LbAlgorithm::Maglev => build_maglev(backends),
+            _ => vec![],
+        };
+        LoadBalancer {
+            algorithm,
+            counter: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; backends.len()]),
+            ring,
+            maglev,
+        }
+    }
+
+    pub fn algorithm(&self) -> LbAlgorithm {
+        self.algorithm
+    }
+
+    /// the index of the picked backend among the `usable` ones.
+    pub fn select(
+        &self,
+        backends: &[Arc<Backend>],
+        key: Option<&[u8]>,
+        usable: &dyn Fn(&Backend) -> bool,
+    ) -> Option<usize> {
+        if backends.is_empty() {
+            return None;
+        }
+        match (self.algorithm, key) {
+            (LbAlgorithm::RoundRobin, _) => self.round_robin(backends, usable),
+            (LbAlgorithm::WeightedRoundRobin, _) => self.weighted_round_robin(backends, usable),
+            (LbAlgorithm::LeastConnections, _) => self.least_connections(backends, usable),
+            (LbAlgorithm::RandomTwoChoices, _) => random_two_choices(backends, usable),
+            (LbAlgorithm::Ketama, Some(key)) => self.ketama(backends, key, usable),
+            (LbAlgorithm::Maglev, Some(key)) => self.maglev(backends, key, usable),
+            (LbAlgorithm::Ketama | LbAlgorithm::Maglev, None) => self.round_robin(backends, usable),
+        }
+    }
+
+    fn round_robin(&self, backends: &[Arc<Backend>], usable: &dyn Fn(&Backend) -> bool) -> Option<usize> {
+        let n = backends.len();
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        (0..n).map(|i| (start + i) % n).find(|i| usable(&backends[*i]))
+    }
+
+    fn weighted_round_robin(&self, backends: &[Arc<Backend>], usable: &dyn Fn(&Backend) -> bool) -> Option<usize> {
+        let mut current = self.current_weights.lock().unwrap();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for (i, backend) in backends.iter().enumerate() {
+            if !usable(backend) {
+                continue;
+            }
+            let weight = backend.weight as i64;
+            current[i] += weight;
+            total += weight;
+            if best.is_none_or(|b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        current[best] -= total;
+        Some(best)
+    }
+
+    fn least_connections(&self, backends: &[Arc<Backend>], usable: &dyn Fn(&Backend) -> bool) -> Option<usize> {
+        // start from a rotating position so that ties are spread
+        let n = backends.len();
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut best: Option<usize> = None;
+        for i in (0..n).map(|i| (start + i) % n) {
+            if !usable(&backends[i]) {
+                continue;
+            }
+            if best.is_none_or(|b| less_loaded(&backends[i], &backends[b])) {
+                best = Some(i);
+            }
+        }
+        best
+    }
+
+    fn ketama(&self, backends: &[Arc<Backend>], key: &[u8], usable: &dyn Fn(&Backend) -> bool) -> Option<usize> {
+        let h = hash64(key, 0);
+        let start = self.ring.partition_point(|(point, _)| *point < h);
+        let n = self.ring.len();
+        (0..n)
+            .map(|i| self.ring[(start + i) % n].1)
+            .find(|i| usable(&backends[*i]))
+    }
+
+    fn maglev(&self, backends: &[Arc<Backend>], key: &[u8], usable: &dyn Fn(&Backend) -> bool) -> Option<usize> {
+        let mut first = None;
+        for seed in 0..MAGLEV_PROBES {
+            let i = self.maglev[(hash64(key, seed) % self.maglev.len() as u64) as usize];
+            if usable(&backends[i]) {
+                return Some(i);
+            }
+            first.get_or_insert(i);
+        }
+        let first = first?;
+        let n = backends.len();
+        (1..n).map(|i| (first + i) % n).find(|i| usable(&backends[*i]))
+    }
+}
+
+/// `a.active / a.weight < b.active / b.weight`
+#[inline]
+fn less_loaded(a: &Backend, b: &Backend) -> bool {
+    (a.active_connections() as u64) * (b.weight as u64) < (b.active_connections() as u64) * (a.weight as u64)
+}
+
+fn random_two_choices(backends: &[Arc<Backend>], usable: &dyn Fn(&Backend) -> bool) -> Option<usize> {
+    let candidates: Vec<usize> = (0..backends.len()).filter(|i| usable(&backends[*i])).collect();
+    let mut rng = rand::thread_rng();
+    match candidates.len() {
+        0 => None,
+        1 => Some(candidates[0]),
+        n => {
+            let a = rng.gen_range(0..n);
+            let b = (a + rng.gen_range(1..n)) % n;
+            let (a, b) = (candidates[a], candidates[b]);
+            Some(if less_loaded(&backends[b], &backends[a]) { b } else { a })
+        }
+    }
+}
+
+fn build_ring(backends: &[Arc<Backend>]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::new();
+    for (i, backend) in backends.iter().enumerate() {
+        let points = KETAMA_POINTS.saturating_mul(backend.weight.min(MAX_WEIGHT));
+        for point in 0..points {
+            ring.push((hash64(format!("{}-{point}", backend.addr).as_bytes(), 0), i));
+        }
+    }
+    ring.sort_unstable();
+    ring
+}
+
+fn build_maglev(backends: &[Arc<Backend>]) -> Vec<usize> {
+    let m = MAGLEV_TABLE_SIZE as u64;
+    let mut table = vec![usize::MAX; MAGLEV_TABLE_SIZE];
+    if backends.is_empty() {
+        return table;
+    }
+    let permutation: Vec<(u64, u64)> = backends
+        .iter()
+        .map(|b| {
+            let offset = hash64(b.addr.as_bytes(), 0xdead) % m;
+            let skip = hash64(b.addr.as_bytes(), 0xbeef) % (m - 1) + 1;
+            (offset, skip)
+        })
+        .collect();
+    let mut next = vec![0u64; backends.len()];
+    let mut filled = 0;
+    // every round, each backend claims as many slots as its weight
+    'fill: loop {
+        for (i, backend) in backends.iter().enumerate() {
+            for _ in 0..backend.weight {
+                let (offset, skip) = permutation[i];
+                let mut slot = ((offset + next[i] * skip) % m) as usize;
+                while table[slot] != usize::MAX {
+                    next[i] += 1;
+                    slot = ((offset + next[i] * skip) % m) as usize;
+                }
+                table[slot] = i;
+                next[i] += 1;
+                filled += 1;
+                if filled == MAGLEV_TABLE_SIZE {
+                    break 'fill;
+                }
+            }
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::Cluster;
+
+    fn cluster(algorithm: LbAlgorithm, weights: &[u32]) -> Cluster {
+        let backends = weights
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (format!("10.0.0.{i}:80"), *w))
+            .collect();
+        Cluster::new("c", backends, algorithm)
+    }
+
+    fn counts(cluster: &Cluster, rounds: usize, key: impl Fn(usize) -> Option<Vec<u8>>) -> Vec<usize> {
+        let mut counts = vec![0; cluster.backends().len()];
+        for i in 0..rounds {
+            counts[cluster.select(key(i).as_deref(), &[]).unwrap().id] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let cluster = cluster(LbAlgorithm::RoundRobin, &[1, 1, 1]);
+        let picked: Vec<usize> = (0..6).map(|_| cluster.select(None, &[]).unwrap().id).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2]);
+        assert_eq!(cluster.select(None, &[0, 1, 2]).map(|b| b.id), None);
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let cluster = cluster(LbAlgorithm::WeightedRoundRobin, &[5, 1, 1]);
+        let picked: Vec<usize> = (0..7).map(|_| cluster.select(None, &[]).unwrap().id).collect();
+        // smooth: the heavy backend is not picked 5 times in a row
+        assert_eq!(picked, vec![0, 0, 1, 0, 2, 0, 0]);
+
+        cluster.backends()[0].set_healthy(false);
+        assert_eq!(counts(&cluster, 10, |_| None), vec![0, 5, 5]);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let cluster = cluster(LbAlgorithm::LeastConnections, &[1, 2, 1]);
+        let backends = cluster.backends().to_vec();
+        let _a = backends[0].acquire().unwrap();
+        let _b = (backends[1].acquire().unwrap(), backends[1].acquire().unwrap());
+        let _c = (backends[2].acquire().unwrap(), backends[2].acquire().unwrap());
+        // 1/1 vs 2/2 vs 2/1
+        let picked = cluster.select(None, &[]).unwrap().id;
+        assert!(picked == 0 || picked == 1);
+        let _more = backends[0].acquire().unwrap();
+        assert_eq!(cluster.select(None, &[]).unwrap().id, 1);
+    }
+
+    #[test]
+    fn test_random_two_choices() {
+        let cluster = cluster(LbAlgorithm::RandomTwoChoices, &[1, 1]);
+        let _busy = cluster.backends()[0].acquire().unwrap();
+        for _ in 0..10 {
+            assert_eq!(cluster.select(None, &[]).unwrap().id, 1);
+        }
+        assert_eq!(cluster.select(None, &[1]).unwrap().id, 0);
+    }
+
+    #[test]
+    fn test_consistent_hashing() {
+        for algorithm in [LbAlgorithm::Ketama, LbAlgorithm::Maglev] {
+            let cluster = cluster(algorithm, &[1, 1, 1, 1]);
+            let key = |i: usize| Some(format!("user-{i}").into_bytes());
+            let before: Vec<usize> = (0..1000).map(|i| cluster.select(key(i).as_deref(), &[]).unwrap().id).collect();
+            // same key, same backend
+            let again: Vec<usize> = (0..1000).map(|i| cluster.select(key(i).as_deref(), &[]).unwrap().id).collect();
+            assert_eq!(before, again);
+
+            let spread = counts(&cluster, 1000, key);
+            assert!(spread.iter().all(|c| *c > 150), "{algorithm:?} {spread:?}");
+
+            // only the keys of the removed backend move
+            cluster.backends()[2].set_healthy(false);
+            for (i, old) in before.iter().enumerate() {
+                let new = cluster.select(key(i).as_deref(), &[]).unwrap().id;
+                if *old != 2 {
+                    assert_eq!(new, *old, "{algorithm:?}");
+                } else {
+                    assert_ne!(new, 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_weights() {
+        let cluster = cluster(LbAlgorithm::Maglev, &[3, 1]);
+        let spread = counts(&cluster, 4000, |i| Some(i.to_string().into_bytes()));
+        assert!(spread[0] > spread[1] * 2, "{spread:?}");
+    }
+
+    #[test]
+    fn test_weight_capped() {
+        let cluster = cluster(LbAlgorithm::Ketama, &[u32::MAX, 1]);
+        assert_eq!(cluster.backends()[0].weight, MAX_WEIGHT);
+        assert_eq!(cluster.lb.ring.len(), (KETAMA_POINTS * (MAX_WEIGHT + 1)) as usize);
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    net::IpAddr,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex},
+    time::Instant,
+};
+
+use gateway_error::Result;
+use gateway_protocols::{
+    connections::request::RequestHeader,
+    l4::{connector::Connector, stream::Stream},
+};
+use http::header::COOKIE;
+
+use crate::retry::UpstreamSelector;
+
+pub mod circuit_breaker;
+pub mod health;
+pub mod lb;
+pub mod outlier;
+
+use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitBreakerGuard, Resource};
+use lb::{LbAlgorithm, LoadBalancer};
+
+/// the largest weight of a [Backend], larger ones are capped to it.
+pub const MAX_WEIGHT: u32 = 256;
+
+/// One server of a [Cluster].
+#[derive(Debug)]
+pub struct Backend {
+    /// position in the cluster
+    pub id: usize,
+    /// `host:port`, the host may be a name to resolve
+    pub addr: String,
+    pub weight: u32,
+    healthy: AtomicBool,
+    /// set by passive health checking, see [outlier::OutlierDetector]
+    ejected_until: Mutex<Option<Instant>>,
+    active_connections: AtomicUsize,
+    /// the breaker of the cluster, shared by all its backends
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+/// Keep a connection to a [Backend] counted until dropped.
+pub struct ConnectionGuard {
+    backend: Arc<Backend>,
+    _breaker: CircuitBreakerGuard,
+}
+
+impl Backend {
+    fn new(id: usize, addr: String, weight: u32, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        Backend {
+            id,
+            addr,
+            weight: weight.clamp(1, MAX_WEIGHT),
+            healthy: AtomicBool::new(true),
+            ejected_until: Mutex::new(None),
+            active_connections: AtomicUsize::new(0),
+            circuit_breaker,
+        }
+    }
+
+    /// whether the backend may be selected: healthy and not ejected.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_ejected()
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.is_ejected_at(Instant::now())
+    }
+
+    fn is_ejected_at(&self, now: Instant) -> bool {
+        self.ejected_until.lock().unwrap().is_some_and(|until| until > now)
+    }
+
+    fn eject_until(&self, until: Option<Instant>) {
+        *self.ejected_until.lock().unwrap() = until;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// return whether the health changed.
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        self.healthy.swap(healthy, Ordering::Relaxed) != healthy
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// count a connection to the backend, fail with [gateway_error::ErrorType::CircuitBreakerOpen]
+    /// when the cluster has `max_connections` open already.
+    pub fn acquire(self: &Arc<Self>) -> Result<ConnectionGuard> {
+        let breaker = self.circuit_breaker.try_acquire(Resource::Connection)?;
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        Ok(ConnectionGuard { backend: self.clone(), _breaker: breaker })
+    }
+
+    /// open a connection to the backend with `connector`.
+    ///
+    /// the request counts against `max_pending_requests` of the cluster until the
+    /// connection is established, and the connection against `max_connections` until
+    /// the guard is dropped.
+    pub async fn connect(self: &Arc<Self>, connector: &Connector) -> Result<(Stream, ConnectionGuard)> {
+        let _pending = self.circuit_breaker.try_acquire(Resource::PendingRequest)?;
+        let guard = self.acquire()?;
+        let stream = connector.connect(&self.addr).await?;
+        Ok((stream, guard))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.backend.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What a consistent hash algorithm keys on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+    ClientIp,
+}
+
+impl HashKey {
+    /// `None` when the request does not carry the key.
+    pub fn extract(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Option<Vec<u8>> {
+        match self {
+            HashKey::Header(name) => req.headers.get(name.as_str()).map(|v| v.as_bytes().to_vec()),
+            HashKey::Cookie(name) => req
+                .headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_bytes().to_vec()),
+            HashKey::ClientIp => client_ip.map(|ip| ip.to_string().into_bytes()),
+        }
+    }
+}
+
+/// A named set of backends and how to pick one of them.
+pub struct Cluster {
+    pub name: String,
+    backends: Vec<Arc<Backend>>,
+    lb: LoadBalancer,
+    pub hash_key: Option<HashKey>,
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+impl Cluster {
+    /// `backends` are `(addr, weight)` pairs, a weight of 0 counts as 1 and one
+    /// above [MAX_WEIGHT] as [MAX_WEIGHT].
+    pub fn new(name: impl Into<String>, backends: Vec<(String, u32)>, algorithm: LbAlgorithm) -> Self {
+        let name = name.into();
+        let circuit_breaker = Arc::new(CircuitBreaker::new(name.clone(), CircuitBreakerConfig::default()));
+        let backends: Vec<Arc<Backend>> = backends
+            .into_iter()
+            .enumerate()
+            .map(|(id, (addr, weight))| Arc::new(Backend::new(id, addr, weight, circuit_breaker.clone())))
+            .collect();
+        let lb = LoadBalancer::new(algorithm, &backends);
+        Cluster {
+            name,
+            backends,
+            lb,
+            hash_key: None,
+            circuit_breaker,
+        }
+    }
+
+    /// replace the limits of the breaker shared by the backends.
+    pub fn with_circuit_breaker(self, config: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker.set_config(config);
+        self
+    }
+
+    pub fn circuit_breaker(&self) -> &Arc<CircuitBreaker> {
+        &self.circuit_breaker
+    }
+
+    pub fn with_hash_key(mut self, hash_key: HashKey) -> Self {
+        self.hash_key = Some(hash_key);
+        self
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    pub fn algorithm(&self) -> LbAlgorithm {
+        self.lb.algorithm()
+    }
+
+    /// the hash key of `req` for this cluster, if it has one.
+    pub fn request_key(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Option<Vec<u8>> {
+        self.hash_key.as_ref().and_then(|k| k.extract(req, client_ip))
+    }
+
+    /// pick an available backend not in `exclude` (ids).
+    ///
+    /// hashing algorithms without a `key` fall back to round robin.
+    pub fn select(&self, key: Option<&[u8]>, exclude: &[usize]) -> Option<Arc<Backend>> {
+        self.lb
+            .select(&self.backends, key, &|b: &Backend| b.is_available() && !exclude.contains(&b.id))
+            .map(|i| self.backends[i].clone())
+    }
+
+    /// a selector for [crate::retry::RetryPolicy::execute] keyed on `key`.
+    pub fn selector(&self, key: Option<Vec<u8>>) -> ClusterSelector<'_> {
+        ClusterSelector { cluster: self, key, tried: vec![] }
+    }
+}
+
+/// Select a different backend for every attempt of a request.
+///
+/// once every available backend was tried, the tried ones are selected again.
+pub struct ClusterSelector<'a> {
+    cluster: &'a Cluster,
+    key: Option<Vec<u8>>,
+    tried: Vec<usize>,
+}
+
+impl UpstreamSelector for ClusterSelector<'_> {
+    type Peer = Arc<Backend>;
+
+    fn select(&mut self, _attempt: usize) -> Option<Arc<Backend>> {
+        let key = self.key.as_deref();
+        let backend = self
+            .cluster
+            .select(key, &self.tried)
+            .or_else(|| self.cluster.select(key, &[]))?;
+        self.tried.push(backend.id);
+        Some(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(algorithm: LbAlgorithm) -> Cluster {
+        Cluster::new(
+            "c",
+            vec![("a:80".into(), 1), ("b:80".into(), 1), ("c:80".into(), 1)],
+            algorithm,
+        )
+    }
+
+    #[test]
+    fn test_hash_key() {
+        let mut req = RequestHeader::build_with_method_path("GET", b"/").unwrap();
+        req.insert_header("Cookie", "theme=dark; sid=abc").unwrap();
+        req.insert_header("X-User", "42").unwrap();
+        assert_eq!(HashKey::Cookie("sid".into()).extract(&req, None).unwrap(), b"abc");
+        assert_eq!(HashKey::Header("x-user".into()).extract(&req, None).unwrap(), b"42");
+        assert!(HashKey::Cookie("none".into()).extract(&req, None).is_none());
+        let ip = "192.0.2.1".parse().ok();
+        assert_eq!(HashKey::ClientIp.extract(&req, ip).unwrap(), b"192.0.2.1");
+    }
+
+    #[test]
+    fn test_skip_unhealthy() {
+        let cluster = cluster(LbAlgorithm::RoundRobin);
+        assert!(cluster.backends()[1].set_healthy(false));
+        assert!(!cluster.backends()[1].set_healthy(false));
+        for _ in 0..6 {
+            assert_ne!(cluster.select(None, &[]).unwrap().id, 1);
+        }
+        for b in cluster.backends() {
+            b.set_healthy(false);
+        }
+        assert!(cluster.select(None, &[]).is_none());
+    }
+
+    #[test]
+    fn test_selector_fallback() {
+        let cluster = cluster(LbAlgorithm::Ketama);
+        let mut selector = cluster.selector(Some(b"user-1".to_vec()));
+        let mut picked: Vec<usize> = (0..3).map(|i| selector.select(i).unwrap().id).collect();
+        let first = picked[0];
+        picked.sort();
+        assert_eq!(picked, vec![0, 1, 2]);
+        // every backend was tried, start over from the hashed one
+        assert_eq!(selector.select(3).unwrap().id, first);
+    }
+
+    #[test]
+    fn test_connection_guard() {
+        let cluster = cluster(LbAlgorithm::LeastConnections);
+        let a = cluster.backends()[0].clone();
+        let guard = a.acquire().unwrap();
+        assert_eq!(a.active_connections(), 1);
+        drop(guard);
+        assert_eq!(a.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let config = CircuitBreakerConfig { max_connections: 2, ..Default::default() };
+        let cluster = cluster(LbAlgorithm::RoundRobin);
+        let (a, b) = (cluster.backends()[0].clone(), cluster.backends()[1].clone());
+        a.set_healthy(false);
+        let cluster = cluster.with_circuit_breaker(config);
+        // the backends handed out before are still the ones of the cluster
+        assert!(Arc::ptr_eq(&a, &cluster.backends()[0]));
+        assert!(!cluster.backends()[0].is_healthy());
+        let _a = a.acquire().unwrap();
+        let held = b.acquire().unwrap();
+        // the limit is of the cluster, not of one backend
+        let e = cluster.backends()[2].acquire().err().unwrap();
+        assert_eq!(*e.etype(), gateway_error::ErrorType::CircuitBreakerOpen);
+        assert_eq!(cluster.backends()[2].active_connections(), 0);
+        drop(held);
+        assert!(cluster.backends()[2].acquire().is_ok());
+        assert_eq!(cluster.circuit_breaker().in_use(Resource::Connection), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_requests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let config = CircuitBreakerConfig { max_pending_requests: 1, ..Default::default() };
+        let cluster = Cluster::new("c", vec![(addr, 1)], LbAlgorithm::RoundRobin).with_circuit_breaker(config);
+        let backend = cluster.backends()[0].clone();
+        let connector = Connector::default();
+
+        // another request is still waiting for its connection
+        let waiting = cluster.circuit_breaker().try_acquire(Resource::PendingRequest).unwrap();
+        let e = backend.connect(&connector).await.err().unwrap();
+        assert_eq!(*e.etype(), gateway_error::ErrorType::CircuitBreakerOpen);
+        assert_eq!(gateway_protocols::http::error_resp::error_status(&e), 503);
+        assert!(e.to_string().contains("max_pending_requests"), "{e}");
+        assert_eq!(backend.active_connections(), 0);
+
+        drop(waiting);
+        let (_stream, _guard) = backend.connect(&connector).await.unwrap();
+        assert_eq!(backend.active_connections(), 1);
+        // no longer pending once connected
+        assert_eq!(cluster.circuit_breaker().in_use(Resource::PendingRequest), 0);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use gateway_error::{Error, ErrorType};
+use log::info;
+
+use super::{Backend, Cluster};
+
+/// When a backend is ejected after the failures seen on real traffic.
+#[derive(Debug, Clone)]
+pub struct OutlierConfig {
+    /// eject after this many failures in a row, 0 disables it
+    pub consecutive_failures: usize,
+    /// eject when the failure ratio of a window reaches it, above 1.0 disables it
+    pub error_rate: f64,
+    /// requests needed in a window before the error rate is judged
+    pub min_requests: usize,
+    /// length of the error rate window
+    pub interval: Duration,
+    /// the first ejection lasts this long, every following one twice as long
+    pub base_ejection_time: Duration,
+    pub max_ejection_time: Duration,
+    /// at most this percent of the backends are out at once, ejected or failing active health checks
+    pub max_ejection_percent: u8,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        OutlierConfig {
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OutlierState {
+    consecutive_failures: usize,
+    window_start: Instant,
+    window_requests: usize,
+    window_failures: usize,
+    /// ejections in a row, doubling the ejection time
+    ejections: u32,
+    last_ejection_end: Option<Instant>,
+}
+
+impl OutlierState {
+    fn new(now: Instant) -> Self {
+        OutlierState {
+            consecutive_failures: 0,
+            window_start: now,
+            window_requests: 0,
+            window_failures: 0,
+            ejections: 0,
+            last_ejection_end: None,
+        }
+    }
+
+    fn reset_window(&mut self, now: Instant) {
+        self.window_start = now;
+        self.window_requests = 0;
+        self.window_failures = 0;
+    }
+}
+
+/// Whether an upstream error counts against the backend.
+///
+/// connect failures, broken reads, timeouts and 5xx do, errors of the downstream or the
+/// proxy itself do not.
+pub fn is_backend_failure(e: &Error) -> bool {
+    match e.etype() {
+        ErrorType::ConnectRefused
+        | ErrorType::ConnectTimeout
+        | ErrorType::ReadError
+        | ErrorType::ConnectionClosed
+        | ErrorType::PerTryTimeout
+        | ErrorType::ReadTimedout
+        | ErrorType::WriteTimedout => true,
+        ErrorType::HttpCode(code) => *code >= 500,
+        _ => false,
+    }
+}
+
+/// Track the outcome of every request per backend and eject the failing ones.
+pub struct OutlierDetector {
+    cluster: Arc<Cluster>,
+    config: OutlierConfig,
+    states: Mutex<Vec<OutlierState>>,
+}
+
+impl OutlierDetector {
+    pub fn new(cluster: Arc<Cluster>, config: OutlierConfig) -> Self {
+        let now = Instant::now();
+        let states = Mutex::new(vec![OutlierState::new(now); cluster.backends().len()]);
+        OutlierDetector { cluster, config, states }
+    }
+
+    pub fn report_success(&self, backend: &Backend) {
+        self.record(backend, false, Instant::now());
+    }
+
+    /// `status` of the upstream response, 5xx count as failures.
+    pub fn report_status(&self, backend: &Backend, status: u16) {
+        self.record(backend, status >= 500, Instant::now());
+    }
+
+    pub fn report_error(&self, backend: &Backend, e: &Error) {
+        self.record(backend, is_backend_failure(e), Instant::now());
+    }
+
+    /// return whether `backend` got ejected.
+    fn record(&self, backend: &Backend, failure: bool, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let state = &mut states[backend.id];
+        if now.duration_since(state.window_start) >= self.config.interval {
+            state.reset_window(now);
+        }
+        state.window_requests += 1;
+        if !failure {
+            state.consecutive_failures = 0;
+            return false;
+        }
+        state.consecutive_failures += 1;
+        state.window_failures += 1;
+
+        let consecutive = self.config.consecutive_failures > 0
+            && state.consecutive_failures >= self.config.consecutive_failures;
+        let rate = state.window_requests >= self.config.min_requests
+            && state.window_failures as f64 >= self.config.error_rate * state.window_requests as f64;
+        if !(consecutive || rate) || backend.is_ejected_at(now) {
+            return false;
+        }
+
+        // backends marked unhealthy by active health checks are out as well
+        let out = self
+            .cluster
+            .backends()
+            .iter()
+            .filter(|b| b.id != backend.id && (b.is_ejected_at(now) || !b.is_healthy()))
+            .count();
+        let n = self.cluster.backends().len();
+        let max_out = n * self.config.max_ejection_percent.min(100) as usize / 100;
+        // never eject the last backend standing
+        if out + 1 > max_out || out + 1 >= n {
+            return false;
+        }
+
+        // a backend which stayed in for a whole max ejection time starts over
+        if state
+            .last_ejection_end
+            .is_some_and(|end| now.duration_since(end) >= self.config.max_ejection_time)
+        {
+            state.ejections = 0;
+        }
+        let duration = self
+            .config
+            .base_ejection_time
+            .saturating_mul(1u32 << state.ejections.min(16))
+            .min(self.config.max_ejection_time);
+        state.ejections += 1;
+        state.last_ejection_end = Some(now + duration);
+        state.consecutive_failures = 0;
+        state.reset_window(now);
+        backend.eject_until(Some(now + duration));
+        info!(
+            "backend {} of {} ejected for {duration:?}, ejection #{}",
+            backend.addr, self.cluster.name, state.ejections
+        );
+        true
+    }
+
+    /// bring every ejected backend back right away.
+    pub fn reset(&self) {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        for (backend, state) in self.cluster.backends().iter().zip(states.iter_mut()) {
+            backend.eject_until(None);
+            *state = OutlierState::new(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::lb::LbAlgorithm;
+
+    fn cluster(n: usize) -> Arc<Cluster> {
+        let backends = (0..n).map(|i| (format!("10.0.0.{i}:80"), 1)).collect();
+        Arc::new(Cluster::new("c", backends, LbAlgorithm::RoundRobin))
+    }
+
+    fn config() -> OutlierConfig {
+        OutlierConfig {
+            consecutive_failures: 3,
+            error_rate: 0.5,
+            min_requests: 10,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(100),
+            max_ejection_percent: 50,
+        }
+    }
+
+    #[test]
+    fn test_failure_classification() {
+        let e = Error::generate_error_with_root_raw(ErrorType::ConnectRefused, "", None);
+        assert!(is_backend_failure(&e));
+        let e = Error::generate_error_with_root_raw(ErrorType::HttpCode(503), "", None);
+        assert!(is_backend_failure(&e));
+        let e = Error::generate_error_with_root_raw(ErrorType::ReadTimedout, "", None);
+        assert!(is_backend_failure(&e));
+        let e = Error::generate_error_with_root_raw(ErrorType::WriteTimedout, "", None);
+        assert!(is_backend_failure(&e));
+        let e = Error::generate_error_with_root_raw(ErrorType::HttpCode(404), "", None);
+        assert!(!is_backend_failure(&e));
+        let e = Error::generate_error_with_root_raw(ErrorType::InvalidHttpHeader, "", None);
+        assert!(!is_backend_failure(&e));
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let cluster = cluster(4);
+        let detector = OutlierDetector::new(cluster.clone(), config());
+        let b = &cluster.backends()[0];
+        let now = Instant::now();
+        assert!(!detector.record(b, true, now));
+        assert!(!detector.record(b, true, now));
+        assert!(!detector.record(b, false, now));
+        assert!(!detector.record(b, true, now));
+        assert!(!detector.record(b, true, now));
+        assert!(detector.record(b, true, now));
+        assert!(b.is_ejected_at(now + Duration::from_secs(29)));
+        assert!(!b.is_ejected_at(now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_error_rate() {
+        let cluster = cluster(4);
+        let detector = OutlierDetector::new(cluster.clone(), config());
+        let b = &cluster.backends()[1];
+        let now = Instant::now();
+        for i in 0..9 {
+            // alternate, so never 3 failures in a row
+            assert!(!detector.record(b, i % 2 == 0, now));
+        }
+        assert!(detector.record(b, true, now));
+
+        // an old window is forgotten
+        let b = &cluster.backends()[2];
+        for i in 0..9 {
+            detector.record(b, i % 2 == 0, now);
+        }
+        assert!(!detector.record(b, true, now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn test_exponential_ejection() {
+        let cluster = cluster(4);
+        let detector = OutlierDetector::new(cluster.clone(), config());
+        let b = &cluster.backends()[0];
+        let mut now = Instant::now();
+        for expected in [30, 60, 100, 100] {
+            for _ in 0..3 {
+                detector.record(b, true, now);
+            }
+            let until = b.ejected_until.lock().unwrap().unwrap();
+            assert_eq!(until - now, Duration::from_secs(expected));
+            now = until;
+        }
+        // stayed healthy longer than the max ejection time
+        now += Duration::from_secs(100);
+        for _ in 0..3 {
+            detector.record(b, true, now);
+        }
+        assert_eq!(b.ejected_until.lock().unwrap().unwrap() - now, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let four = cluster(4);
+        let detector = OutlierDetector::new(four.clone(), config());
+        let now = Instant::now();
+        let ejected: Vec<bool> = four
+            .backends()
+            .iter()
+            .map(|b| (0..3).any(|_| detector.record(b, true, now)))
+            .collect();
+        assert_eq!(ejected, vec![true, true, false, false]);
+
+        // a single backend is never ejected
+        let single = cluster(1);
+        let detector = OutlierDetector::new(single.clone(), OutlierConfig { max_ejection_percent: 100, ..config() });
+        for _ in 0..10 {
+            assert!(!detector.record(&single.backends()[0], true, now));
+        }
+    }
+
+    #[test]
+    fn test_unhealthy_count_as_out() {
+        let cluster = cluster(3);
+        let detector = OutlierDetector::new(cluster.clone(), OutlierConfig { max_ejection_percent: 100, ..config() });
+        let now = Instant::now();
+        cluster.backends()[0].set_healthy(false);
+        assert!((0..3).any(|_| detector.record(&cluster.backends()[1], true, now)));
+        // one backend unhealthy and one ejected, the last one stays in
+        assert!(!(0..10).any(|_| detector.record(&cluster.backends()[2], true, now)));
+        assert_eq!(cluster.select(None, &[]).unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_reset() {
+        let cluster = cluster(2);
+        let detector = OutlierDetector::new(cluster.clone(), OutlierConfig { max_ejection_percent: 100, ..config() });
+        let e = Error::generate_error_with_root_raw(ErrorType::ConnectTimeout, "", None);
+        for _ in 0..3 {
+            detector.report_error(&cluster.backends()[0], &e);
+        }
+        assert!(!cluster.backends()[0].is_available());
+        assert_eq!(cluster.select(None, &[]).unwrap().id, 1);
+        detector.reset();
+        assert!(cluster.backends()[0].is_available());
+    }
+}